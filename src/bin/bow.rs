@@ -1,6 +1,7 @@
 use candle_core::{DType, Result, Shape, Tensor};
 use nanogpt::util::get_device;
 
+#[allow(non_snake_case)]
 fn pointless_exercise() -> Result<()> {
     let (B, T, C) = (4, 8, 2);
 
@@ -1,6 +1,6 @@
 use candle_core::{Device, Result, Tensor};
 use candle_datasets::Batcher;
-use candle_nn::{loss, Optimizer, VarBuilder, VarMap};
+use candle_nn::{loss, Optimizer, VarMap};
 use clap::Parser;
 use nanogpt::config::pretrained_config::PretrainedConfig;
 use nanogpt::config::training_config::TrainingConfig;
@@ -10,6 +10,9 @@ use nanogpt::models::bigram::Bigram;
 use nanogpt::models::transformer::Transformer;
 use nanogpt::models::{Model, WhichModel};
 use nanogpt::tokenizer::Tokenizer;
+use nanogpt::util::seeded_var_builder;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use std::path::{Path, PathBuf};
 use std::{env, process};
 
@@ -26,9 +29,12 @@ fn training_loop<M: Model>(
     model_config: &PretrainedConfig,
     device: &Device,
 ) -> Result<()> {
+    let seed = args.seed.unwrap_or_else(rand::random);
+    println!("Seed: {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
     let mut varmap = VarMap::new();
-    let vs = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
-    let model: M = M::from_config(vs, &model_config)?;
+    let vs = seeded_var_builder(&varmap, seed, candle_core::DType::F32, device);
+    let model: M = M::from_config(vs, model_config)?;
 
     if let Some(load_from) = &args.load_from {
         varmap.load(load_from)?;
@@ -42,9 +48,13 @@ fn training_loop<M: Model>(
 
     for epoch in 0..args.epochs {
         // Recreating here because we must
-        let train_iter =
-            TextDatasetIterator::new(dataset, model_config.context_size as usize, device)
-                .map_err(|e| candle_core::Error::Msg(format!("{:?}", e)))?;
+        let train_iter = TextDatasetIterator::new(
+            dataset,
+            model_config.context_size as usize,
+            device,
+            &mut rng,
+        )
+        .map_err(|e| candle_core::Error::Msg(format!("{:?}", e)))?;
         let mut train_batcher = Batcher::new_r2(train_iter).batch_size(args.batch_size);
         // TODO: Remove arbitrary step limit; just here for bigram
         let mut loss = Tensor::zeros(4, candle_core::DType::F32, device)?;
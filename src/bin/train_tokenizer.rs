@@ -1,11 +1,95 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::{collections::HashMap, env, path::PathBuf, vec};
 
 use nanogpt::tokenizer::{
-    models::{character::Character, ModelWrapper},
+    models::{
+        bpe::{trainer::BpeTrainer, Bpe},
+        byte_level::{bytes_to_char, trainer::ByteLevelBpeTrainer, ByteLevelBpe},
+        character::{trainer::CharacterTrainer, Character},
+        unigram::{trainer::UnigramTrainer, Unigram},
+        wordpiece::{trainer::WordPieceTrainer, WordPiece},
+        ModelWrapper,
+    },
+    normalizers::{
+        Lowercase, Nfc, Nfd, Nfkc, Nfkd, NormalizerWrapper, Sequence, Strip, StripAccents,
+    },
+    pre_tokenizers::{
+        self, ByteLevel, Digits, Metaspace, PreTokenizerWrapper, Punctuation, Whitespace,
+    },
+    trainer::TrainerWrapper,
     Tokenizer,
 };
 
+#[derive(ValueEnum, Clone, Debug, PartialEq)]
+enum WhichTokenizer {
+    Character,
+    Bpe,
+    /// BPE over UTF-8 bytes, which can encode any text
+    ByteLevel,
+    /// SentencePiece-style unigram language model
+    Unigram,
+    /// BERT-style WordPiece, which drops whitespace
+    WordPiece,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum WhichNormalizer {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+    Lowercase,
+    /// Drop combining marks; use after nfd or nfkd
+    StripAccents,
+    /// Trim leading and trailing whitespace
+    Strip,
+}
+
+impl From<WhichNormalizer> for NormalizerWrapper {
+    fn from(n: WhichNormalizer) -> Self {
+        match n {
+            WhichNormalizer::Nfc => Self::Nfc(Nfc),
+            WhichNormalizer::Nfd => Self::Nfd(Nfd),
+            WhichNormalizer::Nfkc => Self::Nfkc(Nfkc),
+            WhichNormalizer::Nfkd => Self::Nfkd(Nfkd),
+            WhichNormalizer::Lowercase => Self::Lowercase(Lowercase),
+            WhichNormalizer::StripAccents => Self::StripAccents(StripAccents),
+            WhichNormalizer::Strip => Self::Strip(Strip {
+                left: true,
+                right: true,
+            }),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum WhichPreTokenizer {
+    /// Split on whitespace, dropping it
+    Whitespace,
+    /// Give each punctuation character a piece of its own
+    Punctuation,
+    /// Give each digit a piece of its own
+    Digits,
+    /// GPT-2 regex split and byte spelling, for the bpe model
+    ByteLevel,
+    /// SentencePiece-style: spaces become `▁`, starting each word
+    Metaspace,
+}
+
+impl From<WhichPreTokenizer> for PreTokenizerWrapper {
+    fn from(p: WhichPreTokenizer) -> Self {
+        match p {
+            WhichPreTokenizer::Whitespace => Self::Whitespace(Whitespace),
+            WhichPreTokenizer::Punctuation => Self::Punctuation(Punctuation),
+            WhichPreTokenizer::Digits => Self::Digits(Digits {
+                individual_digits: true,
+            }),
+            WhichPreTokenizer::ByteLevel => Self::ByteLevel(ByteLevel::default()),
+            WhichPreTokenizer::Metaspace => Self::Metaspace(Metaspace::default()),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(version, long_about=None)]
 struct Args {
@@ -16,26 +100,125 @@ struct Args {
     /// Path to persist trained tokenizer to
     #[arg(short, long)]
     outdir: PathBuf,
+
+    /// Tokenizer model to train
+    #[arg(long, default_value = "character")]
+    model: WhichTokenizer,
+
+    /// Target vocab size, special tokens included (all but character)
+    #[arg(long, default_value_t = 1000)]
+    vocab_size: usize,
+
+    /// Don't merge pairs seen fewer times than this (BPE, byte-level and WordPiece only)
+    #[arg(long, default_value_t = 2)]
+    min_frequency: u64,
+
+    /// Tokens to reserve at the start of the vocab, matched in text before the model
+    #[arg(long)]
+    special_tokens: Vec<String>,
+
+    /// Normalize text before tokenizing. Repeat to apply several, in order
+    #[arg(long)]
+    normalizer: Vec<WhichNormalizer>,
+
+    /// Split normalized text into pieces before tokenizing. Repeat to apply several, in order
+    #[arg(long)]
+    pre_tokenizer: Vec<WhichPreTokenizer>,
+
+    /// Save in the Hugging Face tokenizer.json format instead of our own
+    #[arg(long)]
+    hf: bool,
 }
 
 fn main() {
     let args = Args::parse();
 
     // Init model and trainer
-    let model = Character::new(HashMap::new());
-    let mut tokenizer = Tokenizer::new(ModelWrapper::Character(model));
+    let model: ModelWrapper = match args.model {
+        WhichTokenizer::Character => Character::new(HashMap::new()).into(),
+        WhichTokenizer::Bpe => Bpe::new(HashMap::new(), Vec::new()).unwrap().into(),
+        WhichTokenizer::ByteLevel => {
+            ByteLevelBpe::new(Bpe::new(HashMap::new(), Vec::new()).unwrap()).into()
+        }
+        WhichTokenizer::Unigram => Unigram::new(Vec::new(), None).unwrap().into(),
+        WhichTokenizer::WordPiece => WordPiece::new(HashMap::new()).into(),
+    };
+    let trainer = match args.model {
+        WhichTokenizer::Character => {
+            let mut trainer = CharacterTrainer::new();
+            trainer.special_tokens = args.special_tokens;
+            TrainerWrapper::CharacterTrainer(trainer)
+        }
+        WhichTokenizer::Bpe => {
+            let mut trainer = BpeTrainer::new();
+            trainer.vocab_size = args.vocab_size;
+            trainer.min_frequency = args.min_frequency;
+            trainer.special_tokens = args.special_tokens;
+            // Every byte, so any text can be encoded
+            if args.pre_tokenizer.contains(&WhichPreTokenizer::ByteLevel) {
+                trainer.initial_alphabet = bytes_to_char().to_vec();
+            }
+            TrainerWrapper::BpeTrainer(trainer)
+        }
+        WhichTokenizer::ByteLevel => {
+            let mut trainer = ByteLevelBpeTrainer::new();
+            trainer.bpe.vocab_size = args.vocab_size;
+            trainer.bpe.min_frequency = args.min_frequency;
+            trainer.bpe.special_tokens = args.special_tokens;
+            TrainerWrapper::ByteLevelBpeTrainer(trainer)
+        }
+        WhichTokenizer::Unigram => {
+            let mut trainer = UnigramTrainer::new();
+            trainer.vocab_size = args.vocab_size;
+            trainer.special_tokens = args.special_tokens;
+            TrainerWrapper::UnigramTrainer(trainer)
+        }
+        WhichTokenizer::WordPiece => {
+            let mut trainer = WordPieceTrainer::new();
+            trainer.bpe.vocab_size = args.vocab_size;
+            trainer.bpe.min_frequency = args.min_frequency;
+            trainer.bpe.special_tokens.extend(args.special_tokens);
+            TrainerWrapper::WordPieceTrainer(trainer)
+        }
+    };
+    let normalizer = match args.normalizer.as_slice() {
+        [] => None,
+        [n] => Some((*n).into()),
+        normalizers => Some(NormalizerWrapper::Sequence(Sequence {
+            normalizers: normalizers.iter().map(|&n| n.into()).collect(),
+        })),
+    };
+    let pre_tokenizer = match args.pre_tokenizer.as_slice() {
+        [] => None,
+        [p] => Some((*p).into()),
+        pre_tokenizers => Some(PreTokenizerWrapper::Sequence(pre_tokenizers::Sequence {
+            pre_tokenizers: pre_tokenizers.iter().map(|&p| p.into()).collect(),
+        })),
+    };
+    let mut tokenizer = Tokenizer::new(model)
+        .with_normalizer(normalizer)
+        .with_pre_tokenizer(pre_tokenizer)
+        .unwrap();
 
     // Load contents
     let cwd = env::current_dir().unwrap();
     let input_file_path: PathBuf = [&cwd, &args.infile].iter().collect();
 
-    tokenizer.train_from_files(vec![input_file_path]).unwrap();
+    tokenizer
+        .train_from_files_with(trainer, vec![input_file_path])
+        .unwrap();
 
     // Persist
     let out_dir: PathBuf = [&cwd, &args.outdir].iter().collect();
-    let out_paths = tokenizer
-        .save(&out_dir, Some("shakespeare-tokenizer"))
-        .unwrap();
+    let out_paths = if args.hf {
+        tokenizer
+            .save_hf(&out_dir, Some("shakespeare-tokenizer"))
+            .unwrap()
+    } else {
+        tokenizer
+            .save(&out_dir, Some("shakespeare-tokenizer"))
+            .unwrap()
+    };
 
     println!(
         "Tokenizer saved to {:?}, vocab size: {}",
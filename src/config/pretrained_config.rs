@@ -1,6 +1,7 @@
 use std::fs::{self, read_to_string};
 use std::path::PathBuf;
 
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use thiserror::Error;
@@ -14,6 +15,53 @@ pub enum ConfigError {
     IoError(std::io::Error),
 }
 
+/// How token positions are encoded
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PositionEmbeddingType {
+    /// Trained `wpe` table, as in GPT-2
+    #[default]
+    Learned,
+    /// Fixed sin/cos table from "Attention Is All You Need"
+    Sinusoidal,
+    /// Rotary embeddings applied to queries and keys in every attention layer
+    Rotary,
+    /// No positional information
+    None,
+}
+
+/// Stretch RoPE beyond the context it was trained on
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RopeScaling {
+    /// Divide positions by `factor`
+    Linear { factor: f32 },
+    /// Scale the frequency base ("NTK-aware" interpolation)
+    Ntk { factor: f32 },
+}
+
+/// What the KV cache does once generation runs past `context_size`
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheEviction {
+    /// Clear the cache and re-run the last `context_size` tokens, as nanoGPT crops.
+    /// Exact, but every step past the window costs a full forward pass
+    #[default]
+    Recompute,
+    /// Drop the oldest cached keys/values and keep going, re-rotating keys to their place in
+    /// the window. Cheap, but cached values still carry context from dropped tokens, so logits
+    /// drift from the exact cropped window. Rotary or no position embeddings only
+    Rolling,
+}
+
+fn default_rope_theta() -> f32 {
+    10000.0
+}
+
+fn default_rotary_pct() -> f32 {
+    1.0
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct PretrainedConfig {
     pub architecture: String,
@@ -26,6 +74,19 @@ pub struct PretrainedConfig {
     pub num_attention_heads: u32,
     pub num_key_value_heads: u32,
     pub hidden_layers: u32,
+    #[serde(default)]
+    pub position_embedding: PositionEmbeddingType,
+    /// Base of the RoPE frequencies. Only used with rotary position embeddings
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    /// Fraction of each attention head rotated by RoPE
+    #[serde(default = "default_rotary_pct")]
+    pub rotary_pct: f32,
+    /// Set when running a rotary model past the context it was trained on
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
+    #[serde(default)]
+    pub cache_eviction: CacheEviction,
     /// Name of tokenizer used
     pub tokenizer_id: String,
 }
@@ -60,6 +121,11 @@ mod tests {
             num_attention_heads: 1,
             num_key_value_heads: 1,
             hidden_layers: 1,
+            position_embedding: PositionEmbeddingType::Rotary,
+            rope_theta: 10000.0,
+            rotary_pct: 0.25,
+            rope_scaling: Some(RopeScaling::Ntk { factor: 4.0 }),
+            cache_eviction: CacheEviction::Rolling,
             tokenizer_id: "rick-astley-base-100k".into(),
         };
         sample_config.to_json_file(&out_path).unwrap();
@@ -13,6 +13,9 @@ pub struct TrainingConfig {
     pub load_from: Option<String>,
     /// Safetensors filename to save weights to.
     pub save_to: Option<String>,
+    /// Seeds weight init and data shuffling. Random if unset
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
 impl TrainingConfig {
@@ -23,6 +26,7 @@ impl TrainingConfig {
             batch_size: 32,
             load_from: None,
             save_to: Some("models/bigram/model.safetensors".into()),
+            seed: Some(1337),
         }
     }
 
@@ -33,6 +37,7 @@ impl TrainingConfig {
             batch_size: 32,
             load_from: None,
             save_to: Some("models/transformer/model.safetensors".into()),
+            seed: Some(1337),
         }
     }
 
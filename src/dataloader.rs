@@ -2,7 +2,7 @@ use crate::datasets::TextDataset;
 use candle_core::error::Error;
 use candle_core::{Device, Tensor};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::Rng;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -46,10 +46,12 @@ impl<'a> Iterator for TextDatasetIterator<'a> {
 }
 
 impl<'a> TextDatasetIterator<'a> {
-    pub fn new(
+    /// Windows are visited in an order shuffled by `rng`
+    pub fn new<R: Rng>(
         dataset: &'a TextDataset,
         context_len: usize,
         device: &'a Device,
+        rng: &mut R,
     ) -> Result<Self, TextDatasetIteratorError> {
         // Shuffle indices
         if context_len >= dataset.len() {
@@ -64,7 +66,7 @@ impl<'a> TextDatasetIterator<'a> {
         let mut start_indices: Vec<usize> = (0..dataset.len() - context_len)
             .step_by(context_len)
             .collect();
-        start_indices.shuffle(&mut thread_rng());
+        start_indices.shuffle(rng);
         Ok(TextDatasetIterator {
             dataset,
             device,
@@ -109,6 +111,7 @@ mod tests {
     use crate::datasets::TextDataset;
     use candle_core::{error::Error, Tensor};
     use candle_datasets::Batcher;
+    use rand::{rngs::StdRng, SeedableRng};
 
     #[test]
     fn test_correctness() {
@@ -116,7 +119,8 @@ mod tests {
             token_ids: (0..65).collect(),
         };
         let device = candle_core::Device::cuda_if_available(0).unwrap();
-        let mut iterator = TextDatasetIterator::new(&dataset, 8, &device).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut iterator = TextDatasetIterator::new(&dataset, 8, &device, &mut rng).unwrap();
         let first_batch = iterator.next();
 
         // We can retrieve a single batch
@@ -141,8 +145,25 @@ mod tests {
             token_ids: (0..65).collect(),
         };
         let device = candle_core::Device::cuda_if_available(0).unwrap();
-        let iterator = TextDatasetIterator::new(&dataset, 8, &device).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let iterator = TextDatasetIterator::new(&dataset, 8, &device, &mut rng).unwrap();
         let mut batcher = Batcher::new_r2(iterator).batch_size(8);
         assert!(batcher.next().is_some());
     }
+
+    #[test]
+    fn test_seeded_shuffle() {
+        let dataset = TextDataset {
+            token_ids: (0..65).collect(),
+        };
+        let device = candle_core::Device::Cpu;
+        let order = |seed: u64| -> Vec<Vec<u32>> {
+            let mut rng = StdRng::seed_from_u64(seed);
+            TextDatasetIterator::new(&dataset, 8, &device, &mut rng)
+                .unwrap()
+                .map(|batch| batch.unwrap().0.to_vec1().unwrap())
+                .collect()
+        };
+        assert_eq!(order(7), order(7));
+    }
 }
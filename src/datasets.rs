@@ -47,7 +47,7 @@ impl TextDataset {
         &self,
         test_pct: f64,
     ) -> Result<(TextDataset, TextDataset), DatasetError> {
-        if test_pct > 1.0 || test_pct < 0.0 {
+        if !(0.0..=1.0).contains(&test_pct) {
             return Err(DatasetError::InvalidSplit(format!(
                 "{} is not in 0..1",
                 test_pct
@@ -69,4 +69,8 @@ impl TextDataset {
     pub fn len(&self) -> usize {
         self.token_ids.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.token_ids.is_empty()
+    }
 }
@@ -0,0 +1,190 @@
+use std::rc::Rc;
+
+use candle_core::{Error, Result};
+use serde::Serialize;
+
+use crate::sampling::argsort_desc;
+use crate::tokenizer::Tokenizer;
+
+pub mod beam;
+pub mod constrained;
+pub mod speculative;
+
+use constrained::Constraint;
+
+/// Why a row stopped generating
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    /// Produced `max_new_tokens` without hitting anything else
+    MaxTokens,
+    /// Sampled the end-of-sequence token
+    Eos,
+    /// Decoded text contains this stop string
+    StopString(String),
+    /// Output matches the decoding constraint and no token can extend it
+    Constraint,
+}
+
+/// When to end a row early.
+///
+/// Stop strings are matched on decoded text, so they can span several tokens.
+#[derive(Default)]
+pub struct StoppingCriteria<'a> {
+    pub eos_token_id: Option<u32>,
+    pub stop_strings: Vec<String>,
+    /// Shared with the `Sampler` that masks logits to it
+    pub constraint: Option<Rc<Constraint>>,
+    tokenizer: Option<&'a Tokenizer>,
+}
+
+impl<'a> StoppingCriteria<'a> {
+    /// Stop on the tokenizer's EOS token, if it has one, or any of `stop_strings`
+    pub fn new(tokenizer: &'a Tokenizer, stop_strings: Vec<String>) -> Self {
+        Self {
+            eos_token_id: tokenizer.eos_token_id(),
+            stop_strings,
+            constraint: None,
+            tokenizer: Some(tokenizer),
+        }
+    }
+
+    /// Check a row after each new token. `generated` excludes the prompt
+    pub fn check(&self, generated: &[u32]) -> Result<Option<StopReason>> {
+        if self.eos_token_id.is_some() && generated.last() == self.eos_token_id.as_ref() {
+            return Ok(Some(StopReason::Eos));
+        }
+        if let Some(constraint) = &self.constraint {
+            if constraint.is_complete(generated)? {
+                return Ok(Some(StopReason::Constraint));
+            }
+        }
+        let Some(tokenizer) = self.tokenizer else {
+            return Ok(None);
+        };
+        if self.stop_strings.iter().any(String::is_empty) {
+            return Err(Error::Msg("Stop strings can't be empty".into()));
+        }
+        // Every token decodes to at least one byte, so a stop string completed by the
+        // newest token lies within this many trailing tokens
+        let Some(window) = self.stop_strings.iter().map(|s| s.len()).max() else {
+            return Ok(None);
+        };
+        let tail = &generated[generated.len().saturating_sub(window)..];
+        let text = tokenizer
+            .decode(tail)
+            .map_err(|e| Error::Msg(e.to_string()))?;
+        Ok(self
+            .stop_strings
+            .iter()
+            .find(|s| text.contains(s.as_str()))
+            .map(|s| StopReason::StopString(s.clone())))
+    }
+}
+
+/// Log-prob of one token under the model, before any sampling processors
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenLogprob {
+    pub id: u32,
+    /// Filled in by `decode`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    pub logprob: f32,
+    /// Most likely tokens at this position, best first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_logprobs: Vec<TokenLogprob>,
+}
+
+impl TokenLogprob {
+    /// Pick `id` and the `top_n` best alternatives out of a row of log-probs
+    pub fn from_row(id: u32, log_probs: &[f32], top_n: usize) -> Self {
+        let alternative = |id: usize| Self {
+            id: id as u32,
+            token: None,
+            logprob: log_probs[id],
+            top_logprobs: Vec::new(),
+        };
+        Self {
+            top_logprobs: argsort_desc(log_probs)
+                .into_iter()
+                .take(top_n)
+                .map(alternative)
+                .collect(),
+            ..alternative(id as usize)
+        }
+    }
+
+    /// Look up the text of this token and its alternatives
+    pub fn decode(&mut self, tokenizer: &Tokenizer) {
+        self.token = tokenizer.id_to_token(self.id);
+        for alternative in self.top_logprobs.iter_mut() {
+            alternative.decode(tokenizer);
+        }
+    }
+}
+
+/// Output of `Model::generate_until`
+#[derive(Debug, Clone)]
+pub struct Generation {
+    /// Prompt plus generated tokens for each row, up to and including the one that stopped it
+    pub ids: Vec<Vec<u32>>,
+    pub stop_reasons: Vec<StopReason>,
+    /// One entry per generated token in each row, if the sampler asked for log-probs
+    pub logprobs: Vec<Vec<TokenLogprob>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{StopReason, StoppingCriteria, TokenLogprob};
+    use crate::tokenizer::models::character::Character;
+    use crate::tokenizer::Tokenizer;
+
+    #[test]
+    fn test_stop_strings() {
+        let vocab: HashMap<String, u32> = ["a", "b", "\n", "</s>"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.to_string(), i as u32))
+            .collect();
+        let tokenizer = Tokenizer::new(Character::new(vocab).into());
+        let criteria = StoppingCriteria::new(&tokenizer, vec!["\n\n".into(), "ab".into()]);
+        assert_eq!(criteria.eos_token_id, Some(3));
+
+        assert_eq!(criteria.check(&[0, 0]).unwrap(), None);
+        assert_eq!(criteria.check(&[0, 3]).unwrap(), Some(StopReason::Eos));
+        assert_eq!(
+            criteria.check(&[0, 0, 1]).unwrap(),
+            Some(StopReason::StopString("ab".into()))
+        );
+        assert_eq!(
+            criteria.check(&[1, 2, 2]).unwrap(),
+            Some(StopReason::StopString("\n\n".into()))
+        );
+
+        // Errors rather than running on as if nothing matched
+        assert!(criteria.check(&[0, 9]).is_err());
+        let empty = StoppingCriteria::new(&tokenizer, vec!["".into()]);
+        assert!(empty.check(&[0]).is_err());
+    }
+
+    #[test]
+    fn test_token_logprob() {
+        let vocab: HashMap<String, u32> = ["a", "b", "c"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.to_string(), i as u32))
+            .collect();
+        let tokenizer = Tokenizer::new(Character::new(vocab).into());
+        let mut logprob = TokenLogprob::from_row(2, &[-1.0, -0.5, -3.0], 2);
+        logprob.decode(&tokenizer);
+        assert_eq!(logprob.token.as_deref(), Some("c"));
+        assert_eq!(logprob.logprob, -3.0);
+        let top: Vec<(&str, f32)> = logprob
+            .top_logprobs
+            .iter()
+            .map(|t| (t.token.as_deref().unwrap(), t.logprob))
+            .collect();
+        assert_eq!(top, [("b", -0.5), ("a", -1.0)]);
+    }
+}
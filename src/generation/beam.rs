@@ -0,0 +1,230 @@
+use std::cmp::Ordering;
+
+use candle_core::{Error, IndexOp, Result, Tensor, D};
+use candle_nn::ops;
+
+use super::StoppingCriteria;
+use crate::models::Model;
+
+/// Deterministic decoding that keeps the `num_beams` most likely continuations
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+#[command(about = None, long_about = None, next_help_heading = "Beam search")]
+pub struct BeamSearchConfig {
+    /// Use beam search with this many beams instead of sampling
+    #[arg(long)]
+    pub num_beams: Option<usize>,
+
+    /// Finished beams are ranked by log-prob / length^length_penalty. Above 0 favors longer outputs
+    #[arg(long, default_value_t = 1.0, allow_hyphen_values = true)]
+    pub length_penalty: f32,
+
+    /// Stop as soon as `num_beams` hypotheses have finished, even if a running beam could still win
+    #[arg(long)]
+    pub early_stopping: bool,
+
+    /// How many of the best hypotheses to return
+    #[arg(long, default_value_t = 1)]
+    pub num_return_sequences: usize,
+}
+
+impl Default for BeamSearchConfig {
+    fn default() -> Self {
+        Self {
+            num_beams: None,
+            length_penalty: 1.0,
+            early_stopping: false,
+            num_return_sequences: 1,
+        }
+    }
+}
+
+/// One finished beam
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// Prompt plus generated tokens
+    pub ids: Vec<u32>,
+    /// Sum of the generated tokens' log-probs
+    pub log_prob: f32,
+    /// `log_prob` after the length penalty, used for ranking
+    pub score: f32,
+}
+
+impl BeamSearchConfig {
+    fn score(&self, log_prob: f32, generated: usize) -> f32 {
+        log_prob / (generated.max(1) as f32).powf(self.length_penalty)
+    }
+}
+
+/// Beam search from a (1, T) prompt, returning up to `num_return_sequences` hypotheses, best first
+pub fn beam_search<M: Model>(
+    model: &M,
+    idx: &Tensor,
+    max_new_tokens: usize,
+    cfg: &BeamSearchConfig,
+    stopping: &StoppingCriteria,
+) -> Result<Vec<Hypothesis>> {
+    let (b, prompt_len) = idx.dims2()?;
+    if b != 1 {
+        return Err(Error::Msg(format!(
+            "Beam search takes a single prompt, got a batch of {}",
+            b
+        )));
+    }
+    let num_beams = cfg.num_beams.unwrap_or(1).max(1);
+    let prompt: Vec<u32> = idx.i(0)?.to_vec1()?;
+
+    // Running beams as (generated tokens, cumulative log-prob), in the same order as `preds`
+    let mut beams: Vec<(Vec<u32>, f32)> = vec![(Vec::new(), 0.0)];
+    let mut finished: Vec<Hypothesis> = Vec::new();
+    let mut cache = model.new_cache();
+    let mut preds = idx.clone();
+    let mut n_new = prompt_len;
+    for _ in 0..max_new_tokens {
+        let input = cache.window(&preds, n_new, model.context_size(), model.cache_eviction())?;
+        let logits = model.forward_step(&input, &mut cache)?;
+        let logits = logits.i((.., logits.dim(1)? - 1, ..))?;
+        let log_probs: Vec<Vec<f32>> = ops::log_softmax(&logits, D::Minus1)?
+            .to_dtype(candle_core::DType::F32)?
+            .to_vec2()?;
+
+        // Best 2 * num_beams continuations per beam are enough to fill the next beams
+        // even if up to half of them finish
+        let mut candidates: Vec<(usize, u32, f32)> = Vec::new();
+        for (beam, row) in log_probs.iter().enumerate() {
+            let mut ids: Vec<usize> = (0..row.len()).collect();
+            ids.sort_by(|&i, &j| row[j].partial_cmp(&row[i]).unwrap_or(Ordering::Equal));
+            candidates.extend(
+                ids.into_iter()
+                    .take(2 * num_beams)
+                    .map(|id| (beam, id as u32, beams[beam].1 + row[id])),
+            );
+        }
+        candidates.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(Ordering::Equal));
+
+        let mut next_beams: Vec<(Vec<u32>, f32)> = Vec::new();
+        let mut parents: Vec<u32> = Vec::new();
+        for (rank, (beam, token, log_prob)) in candidates.into_iter().enumerate() {
+            if next_beams.len() == num_beams {
+                break;
+            }
+            let mut generated = beams[beam].0.clone();
+            generated.push(token);
+            if stopping.check(&generated)?.is_some() {
+                // Only keep finished beams that would have made the cut
+                if rank < num_beams {
+                    finished.push(Hypothesis {
+                        score: cfg.score(log_prob, generated.len()),
+                        ids: [prompt.as_slice(), &generated].concat(),
+                        log_prob,
+                    });
+                }
+            } else {
+                next_beams.push((generated, log_prob));
+                parents.push(beam as u32);
+            }
+        }
+        beams = next_beams;
+        if beams.is_empty() || is_done(&finished, &beams, num_beams, cfg) {
+            break;
+        }
+
+        let parents = Tensor::new(parents, idx.device())?;
+        cache.reorder(&parents)?;
+        let next_tokens: Vec<u32> = beams.iter().map(|(g, _)| *g.last().unwrap()).collect();
+        let next_tokens = Tensor::new(next_tokens, idx.device())?.reshape((beams.len(), 1))?;
+        preds = Tensor::cat(
+            &[
+                &preds.contiguous()?.index_select(&parents, 0)?,
+                &next_tokens,
+            ],
+            1,
+        )?;
+        n_new = 1;
+    }
+
+    // Out of tokens: running beams count as finished
+    finished.extend(beams.into_iter().map(|(generated, log_prob)| Hypothesis {
+        score: cfg.score(log_prob, generated.len()),
+        ids: [prompt.as_slice(), &generated].concat(),
+        log_prob,
+    }));
+    finished.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    finished.truncate(cfg.num_return_sequences.max(1));
+    Ok(finished)
+}
+
+/// Whether enough beams have finished that no running beam is expected to beat them
+fn is_done(
+    finished: &[Hypothesis],
+    beams: &[(Vec<u32>, f32)],
+    num_beams: usize,
+    cfg: &BeamSearchConfig,
+) -> bool {
+    if finished.len() < num_beams {
+        return false;
+    }
+    if cfg.early_stopping {
+        return true;
+    }
+    let mut scores: Vec<f32> = finished.iter().map(|h| h.score).collect();
+    scores.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    let worst_kept = scores[num_beams - 1];
+    let best_running = beams
+        .iter()
+        .map(|(g, log_prob)| cfg.score(*log_prob, g.len()))
+        .fold(f32::NEG_INFINITY, f32::max);
+    best_running <= worst_kept
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::VarMap;
+
+    use super::{beam_search, BeamSearchConfig};
+    use crate::generation::StoppingCriteria;
+    use crate::models::bigram::{Bigram, Config};
+    use crate::models::Model;
+    use crate::sampling::{Sampler, SamplingConfig};
+    use crate::util::seeded_var_builder;
+
+    #[test]
+    fn test_beam_search() {
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vs = seeded_var_builder(&varmap, 1337, DType::F32, &device);
+        let model = Bigram::new(vs, &Config { vocab_size: 8 }).unwrap();
+        let idx = Tensor::new(&[[0u32]], &device).unwrap();
+
+        // One beam is greedy decoding
+        let greedy: Vec<Vec<u32>> = model
+            .generate(
+                &idx,
+                6,
+                &mut Sampler::new(&SamplingConfig {
+                    greedy: true,
+                    ..Default::default()
+                }),
+            )
+            .unwrap()
+            .to_vec2()
+            .unwrap();
+        let cfg = BeamSearchConfig {
+            num_beams: Some(1),
+            ..Default::default()
+        };
+        let best = beam_search(&model, &idx, 6, &cfg, &StoppingCriteria::default()).unwrap();
+        assert_eq!(best[0].ids, greedy[0]);
+
+        // Wider beams return distinct hypotheses, best first
+        let cfg = BeamSearchConfig {
+            num_beams: Some(4),
+            num_return_sequences: 3,
+            ..Default::default()
+        };
+        let n_best = beam_search(&model, &idx, 6, &cfg, &StoppingCriteria::default()).unwrap();
+        assert_eq!(n_best.len(), 3);
+        assert_ne!(n_best[0].ids, n_best[1].ids);
+        assert!(n_best[0].score >= n_best[1].score && n_best[1].score >= n_best[2].score);
+    }
+}
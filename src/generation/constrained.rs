@@ -0,0 +1,314 @@
+use std::collections::{HashMap, HashSet};
+
+use candle_core::{Error, Result};
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::Anchored;
+
+use crate::sampling::LogitsProcessor;
+use crate::tokenizer::Tokenizer;
+
+pub mod grammar;
+
+/// Restricts generated text to the language of a regex, by masking every token that would
+/// lead outside it.
+///
+/// The regex is compiled to a DFA once; each step walks it over the text generated so far,
+/// then over every vocab entry. Grammars and JSON schemas are first turned into a regex.
+pub struct Constraint {
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+    /// States from which some continuation still matches. The DFA only dies one byte late
+    /// after a match, so not being dead isn't enough
+    live: HashSet<StateID>,
+    /// Text each token id adds after another token, or `None` for EOS and other special
+    /// tokens, which never spell anything out
+    tokens: Vec<Option<Vec<u8>>>,
+    eos_token_id: Option<u32>,
+}
+
+impl Constraint {
+    /// Generated text must match all of `pattern`
+    pub fn regex(pattern: &str, tokenizer: &Tokenizer) -> Result<Self> {
+        let dfa = dense::Builder::new()
+            .configure(dense::Config::new().start_kind(StartKind::Anchored))
+            .build(pattern)
+            .map_err(|e| Error::Msg(format!("Invalid constraint regex: {}", e)))?;
+        let start = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(Error::wrap)?;
+        let live = live_states(&dfa, start);
+        let eos_token_id = tokenizer.eos_token_id();
+        let mut tokens = vec![None; tokenizer.get_vocab_size()];
+        let mut ids: Vec<u32> = tokenizer
+            .get_vocab()
+            .into_values()
+            .filter(|&id| {
+                Some(id) != eos_token_id
+                    && !tokenizer.is_special(id)
+                    && (id as usize) < tokens.len()
+            })
+            .collect();
+        ids.sort_unstable();
+        // Decoders join tokens depending on what comes before (WordPiece spaces and `##`,
+        // Metaspace's leading space), so each token is decoded after a fixed one, as it would
+        // be mid-generation
+        let prefix = ids.iter().find_map(|&id| {
+            tokenizer
+                .decode_bytes(&[id])
+                .ok()
+                .filter(|bytes| !bytes.is_empty())
+                .map(|bytes| (id, bytes))
+        });
+        let Some((prefix_id, prefix)) = prefix else {
+            return Err(Error::Msg(
+                "No token in the vocab spells out any text".into(),
+            ));
+        };
+        for id in ids {
+            // A token that rewrites the text before it can't be matched piecewise
+            let text = tokenizer
+                .decode_bytes(&[prefix_id, id])
+                .ok()
+                .and_then(|bytes| bytes.strip_prefix(prefix.as_slice()).map(<[u8]>::to_vec));
+            if let Some(text) = text.filter(|text| !text.is_empty()) {
+                tokens[id as usize] = Some(text);
+            }
+        }
+        Ok(Self {
+            dfa,
+            start,
+            live,
+            tokens,
+            eos_token_id,
+        })
+    }
+
+    /// Generated text must be derivable from the `root` (or first) rule of an EBNF grammar
+    pub fn ebnf(grammar: &str, tokenizer: &Tokenizer) -> Result<Self> {
+        Self::regex(&grammar::ebnf_to_regex(grammar)?, tokenizer)
+    }
+
+    /// Generated text must be JSON matching `schema`
+    pub fn json_schema(schema: &serde_json::Value, tokenizer: &Tokenizer) -> Result<Self> {
+        Self::regex(&grammar::json_schema_to_regex(schema)?, tokenizer)
+    }
+
+    fn walk(&self, mut state: StateID, bytes: &[u8]) -> Option<StateID> {
+        for &b in bytes {
+            state = self.dfa.next_state(state, b);
+            if !self.live.contains(&state) {
+                return None;
+            }
+        }
+        Some(state)
+    }
+
+    /// DFA state after `generated`, or `None` if it already left the language. Ids past the
+    /// tokenizer's vocab (e.g. a model's padded vocab) never spell anything allowed
+    fn state(&self, generated: &[u32]) -> Result<Option<StateID>> {
+        Ok(generated
+            .iter()
+            .filter(|&&id| Some(id) != self.eos_token_id)
+            .try_fold(self.start, |state, &id| {
+                match self.tokens.get(id as usize) {
+                    Some(Some(bytes)) => self.walk(state, bytes),
+                    _ => None,
+                }
+            }))
+    }
+
+    fn is_accepting(&self, state: StateID) -> bool {
+        is_accepting(&self.dfa, state)
+    }
+
+    /// Which ids may come next. EOS is allowed once the text is a full match
+    pub fn allowed(&self, generated: &[u32]) -> Result<Vec<bool>> {
+        let mut allowed = vec![false; self.tokens.len()];
+        let Some(state) = self.state(generated)? else {
+            return Ok(allowed);
+        };
+        for (allowed, bytes) in allowed.iter_mut().zip(self.tokens.iter()) {
+            *allowed = bytes
+                .as_ref()
+                .is_some_and(|bytes| self.walk(state, bytes).is_some());
+        }
+        if let Some(allowed) = self
+            .eos_token_id
+            .and_then(|eos| allowed.get_mut(eos as usize))
+        {
+            *allowed = self.is_accepting(state);
+        }
+        Ok(allowed)
+    }
+
+    /// Whether `generated` is a full match that no token can extend
+    pub fn is_complete(&self, generated: &[u32]) -> Result<bool> {
+        let Some(state) = self.state(generated)? else {
+            return Ok(false);
+        };
+        Ok(self.is_accepting(state)
+            && self
+                .tokens
+                .iter()
+                .flatten()
+                .all(|bytes| self.walk(state, bytes).is_none()))
+    }
+}
+
+fn is_accepting(dfa: &dense::DFA<Vec<u32>>, state: StateID) -> bool {
+    dfa.is_match_state(dfa.next_eoi_state(state))
+}
+
+/// States reachable from `start` that can still reach a match
+fn live_states(dfa: &dense::DFA<Vec<u32>>, start: StateID) -> HashSet<StateID> {
+    let mut predecessors: HashMap<StateID, Vec<StateID>> = HashMap::new();
+    let mut seen = HashSet::from([start]);
+    let mut frontier = vec![start];
+    while let Some(state) = frontier.pop() {
+        for b in 0..=255u8 {
+            let next = dfa.next_state(state, b);
+            if dfa.is_dead_state(next) {
+                continue;
+            }
+            predecessors.entry(next).or_default().push(state);
+            if seen.insert(next) {
+                frontier.push(next);
+            }
+        }
+    }
+    // Walk backwards from every accepting state
+    let mut live: HashSet<StateID> = seen.into_iter().filter(|&s| is_accepting(dfa, s)).collect();
+    let mut frontier: Vec<StateID> = live.iter().copied().collect();
+    while let Some(state) = frontier.pop() {
+        for &prev in predecessors.get(&state).into_iter().flatten() {
+            if live.insert(prev) {
+                frontier.push(prev);
+            }
+        }
+    }
+    live
+}
+
+impl LogitsProcessor for Constraint {
+    fn process(&self, _prompt: &[u32], generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        let allowed = self.allowed(generated)?;
+        if !allowed.contains(&true) {
+            return Err(Error::Msg(
+                "No token can continue the constrained output".into(),
+            ));
+        }
+        // Logits past the tokenizer's vocab are masked too
+        for (i, l) in logits.iter_mut().enumerate() {
+            if allowed.get(i) != Some(&true) {
+                *l = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::VarMap;
+
+    use super::Constraint;
+    use crate::generation::{StopReason, StoppingCriteria};
+    use crate::models::bigram::{Bigram, Config};
+    use crate::models::Model;
+    use crate::sampling::{LogitsProcessor, Sampler, SamplingConfig};
+    use crate::tokenizer::models::character::Character;
+    use crate::tokenizer::models::wordpiece::WordPiece;
+    use crate::tokenizer::Tokenizer;
+    use crate::util::seeded_var_builder;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab: HashMap<String, u32> = ["a", "b", "1", "2", "-", "</s>"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.to_string(), i as u32))
+            .collect();
+        Tokenizer::new(Character::new(vocab).into())
+    }
+
+    #[test]
+    fn test_allowed() {
+        let tokenizer = tokenizer();
+        let constraint = Constraint::regex("[ab]+-[12]", &tokenizer).unwrap();
+        assert_eq!(
+            constraint.allowed(&[]).unwrap(),
+            [true, true, false, false, false, false]
+        );
+        assert_eq!(
+            constraint.allowed(&[0, 1]).unwrap(),
+            [true, true, false, false, true, false]
+        );
+        // Complete: only EOS remains
+        assert_eq!(
+            constraint.allowed(&[0, 4, 3]).unwrap(),
+            [false, false, false, false, false, true]
+        );
+        assert!(constraint.is_complete(&[0, 4, 3]).unwrap());
+        assert!(!constraint.is_complete(&[0, 4]).unwrap());
+
+        // Ids the tokenizer doesn't know are rejected rather than panicking
+        assert!(!constraint.allowed(&[0, 9]).unwrap().contains(&true));
+        let mut logits = [0.0f32; 8];
+        constraint.process(&[], &[0], &mut logits).unwrap();
+        assert_eq!(logits[6..], [f32::NEG_INFINITY; 2]);
+    }
+
+    #[test]
+    fn test_wordpiece() {
+        let vocab: HashMap<String, u32> = [("hug".into(), 0), ("##s".into(), 1), ("bug".into(), 2)]
+            .into_iter()
+            .collect();
+        let tokenizer = Tokenizer::new(WordPiece::new(vocab).into());
+        // Words come out with the space that joins them, continuations without `##`
+        let constraint = Constraint::regex(" hugs( bug)?", &tokenizer).unwrap();
+        assert_eq!(constraint.allowed(&[]).unwrap(), [true, false, false]);
+        assert_eq!(constraint.allowed(&[0]).unwrap(), [false, true, false]);
+        assert_eq!(constraint.allowed(&[0, 1]).unwrap(), [false, false, true]);
+        assert!(constraint.is_complete(&[0, 1, 2]).unwrap());
+    }
+
+    #[test]
+    fn test_constrained_generate() {
+        let device = Device::Cpu;
+        let tokenizer = tokenizer();
+        let varmap = VarMap::new();
+        let vs = seeded_var_builder(&varmap, 1337, DType::F32, &device);
+        let model = Bigram::new(vs, &Config { vocab_size: 6 }).unwrap();
+
+        let constraint = Rc::new(Constraint::regex("(ab|ba)-[12]{2}", &tokenizer).unwrap());
+        for seed in 0..5 {
+            let mut sampler = Sampler::new(&SamplingConfig {
+                seed: Some(seed),
+                ..Default::default()
+            });
+            sampler.push(Box::new(constraint.clone()));
+            let mut stopping = StoppingCriteria::new(&tokenizer, Vec::new());
+            stopping.constraint = Some(constraint.clone());
+            let idx = Tensor::new(&[[0u32]], &device).unwrap();
+            let generation = model
+                .generate_until(&idx, 20, &mut sampler, &stopping)
+                .unwrap();
+            let text = tokenizer.decode(&generation.ids[0][1..]).unwrap();
+            let text = text.trim_end_matches("</s>");
+            assert!(
+                ["ab-", "ba-"].contains(&&text[..3]) && text.len() == 5,
+                "{:?}",
+                text
+            );
+            assert!(matches!(
+                generation.stop_reasons[0],
+                StopReason::Eos | StopReason::Constraint
+            ));
+        }
+    }
+}
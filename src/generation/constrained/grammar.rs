@@ -0,0 +1,361 @@
+//! Compiling simple grammars down to a regex for `Constraint`.
+//!
+//! Only regular languages fit in a DFA, so recursive EBNF rules and recursive schemas are
+//! rejected rather than approximated.
+use std::collections::HashMap;
+
+use candle_core::{Error, Result};
+use serde_json::Value;
+
+/// Optional single space between JSON tokens. Unbounded whitespace lets weak models ramble
+const JSON_WS: &str = "[ ]?";
+const JSON_STRING: &str = r#""([^"\\\x00-\x1F]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})*""#;
+const JSON_INTEGER: &str = "-?(0|[1-9][0-9]*)";
+const JSON_NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+
+/// Escape regex metacharacters in a literal
+fn escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Translate an EBNF grammar to a regex.
+///
+/// Rules look like `name ::= expr`, one per line (continuation lines don't contain `::=`),
+/// with `#` comments. Expressions support `"literals"`, `[character classes]`, rule
+/// references, grouping with `( )`, alternation with `|` and the postfix operators `*`, `+`
+/// and `?`. The start rule is `root`, or the first rule if there is none.
+pub fn ebnf_to_regex(grammar: &str) -> Result<String> {
+    let mut rules: Vec<(String, String)> = Vec::new();
+    for line in grammar.lines() {
+        let line = strip_comment(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.split_once("::=") {
+            Some((name, body)) => rules.push((name.trim().to_string(), body.to_string())),
+            None => match rules.last_mut() {
+                Some((_, body)) => {
+                    body.push(' ');
+                    body.push_str(line);
+                }
+                None => {
+                    return Err(Error::Msg(format!(
+                        "Grammar must start with a rule, got {:?}",
+                        line
+                    )))
+                }
+            },
+        }
+    }
+    let root = match rules.iter().find(|(name, _)| name == "root") {
+        Some((name, _)) => name.clone(),
+        None => match rules.first() {
+            Some((name, _)) => name.clone(),
+            None => return Err(Error::Msg("Grammar has no rules".into())),
+        },
+    };
+    let rules: HashMap<String, String> = rules.into_iter().collect();
+    let mut compiled = HashMap::new();
+    compile_rule(&root, &rules, &mut compiled, &mut Vec::new())
+}
+
+/// Drop a `#` comment, unless the `#` is inside a literal or class
+fn strip_comment(line: &str) -> &str {
+    let mut in_literal = false;
+    let mut in_class = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if !in_class => in_literal = !in_literal,
+            '[' if !in_literal => in_class = true,
+            ']' if !in_literal => in_class = false,
+            '#' if !in_literal && !in_class => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn compile_rule(
+    name: &str,
+    rules: &HashMap<String, String>,
+    compiled: &mut HashMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String> {
+    if let Some(regex) = compiled.get(name) {
+        return Ok(regex.clone());
+    }
+    if stack.iter().any(|n| n == name) {
+        return Err(Error::Msg(format!(
+            "Rule {:?} is recursive, which a regular constraint can't express",
+            name
+        )));
+    }
+    let body = rules
+        .get(name)
+        .ok_or_else(|| Error::Msg(format!("Undefined grammar rule {:?}", name)))?;
+    stack.push(name.to_string());
+    let chars: Vec<char> = body.chars().collect();
+    let mut pos = 0;
+    let regex = parse_alternation(&chars, &mut pos, rules, compiled, stack)?;
+    skip_whitespace(&chars, &mut pos);
+    if pos < chars.len() {
+        return Err(Error::Msg(format!(
+            "Unexpected {:?} in rule {:?}",
+            chars[pos], name
+        )));
+    }
+    stack.pop();
+    compiled.insert(name.to_string(), regex.clone());
+    Ok(regex)
+}
+
+fn skip_whitespace(chars: &[char], pos: &mut usize) {
+    while *pos < chars.len() && chars[*pos].is_whitespace() {
+        *pos += 1;
+    }
+}
+
+fn parse_alternation(
+    chars: &[char],
+    pos: &mut usize,
+    rules: &HashMap<String, String>,
+    compiled: &mut HashMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String> {
+    let mut alternatives = vec![parse_sequence(chars, pos, rules, compiled, stack)?];
+    skip_whitespace(chars, pos);
+    while chars.get(*pos) == Some(&'|') {
+        *pos += 1;
+        alternatives.push(parse_sequence(chars, pos, rules, compiled, stack)?);
+        skip_whitespace(chars, pos);
+    }
+    Ok(match alternatives.len() {
+        1 => alternatives.remove(0),
+        _ => format!("(?:{})", alternatives.join("|")),
+    })
+}
+
+fn parse_sequence(
+    chars: &[char],
+    pos: &mut usize,
+    rules: &HashMap<String, String>,
+    compiled: &mut HashMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String> {
+    let mut sequence = String::new();
+    loop {
+        skip_whitespace(chars, pos);
+        let atom = match chars.get(*pos) {
+            None | Some('|') | Some(')') => break,
+            Some('"') => {
+                *pos += 1;
+                let mut literal = String::new();
+                loop {
+                    match chars.get(*pos) {
+                        None => return Err(Error::Msg("Unterminated string literal".into())),
+                        Some('"') => break,
+                        Some('\\') => {
+                            *pos += 1;
+                            literal.push(match chars.get(*pos) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('r') => '\r',
+                                Some(&c) => c,
+                                None => {
+                                    return Err(Error::Msg("Unterminated string literal".into()))
+                                }
+                            });
+                        }
+                        Some(&c) => literal.push(c),
+                    }
+                    *pos += 1;
+                }
+                *pos += 1;
+                format!("(?:{})", escape(&literal))
+            }
+            Some('[') => {
+                // Character classes already use regex syntax
+                let start = *pos;
+                *pos += 1;
+                while chars.get(*pos).is_some_and(|&c| c != ']') {
+                    if chars[*pos] == '\\' {
+                        *pos += 1;
+                    }
+                    *pos += 1;
+                }
+                if *pos >= chars.len() {
+                    return Err(Error::Msg("Unterminated character class".into()));
+                }
+                *pos += 1;
+                chars[start..*pos].iter().collect()
+            }
+            Some('(') => {
+                *pos += 1;
+                let inner = parse_alternation(chars, pos, rules, compiled, stack)?;
+                if chars.get(*pos) != Some(&')') {
+                    return Err(Error::Msg("Unbalanced parenthesis in grammar".into()));
+                }
+                *pos += 1;
+                format!("(?:{})", inner)
+            }
+            Some(c) if c.is_alphanumeric() || *c == '_' => {
+                let start = *pos;
+                while chars
+                    .get(*pos)
+                    .is_some_and(|&c| c.is_alphanumeric() || c == '_' || c == '-')
+                {
+                    *pos += 1;
+                }
+                let name: String = chars[start..*pos].iter().collect();
+                format!("(?:{})", compile_rule(&name, rules, compiled, stack)?)
+            }
+            Some(c) => return Err(Error::Msg(format!("Unexpected {:?} in grammar", c))),
+        };
+        sequence.push_str(&atom);
+        if let Some(&op) = chars.get(*pos).filter(|c| matches!(c, '*' | '+' | '?')) {
+            sequence.push(op);
+            *pos += 1;
+        }
+    }
+    Ok(sequence)
+}
+
+/// Translate a JSON schema to a regex over its compact-ish serialization.
+///
+/// Supports `type` (string, integer, number, boolean, null, array, object), `enum`, `const`,
+/// string `pattern`, array `items`, `anyOf`/`oneOf` and object `properties`. Objects always
+/// list every property, in schema order.
+pub fn json_schema_to_regex(schema: &Value) -> Result<String> {
+    if let Some(value) = schema.get("const") {
+        return Ok(escape(&value.to_string()));
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        let values: Vec<String> = values.iter().map(|v| escape(&v.to_string())).collect();
+        return Ok(format!("(?:{})", values.join("|")));
+    }
+    if let Some(schemas) = schema
+        .get("anyOf")
+        .or_else(|| schema.get("oneOf"))
+        .and_then(Value::as_array)
+    {
+        let alternatives = schemas
+            .iter()
+            .map(json_schema_to_regex)
+            .collect::<Result<Vec<String>>>()?;
+        return Ok(format!("(?:{})", alternatives.join("|")));
+    }
+    let ty = schema
+        .get("type")
+        .and_then(Value::as_str)
+        .unwrap_or("object");
+    match ty {
+        "string" => match schema.get("pattern").and_then(Value::as_str) {
+            Some(pattern) => Ok(format!("\"(?:{})\"", pattern)),
+            None => Ok(JSON_STRING.to_string()),
+        },
+        "integer" => Ok(JSON_INTEGER.to_string()),
+        "number" => Ok(JSON_NUMBER.to_string()),
+        "boolean" => Ok("(?:true|false)".to_string()),
+        "null" => Ok("null".to_string()),
+        "array" => {
+            let item = match schema.get("items") {
+                Some(items) => json_schema_to_regex(items)?,
+                None => return Err(Error::Msg("Array schemas need `items`".into())),
+            };
+            Ok(format!(
+                r"\[{ws}(?:{item}(?:{ws},{ws}{item})*)?{ws}\]",
+                ws = JSON_WS,
+                item = item
+            ))
+        }
+        "object" => {
+            let properties = match schema.get("properties").and_then(Value::as_object) {
+                Some(properties) => properties,
+                None => return Ok(format!(r"\{{{}\}}", JSON_WS)),
+            };
+            let fields = properties
+                .iter()
+                .map(|(name, schema)| {
+                    Ok(format!(
+                        "{}{ws}:{ws}{}",
+                        escape(&Value::String(name.clone()).to_string()),
+                        json_schema_to_regex(schema)?,
+                        ws = JSON_WS
+                    ))
+                })
+                .collect::<Result<Vec<String>>>()?;
+            Ok(format!(
+                r"\{{{ws}{}{ws}\}}",
+                fields.join(&format!("{ws},{ws}", ws = JSON_WS)),
+                ws = JSON_WS
+            ))
+        }
+        other => Err(Error::Msg(format!("Unsupported schema type {:?}", other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex_automata::meta::Regex;
+
+    use super::{ebnf_to_regex, json_schema_to_regex};
+
+    fn full_match(pattern: &str, text: &str) -> bool {
+        Regex::new(&format!("^(?:{})$", pattern))
+            .unwrap()
+            .is_match(text)
+    }
+
+    #[test]
+    fn test_ebnf() {
+        let grammar = r#"
+            # A couplet of two short lines
+            root ::= line "\n" line
+            line ::= word (" " word)* ("." | "!")?
+            word ::= [a-z]+
+        "#;
+        let pattern = ebnf_to_regex(grammar).unwrap();
+        assert!(full_match(&pattern, "to be\nor not."));
+        assert!(!full_match(&pattern, "to be"));
+        assert!(!full_match(&pattern, "To be\nor not"));
+
+        assert!(ebnf_to_regex("expr ::= \"(\" expr \")\" | \"x\"").is_err());
+        assert!(ebnf_to_regex("root ::= missing").is_err());
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}}
+            }
+        });
+        let pattern = json_schema_to_regex(&schema).unwrap();
+        assert!(full_match(
+            &pattern,
+            r#"{"name": "Hamlet", "age": 30, "tags": ["a", "b"]}"#
+        ));
+        assert!(full_match(&pattern, r#"{"name":"","age":-1,"tags":[]}"#));
+        assert!(!full_match(
+            &pattern,
+            r#"{"age": 30, "name": "Hamlet", "tags": []}"#
+        ));
+        assert!(!full_match(
+            &pattern,
+            r#"{"name": "Hamlet", "age": 3.5, "tags": []}"#
+        ));
+    }
+}
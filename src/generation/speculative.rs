@@ -0,0 +1,272 @@
+use candle_core::{DType, Error, IndexOp, Result, Tensor};
+
+use super::{Generation, StopReason, StoppingCriteria};
+use crate::models::Model;
+use crate::sampling::Sampler;
+
+/// How many drafted tokens the target model kept
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SpeculativeStats {
+    pub proposed: usize,
+    pub accepted: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.proposed == 0 {
+            0.0
+        } else {
+            self.accepted as f32 / self.proposed as f32
+        }
+    }
+}
+
+/// Speculative decoding (Leviathan et al. 2023) from a (1, T) prompt.
+///
+/// Each round `draft` proposes up to `num_draft_tokens` tokens and `target` scores them all in
+/// one forward pass. Drafted token `x` is kept with probability `min(1, p(x) / q(x))`; the first
+/// rejected one is resampled from `max(0, p - q)`, so outputs follow `target`'s distribution
+/// under `sampler`. Both models must share a vocabulary.
+///
+/// Past the target's context window, each verification pass sees one window for all its
+/// positions, so outputs drift from plain `generate` there.
+pub fn speculative_generate<T: Model, D: Model>(
+    target: &T,
+    draft: &D,
+    idx: &Tensor,
+    max_new_tokens: usize,
+    num_draft_tokens: usize,
+    sampler: &mut Sampler,
+    stopping: &StoppingCriteria,
+) -> Result<(Generation, SpeculativeStats)> {
+    let (b, prompt_len) = idx.dims2()?;
+    if b != 1 {
+        return Err(Error::Msg(format!(
+            "Speculative decoding takes a single prompt, got a batch of {}",
+            b
+        )));
+    }
+    if sampler.logprobs().is_some() {
+        return Err(Error::Msg(
+            "Speculative decoding doesn't record log-probs".into(),
+        ));
+    }
+    let num_draft_tokens = num_draft_tokens.max(1);
+    if num_draft_tokens >= target.context_size() {
+        return Err(Error::Msg(format!(
+            "Cannot verify {} draft tokens with a context size of {}",
+            num_draft_tokens,
+            target.context_size()
+        )));
+    }
+
+    let mut ids: Vec<u32> = idx.i(0)?.to_vec1()?;
+    let mut stats = SpeculativeStats::default();
+    let mut stop_reason = None;
+    let mut target_cache = target.new_cache();
+    let mut draft_cache = draft.new_cache();
+    // Trailing tokens of `ids` that each cache hasn't seen yet
+    let mut target_pending = prompt_len;
+    let mut draft_pending = prompt_len;
+    let to_tensor = |ids: &[u32]| Tensor::new(ids, idx.device())?.unsqueeze(0);
+
+    while stop_reason.is_none() && ids.len() - prompt_len < max_new_tokens {
+        let k = num_draft_tokens.min(max_new_tokens - (ids.len() - prompt_len));
+        let (prompt, _) = ids.split_at(prompt_len);
+
+        // Draft k tokens autoregressively, keeping each distribution for the correction
+        let mut proposals: Vec<u32> = Vec::with_capacity(k);
+        let mut draft_probs: Vec<Vec<f32>> = Vec::with_capacity(k);
+        for _ in 0..k {
+            let seq = to_tensor(&[ids.as_slice(), &proposals].concat())?;
+            let input = draft_cache.window(
+                &seq,
+                draft_pending,
+                draft.context_size(),
+                draft.cache_eviction(),
+            )?;
+            let logits = draft.forward_step(&input, &mut draft_cache)?;
+            let logits: Vec<f32> = logits
+                .i((0, logits.dim(1)? - 1))?
+                .to_dtype(DType::F32)?
+                .to_vec1()?;
+            let q = sampler.probs(logits, prompt, &[&ids[prompt_len..], &proposals].concat())?;
+            proposals.push(sampler.sample_probs(&q)?);
+            draft_probs.push(q);
+            draft_pending = 1;
+        }
+
+        // Score every proposal, plus one bonus position, in a single target pass
+        let seq = to_tensor(&[ids.as_slice(), &proposals].concat())?;
+        let input = target_cache.window(
+            &seq,
+            target_pending + k,
+            target.context_size(),
+            target.cache_eviction(),
+        )?;
+        let logits = target.forward_step(&input, &mut target_cache)?;
+        let logits: Vec<Vec<f32>> = logits
+            .i(0)?
+            .narrow(0, logits.dim(1)? - (k + 1), k + 1)?
+            .to_dtype(DType::F32)?
+            .to_vec2()?;
+        if logits[0].len() != draft_probs[0].len() {
+            return Err(Error::Msg(format!(
+                "Draft vocab size {} does not match target vocab size {}",
+                draft_probs[0].len(),
+                logits[0].len()
+            )));
+        }
+
+        let mut accepted = 0;
+        let mut next_token = None;
+        for (j, row) in logits.into_iter().enumerate() {
+            let generated = [&ids[prompt_len..], &proposals[..j]].concat();
+            let p = sampler.probs(row, prompt, &generated)?;
+            if j == k {
+                // Every proposal survived: the last target position is a free extra token
+                next_token = Some(sampler.sample_probs(&p)?);
+                break;
+            }
+            let (x, q) = (proposals[j] as usize, &draft_probs[j]);
+            if q[x] > 0.0 && sampler.uniform() < (p[x] / q[x]).min(1.0) {
+                accepted += 1;
+                continue;
+            }
+            let residual: Vec<f32> = p.iter().zip(q).map(|(p, q)| (p - q).max(0.0)).collect();
+            // p == q up to rounding leaves nothing to resample from
+            next_token = Some(if residual.iter().sum::<f32>() > 0.0 {
+                sampler.sample_probs(&residual)?
+            } else {
+                sampler.sample_probs(&p)?
+            });
+            break;
+        }
+        stats.proposed += k;
+        stats.accepted += accepted;
+
+        // Roll both caches back to the kept tokens
+        target_cache.truncate(k - accepted)?;
+        target_pending = 1;
+        if accepted == k {
+            draft_pending = 2;
+        } else {
+            draft_cache.truncate(k - 1 - accepted)?;
+            draft_pending = 1;
+        }
+
+        for token in proposals[..accepted].iter().copied().chain(next_token) {
+            ids.push(token);
+            stop_reason = stopping.check(&ids[prompt_len..])?;
+            if stop_reason.is_some() || ids.len() - prompt_len == max_new_tokens {
+                break;
+            }
+        }
+    }
+
+    let generation = Generation {
+        ids: vec![ids],
+        stop_reasons: vec![stop_reason.unwrap_or(StopReason::MaxTokens)],
+        logprobs: vec![Vec::new()],
+    };
+    Ok((generation, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::VarMap;
+
+    use super::speculative_generate;
+    use crate::config::pretrained_config::{CacheEviction, PositionEmbeddingType};
+    use crate::generation::StoppingCriteria;
+    use crate::models::bigram::{self, Bigram};
+    use crate::models::transformer::{Config, Transformer};
+    use crate::models::Model;
+    use crate::sampling::{Sampler, SamplingConfig};
+    use crate::util::seeded_var_builder;
+
+    #[test]
+    fn test_speculative_greedy() {
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let target = Transformer::new(
+            seeded_var_builder(&varmap, 1, DType::F32, &device),
+            &Config {
+                vocab_size: 6,
+                context_size: 32,
+                hidden_dim: 16,
+                intermediate_dim: 64,
+                num_attention_heads: 2,
+                num_key_value_heads: 2,
+                hidden_layers: 1,
+                position_embedding: PositionEmbeddingType::Rotary,
+                rope_theta: 10000.0,
+                rotary_pct: 1.0,
+                rope_scaling: None,
+                cache_eviction: CacheEviction::Recompute,
+            },
+        )
+        .unwrap();
+        let varmap = VarMap::new();
+        let draft = Bigram::new(
+            seeded_var_builder(&varmap, 2, DType::F32, &device),
+            &bigram::Config { vocab_size: 6 },
+        )
+        .unwrap();
+        let greedy = SamplingConfig {
+            greedy: true,
+            ..Default::default()
+        };
+        let idx = Tensor::new(&[[1u32, 2]], &device).unwrap();
+
+        // Greedy speculative decoding reproduces the target exactly, whatever the draft says
+        let expected: Vec<Vec<u32>> = target
+            .generate(&idx, 20, &mut Sampler::new(&greedy))
+            .unwrap()
+            .to_vec2()
+            .unwrap();
+        let (generation, stats) = speculative_generate(
+            &target,
+            &draft,
+            &idx,
+            20,
+            3,
+            &mut Sampler::new(&greedy),
+            &StoppingCriteria::default(),
+        )
+        .unwrap();
+        assert_eq!(generation.ids, expected);
+        assert!(stats.proposed >= stats.accepted);
+        assert!(stats.proposed > 0);
+
+        // A model drafting for itself is always right
+        let (_, stats) = speculative_generate(
+            &target,
+            &target,
+            &idx,
+            20,
+            3,
+            &mut Sampler::new(&greedy),
+            &StoppingCriteria::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.acceptance_rate(), 1.0);
+
+        // Log-probs aren't recorded, so asking for them is an error rather than an empty list
+        let with_logprobs = SamplingConfig {
+            logprobs: Some(1),
+            ..greedy
+        };
+        assert!(speculative_generate(
+            &target,
+            &draft,
+            &idx,
+            20,
+            3,
+            &mut Sampler::new(&with_logprobs),
+            &StoppingCriteria::default(),
+        )
+        .is_err());
+    }
+}
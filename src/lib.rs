@@ -1,6 +1,8 @@
 pub mod config;
 pub mod dataloader;
 pub mod datasets;
+pub mod generation;
 pub mod models;
+pub mod sampling;
 pub mod tokenizer;
 pub mod util;
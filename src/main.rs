@@ -1,15 +1,26 @@
 use candle_core::{Device, Error, Result, Tensor};
-use candle_nn::{VarBuilder, VarMap};
-use clap::Parser;
-use nanogpt::config::pretrained_config::PretrainedConfig;
+use candle_nn::VarMap;
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser};
+use nanogpt::config::pretrained_config::{CacheEviction, PretrainedConfig};
+use nanogpt::generation::beam::{beam_search, BeamSearchConfig};
+use nanogpt::generation::constrained::Constraint;
+use nanogpt::generation::speculative::speculative_generate;
+use nanogpt::generation::{StopReason, StoppingCriteria, TokenLogprob};
 use nanogpt::models::{Model, ModelWrapper, WhichModel};
+use nanogpt::sampling::{parse_logit_bias, LogitBias, Sampler, SamplingConfig};
 use nanogpt::tokenizer::Tokenizer;
+use nanogpt::util::seeded_var_builder;
 use std::env;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::rc::Rc;
 
 #[derive(Parser, Debug)]
 #[command(version, long_about=None)]
+// Beam search never consults the sampler, so it can't apply a constraint
+#[command(group(ArgGroup::new("constraint").conflicts_with("num_beams")))]
 struct Args {
     /// Name of folder in model directory.
     #[arg(short, long, default_value = "transformer")]
@@ -18,39 +29,221 @@ struct Args {
     #[arg(short, long)]
     prompt: Option<String>,
 
+    /// Generate for every non-blank line of this file as one batch, printing outputs in the
+    /// same order
+    #[arg(long, conflicts_with_all = ["prompt", "draft_model", "num_beams"])]
+    prompts_file: Option<PathBuf>,
+
     #[arg(short, long)]
     n_tokens: Option<usize>,
+
+    /// How to keep generating once the sequence outgrows the context. Defaults to the model config
+    #[arg(long)]
+    cache_eviction: Option<CacheEviction>,
+
+    #[command(flatten)]
+    sampling: SamplingConfig,
+
+    /// Add BIAS to the logit of TOKEN. Repeatable
+    #[arg(long, value_name = "TOKEN=BIAS", value_parser = parse_logit_bias, help_heading = "Sampling")]
+    logit_bias: Vec<(String, f32)>,
+
+    /// Stop once the generated text contains STOP. Repeatable
+    #[arg(long)]
+    stop: Vec<String>,
+
+    #[command(flatten)]
+    beam: BeamSearchConfig,
+
+    /// Draft with this smaller model and verify with `--model-type` (speculative decoding)
+    #[arg(long)]
+    draft_model: Option<WhichModel>,
+
+    /// Tokens the draft model proposes per verification step
+    #[arg(long, default_value_t = 4)]
+    num_draft_tokens: usize,
+
+    /// Only generate text matching this regex
+    #[arg(long, group = "constraint", help_heading = "Constrained decoding")]
+    regex: Option<String>,
+
+    /// Only generate text derivable from this EBNF grammar file
+    #[arg(long, group = "constraint", help_heading = "Constrained decoding")]
+    grammar: Option<PathBuf>,
+
+    /// Only generate JSON matching this JSON schema file
+    #[arg(long, group = "constraint", help_heading = "Constrained decoding")]
+    json_schema: Option<PathBuf>,
 }
 
-fn generate<M: Model>(
+/// Compile whichever of `--regex`, `--grammar` or `--json-schema` was given
+fn load_constraint(
+    regex: Option<&str>,
+    grammar: Option<&Path>,
+    json_schema: Option<&Path>,
     tokenizer: &Tokenizer,
-    model: &mut M,
-    prompt: &str,
-    device: &Device,
-    max_tokens: usize,
-) -> Result<String> {
+) -> Result<Option<Constraint>> {
+    let read = |path: &Path| {
+        std::fs::read_to_string(path)
+            .map_err(|e| Error::Msg(format!("Cannot read {:?}: {}", path, e)))
+    };
+    if let Some(pattern) = regex {
+        Constraint::regex(pattern, tokenizer).map(Some)
+    } else if let Some(path) = grammar {
+        Constraint::ebnf(&read(path)?, tokenizer).map(Some)
+    } else if let Some(path) = json_schema {
+        let schema = serde_json::from_str(&read(path)?)
+            .map_err(|e| Error::Msg(format!("Invalid JSON schema: {}", e)))?;
+        Constraint::json_schema(&schema, tokenizer).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+fn encode_prompt(tokenizer: &Tokenizer, prompt: &str, device: &Device) -> Result<Tensor> {
     let input_encoding = tokenizer
         .encode(prompt)
         .map_err(|_| Error::Msg("Tokenizer error".into()))?;
     let idx_1d = Tensor::new(input_encoding.ids, device)?;
-    let idx = idx_1d.reshape((1, idx_1d.dims1()?))?;
+    idx_1d.reshape((1, idx_1d.dims1()?))
+}
+
+/// Print the prompt and then each piece of text as soon as it is generated.
+///
+/// Log-probs come back empty unless the sampler records them
+fn generate<M: Model>(
+    tokenizer: &Tokenizer,
+    model: &M,
+    prompt: &str,
+    device: &Device,
+    max_tokens: usize,
+    sampler: &mut Sampler,
+    stopping: &StoppingCriteria,
+) -> Result<(StopReason, Vec<TokenLogprob>)> {
+    let idx = encode_prompt(tokenizer, prompt, device)?;
     println!("Tokenized");
-    let data = model.generate(&idx, max_tokens)?;
-    println!("Generated");
-    let (b, c) = data.dims2()?;
-    let data_1d = data.reshape(b * c)?;
-
-    Ok(tokenizer
-        .decode(&data_1d.to_vec1()?)
-        .map_err(|_| Error::Msg("Could not decode".into()))?)
+
+    print!("{}", prompt);
+    let mut stream = tokenizer.decode_stream();
+    let mut generation = model.generate_stream(&idx, max_tokens, sampler, stopping, |_, id| {
+        // Undecodable ids are skipped rather than ending the stream
+        if let Ok(Some(text)) = stream.step(id) {
+            print!("{}", text);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    println!();
+    let mut logprobs = generation.logprobs.remove(0);
+    logprobs.iter_mut().for_each(|lp| lp.decode(tokenizer));
+    Ok((generation.stop_reasons.remove(0), logprobs))
 }
 
-fn main() {
-    let args = Args::parse();
+fn print_logprobs(logprobs: &[TokenLogprob]) -> Result<()> {
+    let json = serde_json::to_string_pretty(logprobs).map_err(|e| Error::Msg(e.to_string()))?;
+    println!("{}", json);
+    Ok(())
+}
 
-    let cwd = env::current_dir().unwrap();
-    // Hardcode to bigram for now
-    let model_name: String = args.model_type.into();
+/// Generate for all `prompts` in one padded batch and print each output on its own line,
+/// followed by its log-probs if the sampler records them
+fn generate_batch<M: Model>(
+    tokenizer: &Tokenizer,
+    model: &M,
+    prompts: &[String],
+    device: &Device,
+    max_tokens: usize,
+    sampler: &mut Sampler,
+    stopping: &StoppingCriteria,
+) -> Result<()> {
+    let prompts = prompts
+        .iter()
+        .map(|p| tokenizer.encode(p).map(|e| e.ids))
+        .collect::<std::result::Result<Vec<Vec<u32>>, _>>()
+        .map_err(|_| Error::Msg("Tokenizer error".into()))?;
+    // Padding is masked out, so any id will do if the vocab has no pad token
+    let pad_token_id = tokenizer.pad_token_id().unwrap_or(0);
+    let generation = model.generate_batch(
+        &prompts,
+        pad_token_id,
+        device,
+        max_tokens,
+        sampler,
+        stopping,
+    )?;
+    for (ids, mut logprobs) in generation.ids.iter().zip(generation.logprobs) {
+        let text = tokenizer
+            .decode(ids)
+            .map_err(|_| Error::Msg("Could not decode".into()))?;
+        println!("{:?}", text);
+        if sampler.logprobs().is_some() {
+            logprobs.iter_mut().for_each(|lp| lp.decode(tokenizer));
+            print_logprobs(&logprobs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Speculative decoding with `draft`, printing the output and the draft acceptance rate
+#[allow(clippy::too_many_arguments)]
+fn generate_speculative<M: Model>(
+    tokenizer: &Tokenizer,
+    model: &M,
+    draft: &M,
+    prompt: &str,
+    device: &Device,
+    max_tokens: usize,
+    num_draft_tokens: usize,
+    sampler: &mut Sampler,
+    stopping: &StoppingCriteria,
+) -> Result<()> {
+    let idx = encode_prompt(tokenizer, prompt, device)?;
+    let (mut generation, stats) = speculative_generate(
+        model,
+        draft,
+        &idx,
+        max_tokens,
+        num_draft_tokens,
+        sampler,
+        stopping,
+    )?;
+    let text = tokenizer
+        .decode(&generation.ids.remove(0))
+        .map_err(|_| Error::Msg("Could not decode".into()))?;
+    println!("{:?}", text);
+    println!("Stopped: {:?}", generation.stop_reasons.remove(0));
+    println!(
+        "Accepted {}/{} draft tokens ({:.1}%)",
+        stats.accepted,
+        stats.proposed,
+        100.0 * stats.acceptance_rate()
+    );
+    Ok(())
+}
+
+/// Print the n-best beam search hypotheses with their cumulative log-probs
+fn generate_beams<M: Model>(
+    tokenizer: &Tokenizer,
+    model: &M,
+    prompt: &str,
+    device: &Device,
+    max_tokens: usize,
+    cfg: &BeamSearchConfig,
+    stopping: &StoppingCriteria,
+) -> Result<()> {
+    let idx = encode_prompt(tokenizer, prompt, device)?;
+    for hypothesis in beam_search(model, &idx, max_tokens, cfg, stopping)? {
+        let text = tokenizer
+            .decode(&hypothesis.ids)
+            .map_err(|_| Error::Msg("Could not decode".into()))?;
+        println!(
+            "{:?} (log-prob {:.4}, score {:.4})",
+            text, hypothesis.log_prob, hypothesis.score
+        );
+    }
+    Ok(())
+}
+
+fn load_config(cwd: &Path, model_name: &str) -> PretrainedConfig {
     let config_path: PathBuf = cwd.join(format!("models/{}/config.json", model_name));
     if !config_path.exists() {
         eprintln!(
@@ -59,13 +252,59 @@ fn main() {
         );
         process::exit(1);
     }
-    let config: PretrainedConfig = match PretrainedConfig::from_json_file(&config_path) {
+    match PretrainedConfig::from_json_file(&config_path) {
         Ok(cfg) => cfg,
         Err(e) => {
             eprintln!("Failed to load config from {:?}: {}", config_path, e);
             process::exit(1);
         }
-    };
+    }
+}
+
+fn load_model(
+    cwd: &Path,
+    model_name: &str,
+    config: &PretrainedConfig,
+    seed: u64,
+    device: &Device,
+) -> ModelWrapper {
+    let mut varmap = VarMap::new();
+    // Seed also covers the random weights used when nothing is saved
+    let vs = seeded_var_builder(&varmap, seed, candle_core::DType::F32, device);
+    let model: ModelWrapper = ModelWrapper::from_config(vs, config).unwrap();
+
+    // Get weights if exist, else bail
+    let weight_path = cwd.join(format!("models/{}/model.safetensors", model_name));
+    if weight_path.exists() {
+        println!("Loading {} model", model_name);
+        varmap.load(weight_path).unwrap();
+    } else {
+        println!("Fail!");
+    }
+    model
+}
+
+fn main() {
+    let args = Args::parse();
+    // Neither records per-token log-probs
+    if args.sampling.logprobs.is_some()
+        && (args.beam.num_beams.is_some() || args.draft_model.is_some())
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--logprobs cannot be used with --num-beams or --draft-model",
+            )
+            .exit();
+    }
+
+    let cwd = env::current_dir().unwrap();
+    let model_name: String = args.model_type.into();
+    let mut config = load_config(&cwd, &model_name);
+
+    if let Some(cache_eviction) = args.cache_eviction {
+        config.cache_eviction = cache_eviction;
+    }
 
     let tokenizer_path: PathBuf =
         cwd.join(format!("models/{}-tokenizer.json", config.tokenizer_id));
@@ -86,25 +325,107 @@ fn main() {
     };
 
     let device = nanogpt::util::get_device();
-
-    // TODO: Factor this out once we make this multi-model
-    let mut varmap = VarMap::new();
-    let vs = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, &device);
-    let mut model: ModelWrapper = ModelWrapper::from_config(vs, &config).unwrap();
-
-    // Get weights if exist, else bail
-    let weight_path = cwd.join(format!("models/{}/model.safetensors", model_name));
-    if weight_path.exists() {
-        println!("Loading {} model", model_name);
-        varmap.load(weight_path).unwrap();
-    } else {
-        println!("Fail!");
-    }
+    let seed = args.sampling.seed.unwrap_or_else(rand::random);
+    let model = load_model(&cwd, &model_name, &config, seed, &device);
+    let draft = args.draft_model.map(|which| {
+        let draft_name: String = which.into();
+        let draft_config = load_config(&cwd, &draft_name);
+        if draft_config.tokenizer_id != config.tokenizer_id {
+            eprintln!(
+                "Error: draft model uses tokenizer {:?}, but {} uses {:?}",
+                draft_config.tokenizer_id, model_name, config.tokenizer_id
+            );
+            process::exit(1);
+        }
+        load_model(&cwd, &draft_name, &draft_config, seed, &device)
+    });
 
     let prompt = args.prompt.unwrap_or(" ".to_string());
     let max_tokens = args.n_tokens.unwrap_or(20);
-    println!(
-        "{:?}",
-        generate(&tokenizer, &mut model, &prompt, &device, max_tokens)
-    )
+    let mut sampler = Sampler::new(&args.sampling);
+    if !args.logit_bias.is_empty() {
+        match LogitBias::from_tokens(&args.logit_bias, &tokenizer) {
+            Ok(bias) => sampler.push(Box::new(bias)),
+            Err(e) => {
+                eprintln!("Invalid --logit-bias: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    let mut stopping = StoppingCriteria::new(&tokenizer, args.stop);
+    match load_constraint(
+        args.regex.as_deref(),
+        args.grammar.as_deref(),
+        args.json_schema.as_deref(),
+        &tokenizer,
+    ) {
+        Ok(Some(constraint)) => {
+            let constraint = Rc::new(constraint);
+            sampler.push(Box::new(constraint.clone()));
+            stopping.constraint = Some(constraint);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+    let result = if let Some(draft) = &draft {
+        generate_speculative(
+            &tokenizer,
+            &model,
+            draft,
+            &prompt,
+            &device,
+            max_tokens,
+            args.num_draft_tokens,
+            &mut sampler,
+            &stopping,
+        )
+    } else if let Some(path) = args.prompts_file {
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let prompts: Vec<String> = contents
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(String::from)
+                    .collect();
+                generate_batch(
+                    &tokenizer,
+                    &model,
+                    &prompts,
+                    &device,
+                    max_tokens,
+                    &mut sampler,
+                    &stopping,
+                )
+            }
+            Err(e) => Err(Error::Msg(format!("Cannot read {:?}: {}", path, e))),
+        }
+    } else if args.beam.num_beams.is_some() {
+        generate_beams(
+            &tokenizer, &model, &prompt, &device, max_tokens, &args.beam, &stopping,
+        )
+    } else {
+        generate(
+            &tokenizer,
+            &model,
+            &prompt,
+            &device,
+            max_tokens,
+            &mut sampler,
+            &stopping,
+        )
+        .and_then(|(reason, logprobs)| {
+            println!("Stopped: {:?}", reason);
+            match args.sampling.logprobs {
+                Some(_) => print_logprobs(&logprobs),
+                None => Ok(()),
+            }
+        })
+    };
+    if let Err(e) = result {
+        eprintln!("Generation failed: {}", e);
+        process::exit(1);
+    }
 }
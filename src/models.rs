@@ -1,16 +1,213 @@
 use self::{bigram::Bigram, transformer::Transformer};
+pub use cache::Cache;
 
-use super::config::pretrained_config::PretrainedConfig;
-use candle_core::{Result, Tensor};
-use candle_nn::{Module, VarBuilder};
+use super::config::pretrained_config::{CacheEviction, PretrainedConfig};
+use crate::generation::TokenLogprob;
+use crate::generation::{Generation, StopReason, StoppingCriteria};
+use crate::sampling::Sampler;
+use candle_core::{DType, Device, Error, IndexOp, Result, Tensor, D};
+use candle_nn::{ops, Module, VarBuilder};
 use clap::ValueEnum;
 
 pub mod bigram;
+pub mod cache;
 pub mod transformer;
 
 pub trait Model: Sized + Module {
-    fn generate(&mut self, idx: &Tensor, max_new_tokens: usize) -> Result<Tensor>;
     fn from_config(vs: VarBuilder, cfg: &PretrainedConfig) -> Result<Self>;
+    /// Empty cache to thread through `forward_step`
+    fn new_cache(&self) -> Cache;
+    /// Logits for (B, T) tokens that continue the sequence already in `cache`
+    fn forward_step(&self, xs: &Tensor, cache: &mut Cache) -> Result<Tensor>;
+    /// `forward_step` where (B, T) `attention_mask` is 0 at padding tokens, which nothing
+    /// attends to. Models that don't mix positions can ignore it
+    fn forward_step_masked(
+        &self,
+        xs: &Tensor,
+        _attention_mask: Option<&Tensor>,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        self.forward_step(xs, cache)
+    }
+    /// Longest sequence a single forward pass accepts
+    fn context_size(&self) -> usize;
+    /// How `generate` keeps the cache within `context_size`
+    fn cache_eviction(&self) -> CacheEviction {
+        CacheEviction::Recompute
+    }
+
+    /// Extend (B, T) `idx` by `max_new_tokens`, picking each token with `sampler`
+    fn generate(
+        &self,
+        idx: &Tensor,
+        max_new_tokens: usize,
+        sampler: &mut Sampler,
+    ) -> Result<Tensor> {
+        let generation =
+            self.generate_until(idx, max_new_tokens, sampler, &StoppingCriteria::default())?;
+        Tensor::new(generation.ids, idx.device())
+    }
+
+    /// Like `generate`, but each row ends as soon as `stopping` fires.
+    ///
+    /// Rows that stop early keep repeating their last token so the batch stays rectangular;
+    /// the whole batch ends once every row has stopped.
+    fn generate_until(
+        &self,
+        idx: &Tensor,
+        max_new_tokens: usize,
+        sampler: &mut Sampler,
+        stopping: &StoppingCriteria,
+    ) -> Result<Generation> {
+        self.generate_stream(idx, max_new_tokens, sampler, stopping, |_, _| {})
+    }
+
+    /// Like `generate_until`, calling `on_token(row, token)` as soon as each token is picked
+    fn generate_stream<F: FnMut(usize, u32)>(
+        &self,
+        idx: &Tensor,
+        max_new_tokens: usize,
+        sampler: &mut Sampler,
+        stopping: &StoppingCriteria,
+        on_token: F,
+    ) -> Result<Generation> {
+        self.generate_masked(idx, None, max_new_tokens, sampler, stopping, on_token)
+    }
+
+    /// Generate for prompts of different lengths at once, left-padded with `pad_token_id`.
+    ///
+    /// Returned ids don't include the padding.
+    fn generate_batch(
+        &self,
+        prompts: &[Vec<u32>],
+        pad_token_id: u32,
+        device: &Device,
+        max_new_tokens: usize,
+        sampler: &mut Sampler,
+        stopping: &StoppingCriteria,
+    ) -> Result<Generation> {
+        // An all-padding row has nothing to continue
+        if prompts.is_empty() || prompts.iter().any(Vec::is_empty) {
+            return Err(Error::Msg("Every prompt needs at least one token".into()));
+        }
+        let max_len = prompts.iter().map(Vec::len).max().unwrap_or(0);
+        let pads: Vec<usize> = prompts.iter().map(|p| max_len - p.len()).collect();
+        let idx: Vec<Vec<u32>> = prompts
+            .iter()
+            .zip(pads.iter())
+            .map(|(p, &pad)| [vec![pad_token_id; pad], p.clone()].concat())
+            .collect();
+        let mask: Vec<Vec<u32>> = pads
+            .iter()
+            .map(|&pad| [vec![0; pad], vec![1; max_len - pad]].concat())
+            .collect();
+        let mut generation = self.generate_masked(
+            &Tensor::new(idx, device)?,
+            Some(&Tensor::new(mask, device)?),
+            max_new_tokens,
+            sampler,
+            stopping,
+            |_, _| {},
+        )?;
+        for (row, pad) in generation.ids.iter_mut().zip(pads) {
+            row.drain(..pad);
+        }
+        Ok(generation)
+    }
+
+    /// `generate_stream` with an optional (B, T) `attention_mask` over `idx`, 0 at padding
+    fn generate_masked<F: FnMut(usize, u32)>(
+        &self,
+        idx: &Tensor,
+        attention_mask: Option<&Tensor>,
+        max_new_tokens: usize,
+        sampler: &mut Sampler,
+        stopping: &StoppingCriteria,
+        mut on_token: F,
+    ) -> Result<Generation> {
+        let prompt_len = idx.dim(1)?;
+        let mut ids: Vec<Vec<u32>> = idx.to_vec2()?;
+        let mut stop_reasons: Vec<Option<StopReason>> = vec![None; ids.len()];
+        let mut logprobs: Vec<Vec<TokenLogprob>> = vec![Vec::new(); ids.len()];
+        // Leading padding of each row, which the sampler mustn't take for prompt
+        let pads: Vec<usize> = match attention_mask {
+            Some(mask) => mask
+                .to_dtype(DType::U32)?
+                .to_vec2::<u32>()?
+                .iter()
+                .map(|row| row.iter().take_while(|&&m| m == 0).count())
+                .collect(),
+            None => vec![0; ids.len()],
+        };
+        let mut cache = self.new_cache();
+        let mut preds = idx.clone();
+        let mut mask = attention_mask.cloned();
+        // Whole prompt first, then one token per step
+        let mut n_new = prompt_len;
+        for _ in 0..max_new_tokens {
+            let input = cache.window(&preds, n_new, self.context_size(), self.cache_eviction())?;
+            let input_mask = match &mask {
+                Some(mask) => {
+                    let n = input.dim(1)?;
+                    Some(mask.narrow(1, mask.dim(1)? - n, n)?)
+                }
+                None => None,
+            };
+            let logits = self.forward_step_masked(&input, input_mask.as_ref(), &mut cache)?;
+            // Only the last time step predicts a new token
+            let logits = logits.i((.., logits.dim(1)? - 1, ..))?;
+            let history: Vec<(&[u32], &[u32])> = ids
+                .iter()
+                .zip(pads.iter())
+                .map(|(row, &pad)| (&row[pad..prompt_len], &row[prompt_len..]))
+                .collect();
+            let mut next_tokens = sampler.sample_rows(&logits, &history)?;
+            let log_probs: Option<Vec<Vec<f32>>> = match sampler.logprobs() {
+                Some(_) => Some(
+                    ops::log_softmax(&logits, D::Minus1)?
+                        .to_dtype(DType::F32)?
+                        .to_vec2()?,
+                ),
+                None => None,
+            };
+            for (i, ((token, row), reason)) in next_tokens
+                .iter_mut()
+                .zip(ids.iter_mut())
+                .zip(stop_reasons.iter_mut())
+                .enumerate()
+            {
+                if reason.is_some() {
+                    *token = *row.last().unwrap_or(token);
+                    continue;
+                }
+                row.push(*token);
+                if let (Some(log_probs), Some(top_n)) = (&log_probs, sampler.logprobs()) {
+                    logprobs[i].push(TokenLogprob::from_row(*token, &log_probs[i], top_n));
+                }
+                on_token(i, *token);
+                *reason = stopping.check(&row[prompt_len..])?;
+            }
+            if stop_reasons.iter().all(Option::is_some) {
+                break;
+            }
+            let b = next_tokens.len();
+            let next_tokens = Tensor::new(next_tokens, idx.device())?.reshape((b, 1))?;
+            preds = Tensor::cat(&[&preds, &next_tokens], 1)?;
+            if let Some(m) = &mask {
+                let ones = Tensor::ones((b, 1), DType::U32, idx.device())?;
+                mask = Some(Tensor::cat(&[m, &ones], 1)?);
+            }
+            n_new = 1;
+        }
+        Ok(Generation {
+            ids,
+            stop_reasons: stop_reasons
+                .into_iter()
+                .map(|r| r.unwrap_or(StopReason::MaxTokens))
+                .collect(),
+            logprobs,
+        })
+    }
 }
 
 pub enum ModelWrapper {
@@ -28,10 +225,39 @@ impl Module for ModelWrapper {
 }
 
 impl Model for ModelWrapper {
-    fn generate(&mut self, idx: &Tensor, max_new_tokens: usize) -> Result<Tensor> {
+    fn new_cache(&self) -> Cache {
+        match self {
+            Self::Bigram(b) => b.new_cache(),
+            Self::Transformer(t) => t.new_cache(),
+        }
+    }
+    fn forward_step(&self, xs: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        match self {
+            Self::Bigram(b) => b.forward_step(xs, cache),
+            Self::Transformer(t) => t.forward_step(xs, cache),
+        }
+    }
+    fn forward_step_masked(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
         match self {
-            Self::Bigram(b) => b.generate(idx, max_new_tokens),
-            Self::Transformer(t) => t.generate(idx, max_new_tokens),
+            Self::Bigram(b) => b.forward_step_masked(xs, attention_mask, cache),
+            Self::Transformer(t) => t.forward_step_masked(xs, attention_mask, cache),
+        }
+    }
+    fn context_size(&self) -> usize {
+        match self {
+            Self::Bigram(b) => b.context_size(),
+            Self::Transformer(t) => t.context_size(),
+        }
+    }
+    fn cache_eviction(&self) -> CacheEviction {
+        match self {
+            Self::Bigram(b) => b.cache_eviction(),
+            Self::Transformer(t) => t.cache_eviction(),
         }
     }
     fn from_config(vs: VarBuilder, cfg: &PretrainedConfig) -> Result<Self> {
@@ -51,9 +277,9 @@ pub enum WhichModel {
     Transformer,
 }
 
-impl Into<String> for WhichModel {
-    fn into(self) -> String {
-        match self {
+impl From<WhichModel> for String {
+    fn from(which: WhichModel) -> Self {
+        match which {
             WhichModel::Bigram => "bigram".into(),
             WhichModel::Transformer => "transformer".into(),
         }
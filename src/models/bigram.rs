@@ -1,7 +1,6 @@
-use super::Model;
-use candle_core::{Error, IndexOp, Result, Tensor};
-use candle_nn::{embedding, ops, Embedding, Module, VarBuilder};
-use rand::{distributions::Distribution, thread_rng};
+use super::{Cache, Model};
+use candle_core::{Result, Tensor};
+use candle_nn::{embedding, Embedding, Module, VarBuilder};
 use serde::Deserialize;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -13,14 +12,12 @@ pub struct Config {
 #[derive(Debug, Clone)]
 pub struct Bigram {
     token_embedding_table: Embedding,
-    rng: rand::rngs::ThreadRng,
 }
 
 impl Bigram {
     pub fn new(vs: VarBuilder, cfg: &Config) -> Result<Self> {
         Ok(Self {
             token_embedding_table: embedding(cfg.vocab_size, cfg.vocab_size, vs.pp("wte"))?,
-            rng: thread_rng(),
         })
     }
 }
@@ -36,7 +33,7 @@ impl Module for Bigram {
         //     self.token_embedding_table.embeddings().to_string()
         // );
         //let logits = self.token_embedding_table.forward(&xs.flatten_all()?)?;
-        let logits = self.token_embedding_table.forward(&xs)?;
+        let logits = self.token_embedding_table.forward(xs)?;
         //let log_sm = ops::log_softmax(&logits, D::Minus1)?;
         Ok(logits)
     }
@@ -53,37 +50,22 @@ impl Model for Bigram {
                 cfg.vocab_size as usize,
                 vs.pp("wte"),
             )?,
-            rng: thread_rng(),
         })
     }
 
-    fn generate(&mut self, idx: &Tensor, max_new_tokens: usize) -> Result<Tensor> {
-        let mut preds = idx.clone();
-        for _ in 0..max_new_tokens {
-            let logits = self.forward(&preds)?;
-            // Get logprobs for last time step
-            let logits = logits.i((.., logits.dim(1)? - 1, ..))?;
-            //let logprobs = ops::softmax_last_dim(&logits)?;
-            let logprobs = ops::softmax(&logits, 1)?;
+    /// Nothing to cache: the next token only depends on the current one
+    fn new_cache(&self) -> Cache {
+        Cache::new(0)
+    }
+
+    fn forward_step(&self, xs: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        cache.advance(xs.dim(1)?);
+        self.forward(xs)
+    }
 
-            // Forced to do this because Candle doesn't have `torch.multinomial` built in.
-            // May need to PR this in myself eventually.
-            let prs: Vec<Vec<f32>> = logprobs.to_dtype(candle_core::DType::F32)?.to_vec2()?;
-            let next_tokens: Result<Vec<u32>> = prs
-                .iter()
-                .map(|p| {
-                    let distr = rand::distributions::WeightedIndex::new(p).map_err(Error::wrap)?;
-                    let next_token = distr.sample(&mut self.rng) as u32;
-                    Ok(next_token)
-                })
-                .collect();
-            let next_tokens = next_tokens?;
-            let b = &next_tokens.len();
-            let next_tokens_tensor = Tensor::new(next_tokens, idx.device())?.reshape(&[*b, 1])?;
-            preds = Tensor::cat(&[preds, next_tokens_tensor], 1)?;
-        }
-        // TODO: Delete!
-        Ok(preds)
+    /// Only the current token matters
+    fn context_size(&self) -> usize {
+        1
     }
 }
 
@@ -94,6 +76,7 @@ mod tests {
 
     use super::{Bigram, Config};
     use crate::models::Model;
+    use crate::sampling::{Sampler, SamplingConfig};
 
     #[test]
     fn test_generate() {
@@ -102,10 +85,16 @@ mod tests {
 
         let varmap = VarMap::new();
         let vs = VarBuilder::from_varmap(&varmap, candle_core::DType::F16, &device);
-        let mut model = Bigram::new(vs, &Config { vocab_size: 4 }).unwrap();
+        let model = Bigram::new(vs, &Config { vocab_size: 4 }).unwrap();
 
         // Generate complete gibberish
-        let preds = model.generate(&start_idx, 100).unwrap();
+        let preds = model
+            .generate(
+                &start_idx,
+                100,
+                &mut Sampler::new(&SamplingConfig::default()),
+            )
+            .unwrap();
         println!("Tokens: {:?}", preds.to_vec2::<u32>().unwrap())
     }
 }
@@ -0,0 +1,184 @@
+use candle_core::{Result, Tensor};
+
+use crate::config::pretrained_config::CacheEviction;
+
+/// Keys and values from earlier decoding steps, so each step only runs the new tokens.
+///
+/// One slot per decoder layer; models without attention only use `seq_len`.
+#[derive(Debug, Clone, Default)]
+pub struct Cache {
+    /// (B, n_kv, T, hd) keys and values per layer
+    kvs: Vec<Option<(Tensor, Tensor)>>,
+    /// Number of tokens already processed
+    seq_len: usize,
+    /// (B, seq_len) attention mask, 0 at padding. `None` when nothing is padded
+    mask: Option<Tensor>,
+}
+
+impl Cache {
+    pub fn new(num_layers: usize) -> Self {
+        Self {
+            kvs: vec![None; num_layers],
+            seq_len: 0,
+            mask: None,
+        }
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    /// Mark `n` more tokens as processed. Call once per forward step, after all layers
+    pub fn advance(&mut self, n: usize) {
+        self.seq_len += n;
+    }
+
+    /// Append new keys and values for `layer`, returning everything cached so far
+    pub fn append(&mut self, layer: usize, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let kv = match &self.kvs[layer] {
+            Some((prev_k, prev_v)) => (
+                Tensor::cat(&[prev_k, k], 2)?.contiguous()?,
+                Tensor::cat(&[prev_v, v], 2)?.contiguous()?,
+            ),
+            None => (k.clone(), v.clone()),
+        };
+        self.kvs[layer] = Some(kv.clone());
+        Ok(kv)
+    }
+
+    pub fn mask(&self) -> Option<&Tensor> {
+        self.mask.as_ref()
+    }
+
+    /// Append the (B, T) attention mask of the tokens about to be processed, returning the
+    /// mask over everything cached so far plus them
+    pub fn append_mask(&mut self, mask: &Tensor) -> Result<Tensor> {
+        let full = match &self.mask {
+            Some(prev) => Tensor::cat(&[prev, mask], 1)?,
+            // Nothing before this was padded
+            None if self.seq_len > 0 => {
+                let ones = Tensor::ones((mask.dim(0)?, self.seq_len), mask.dtype(), mask.device())?;
+                Tensor::cat(&[&ones, mask], 1)?
+            }
+            None => mask.clone(),
+        };
+        self.mask = Some(full.clone());
+        Ok(full)
+    }
+
+    pub fn clear(&mut self) {
+        self.kvs.iter_mut().for_each(|kv| *kv = None);
+        self.seq_len = 0;
+        self.mask = None;
+    }
+
+    /// Drop the oldest `n` tokens from every layer
+    pub fn evict(&mut self, n: usize) -> Result<()> {
+        let n = n.min(self.seq_len);
+        for (k, v) in self.kvs.iter_mut().flatten() {
+            let keep = k.dim(2)? - n;
+            *k = k.narrow(2, n, keep)?.contiguous()?;
+            *v = v.narrow(2, n, keep)?.contiguous()?;
+        }
+        if let Some(mask) = self.mask.as_mut() {
+            *mask = mask.narrow(1, n, mask.dim(1)? - n)?;
+        }
+        self.seq_len -= n;
+        Ok(())
+    }
+
+    /// Drop the newest `n` tokens from every layer, e.g. rejected speculative tokens
+    pub fn truncate(&mut self, n: usize) -> Result<()> {
+        let n = n.min(self.seq_len);
+        for (k, v) in self.kvs.iter_mut().flatten() {
+            let keep = k.dim(2)? - n;
+            *k = k.narrow(2, 0, keep)?.contiguous()?;
+            *v = v.narrow(2, 0, keep)?.contiguous()?;
+        }
+        if let Some(mask) = self.mask.as_mut() {
+            *mask = mask.narrow(1, 0, mask.dim(1)? - n)?;
+        }
+        self.seq_len -= n;
+        Ok(())
+    }
+
+    /// Reorder the batch, e.g. when beams are pruned or duplicated. `indices` is the old
+    /// row for each new one
+    pub fn reorder(&mut self, indices: &Tensor) -> Result<()> {
+        for (k, v) in self.kvs.iter_mut().flatten() {
+            *k = k.contiguous()?.index_select(indices, 0)?;
+            *v = v.contiguous()?.index_select(indices, 0)?;
+        }
+        if let Some(mask) = self.mask.as_mut() {
+            *mask = mask.contiguous()?.index_select(indices, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Tokens to feed next so the cache never spans more than `context_size` positions.
+    ///
+    /// `preds` is the whole (B, T) sequence so far; its last `n_new` tokens are not cached yet.
+    pub fn window(
+        &mut self,
+        preds: &Tensor,
+        n_new: usize,
+        context_size: usize,
+        eviction: CacheEviction,
+    ) -> Result<Tensor> {
+        let total = preds.dim(1)?;
+        if self.seq_len + n_new <= context_size {
+            return preds.narrow(1, total - n_new, n_new);
+        }
+        match eviction {
+            CacheEviction::Rolling if n_new < context_size => {
+                self.evict(self.seq_len + n_new - context_size)?;
+                preds.narrow(1, total - n_new, n_new)
+            }
+            // Start over from the last full window
+            _ => {
+                self.clear();
+                let n = total.min(context_size);
+                preds.narrow(1, total - n, n)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, Tensor};
+
+    use super::Cache;
+    use crate::config::pretrained_config::CacheEviction;
+
+    #[test]
+    fn test_window() {
+        let device = Device::Cpu;
+        let preds = Tensor::arange(0u32, 10, &device)
+            .unwrap()
+            .reshape((1, 10))
+            .unwrap();
+        let kv = Tensor::zeros((1, 1, 1, 2), DType::F32, &device).unwrap();
+
+        // Prompt longer than the context is cropped to its tail
+        let mut cache = Cache::new(1);
+        let input = cache.window(&preds, 10, 4, CacheEviction::Rolling).unwrap();
+        assert_eq!(input.to_vec2::<u32>().unwrap(), [[6, 7, 8, 9]]);
+
+        // Full cache: rolling eviction keeps feeding one token
+        for _ in 0..4 {
+            cache.append(0, &kv, &kv).unwrap();
+        }
+        cache.advance(4);
+        let input = cache.window(&preds, 1, 4, CacheEviction::Rolling).unwrap();
+        assert_eq!(input.to_vec2::<u32>().unwrap(), [[9]]);
+        assert_eq!(cache.seq_len(), 3);
+
+        // ...while recompute starts over from the last window
+        let input = cache
+            .window(&preds, 2, 4, CacheEviction::Recompute)
+            .unwrap();
+        assert_eq!(input.to_vec2::<u32>().unwrap(), [[6, 7, 8, 9]]);
+        assert_eq!(cache.seq_len(), 0);
+    }
+}
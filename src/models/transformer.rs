@@ -1,108 +1,467 @@
-use candle_core::{Error, IndexOp, Result, Tensor};
-use candle_nn::{embedding, linear, ops, Embedding, Linear, Module, VarBuilder};
-use rand::{distributions::Distribution, thread_rng};
+use candle_core::{DType, Device, Error, Result, Tensor, D};
+use candle_nn::{
+    embedding, layer_norm, linear, ops, Embedding, LayerNorm, Linear, Module, VarBuilder,
+};
 use serde::Deserialize;
 
-use super::Model;
+use super::{Cache, Model};
+use crate::config::pretrained_config::{
+    CacheEviction, PositionEmbeddingType, PretrainedConfig, RopeScaling,
+};
+use position::PositionEmbedding;
+use rotary::RotaryEmbedding;
+
+pub mod position;
+pub mod rotary;
+
+const LAYER_NORM_EPS: f64 = 1e-5;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     /// Number of n-grams
     pub vocab_size: usize,
+    /// Maximum sequence length the model accepts
+    pub context_size: usize,
     pub hidden_dim: usize,
+    /// MLP hidden dimension
+    pub intermediate_dim: usize,
+    pub num_attention_heads: usize,
+    /// Heads produced by the K/V projections. Fewer than `num_attention_heads` gives
+    /// grouped-query attention; 1 gives multi-query attention
+    pub num_key_value_heads: usize,
+    /// Number of decoder blocks
+    pub hidden_layers: usize,
+    pub position_embedding: PositionEmbeddingType,
+    pub rope_theta: f32,
+    /// Fraction of each head's dimensions rotated by RoPE
+    pub rotary_pct: f32,
+    pub rope_scaling: Option<RopeScaling>,
+    /// How `generate` keeps the KV cache within `context_size`
+    pub cache_eviction: CacheEviction,
+}
+
+impl From<&PretrainedConfig> for Config {
+    fn from(cfg: &PretrainedConfig) -> Self {
+        Self {
+            vocab_size: cfg.vocab_size as usize,
+            context_size: cfg.context_size as usize,
+            hidden_dim: cfg.hidden_size as usize,
+            intermediate_dim: cfg.intermediate_size as usize,
+            num_attention_heads: cfg.num_attention_heads as usize,
+            num_key_value_heads: cfg.num_key_value_heads as usize,
+            hidden_layers: cfg.hidden_layers as usize,
+            position_embedding: cfg.position_embedding,
+            rope_theta: cfg.rope_theta,
+            rotary_pct: cfg.rotary_pct,
+            rope_scaling: cfg.rope_scaling,
+            cache_eviction: cfg.cache_eviction,
+        }
+    }
+}
+
+/// (T, offset + T) mask: 1 where query `i` may NOT attend to key `j` (i.e. `j > offset + i`)
+fn causal_mask(t: usize, offset: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<u8> = (0..t)
+        .flat_map(|i| (0..offset + t).map(move |j| u8::from(j > offset + i)))
+        .collect();
+    Tensor::from_slice(&mask, (t, offset + t), device)
+}
+
+/// (B, offset + T) key positions and (B, 1, T, offset + T) attention mask for the last `t`
+/// of the keys in `key_mask`, which is (B, offset + T) and 0 at padding.
+///
+/// Positions count only real tokens, so a left-padded row lines up with its unpadded self.
+/// Padding never gets attended to, except by itself so its softmax stays finite.
+fn padded_positions_and_mask(key_mask: &Tensor, t: usize) -> Result<(Tensor, Tensor)> {
+    let (b, s) = key_mask.dims2()?;
+    let offset = s - t;
+    let device = key_mask.device();
+    let key_mask: Vec<Vec<u32>> = key_mask.to_dtype(DType::U32)?.to_vec2()?;
+    let mut positions: Vec<u32> = Vec::with_capacity(b * s);
+    let mut blocked: Vec<u8> = Vec::with_capacity(b * t * s);
+    for row in key_mask.iter() {
+        let mut seen = 0;
+        for &m in row.iter() {
+            seen += m.min(1);
+            positions.push(seen.max(1) - 1);
+        }
+        for i in offset..s {
+            blocked.extend((0..s).map(|j| u8::from(j > i || (row[j] == 0 && j != i))));
+        }
+    }
+    Ok((
+        Tensor::from_vec(positions, (b, s), device)?,
+        Tensor::from_vec(blocked, (b, 1, t, s), device)?,
+    ))
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
+    let shape = mask.shape();
+    let on_true = Tensor::new(on_true, on_false.device())?
+        .to_dtype(on_false.dtype())?
+        .broadcast_as(shape.dims())?;
+    mask.where_cond(&on_true, on_false)
+}
+
+/// Share each K/V head across `n_rep` query heads: (B, n_kv, T, hd) -> (B, n_kv * n_rep, T, hd)
+fn repeat_kv(xs: Tensor, n_rep: usize) -> Result<Tensor> {
+    if n_rep == 1 {
+        return Ok(xs);
+    }
+    let (b, n_kv, t, hd) = xs.dims4()?;
+    xs.unsqueeze(2)?
+        .broadcast_as((b, n_kv, n_rep, t, hd))?
+        .reshape((b, n_kv * n_rep, t, hd))
+}
+
+#[derive(Debug, Clone)]
+struct CausalSelfAttention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    c_proj: Linear,
+    rotary: Option<RotaryEmbedding>,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    /// Index of this layer's slot in the KV cache
+    layer_idx: usize,
+    /// Cache keys before rotation, since rolling eviction moves them to new positions
+    rotate_cached_keys: bool,
+}
+
+impl CausalSelfAttention {
+    fn new(
+        vs: VarBuilder,
+        cfg: &Config,
+        rotary: Option<RotaryEmbedding>,
+        layer_idx: usize,
+    ) -> Result<Self> {
+        if !cfg.hidden_dim.is_multiple_of(cfg.num_attention_heads) {
+            return Err(Error::Msg(format!(
+                "hidden_dim {} is not divisible by num_attention_heads {}",
+                cfg.hidden_dim, cfg.num_attention_heads
+            )));
+        }
+        if cfg.num_key_value_heads == 0
+            || !cfg
+                .num_attention_heads
+                .is_multiple_of(cfg.num_key_value_heads)
+        {
+            return Err(Error::Msg(format!(
+                "num_attention_heads {} is not divisible by num_key_value_heads {}",
+                cfg.num_attention_heads, cfg.num_key_value_heads
+            )));
+        }
+        let head_dim = cfg.hidden_dim / cfg.num_attention_heads;
+        let kv_dim = cfg.num_key_value_heads * head_dim;
+        Ok(Self {
+            q_proj: linear(cfg.hidden_dim, cfg.hidden_dim, vs.pp("q_proj"))?,
+            k_proj: linear(cfg.hidden_dim, kv_dim, vs.pp("k_proj"))?,
+            v_proj: linear(cfg.hidden_dim, kv_dim, vs.pp("v_proj"))?,
+            c_proj: linear(cfg.hidden_dim, cfg.hidden_dim, vs.pp("c_proj"))?,
+            rotary,
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim,
+            layer_idx,
+            rotate_cached_keys: cfg.cache_eviction == CacheEviction::Rolling,
+        })
+    }
+
+    /// `positions` is (B or 1, offset + T), one per key; `mask` is (B or 1, 1, T, offset + T),
+    /// 1 where attention is not allowed
+    fn forward(
+        &self,
+        xs: &Tensor,
+        positions: &Tensor,
+        mask: Option<&Tensor>,
+        cache: Option<&mut Cache>,
+    ) -> Result<Tensor> {
+        let (b, t, c) = xs.dims3()?;
+        // (B, T, n * hd) -> (B, n, T, hd)
+        let split_heads = |x: Tensor, n: usize| -> Result<Tensor> {
+            x.reshape((b, t, n, self.head_dim))?
+                .transpose(1, 2)?
+                .contiguous()
+        };
+        let q = split_heads(self.q_proj.forward(xs)?, self.num_heads)?;
+        let k = split_heads(self.k_proj.forward(xs)?, self.num_kv_heads)?;
+        let v = split_heads(self.v_proj.forward(xs)?, self.num_kv_heads)?;
+        let s = positions.dim(1)?;
+        let query_positions = positions.narrow(1, s - t, t)?;
+        let rotate = |x: Tensor, positions: &Tensor| match &self.rotary {
+            Some(rotary) => rotary.apply(&x, positions),
+            None => Ok(x),
+        };
+        let q = rotate(q, &query_positions)?;
+        // Cache before repeating, so grouped-query models keep the smaller K/V
+        let (k, v) = if self.rotate_cached_keys {
+            let (k, v) = match cache {
+                Some(cache) => cache.append(self.layer_idx, &k, &v)?,
+                None => (k, v),
+            };
+            (rotate(k, positions)?, v)
+        } else {
+            let k = rotate(k, &query_positions)?;
+            match cache {
+                Some(cache) => cache.append(self.layer_idx, &k, &v)?,
+                None => (k, v),
+            }
+        };
+        let n_rep = self.num_heads / self.num_kv_heads;
+        let k = repeat_kv(k, n_rep)?.contiguous()?;
+        let v = repeat_kv(v, n_rep)?.contiguous()?;
+
+        // (B, nh, T, hd) x (B, nh, hd, offset + T) -> (B, nh, T, offset + T)
+        let scale = 1.0 / (self.head_dim as f64).sqrt();
+        let att = (q.matmul(&k.t()?)? * scale)?;
+        let att = match mask {
+            Some(mask) => masked_fill(&att, &mask.broadcast_as(att.shape())?, f32::NEG_INFINITY)?,
+            None => att,
+        };
+        // Not softmax_last_dim: it has no backward pass
+        let att = ops::softmax(&att, D::Minus1)?;
+
+        // (B, nh, T, offset + T) x (B, nh, offset + T, hd) -> (B, nh, T, hd) -> (B, T, C)
+        let y = att.matmul(&v)?.transpose(1, 2)?.reshape((b, t, c))?;
+        self.c_proj.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    c_fc: Linear,
+    c_proj: Linear,
+}
+
+impl Mlp {
+    fn new(vs: VarBuilder, cfg: &Config) -> Result<Self> {
+        Ok(Self {
+            c_fc: linear(cfg.hidden_dim, cfg.intermediate_dim, vs.pp("c_fc"))?,
+            c_proj: linear(cfg.intermediate_dim, cfg.hidden_dim, vs.pp("c_proj"))?,
+        })
+    }
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.c_proj.forward(&self.c_fc.forward(xs)?.gelu()?)
+    }
+}
+
+/// Pre-norm decoder block, as in GPT-2
+#[derive(Debug, Clone)]
+struct Block {
+    ln_1: LayerNorm,
+    attn: CausalSelfAttention,
+    ln_2: LayerNorm,
+    mlp: Mlp,
+}
+
+impl Block {
+    fn new(
+        vs: VarBuilder,
+        cfg: &Config,
+        rotary: Option<RotaryEmbedding>,
+        layer_idx: usize,
+    ) -> Result<Self> {
+        Ok(Self {
+            ln_1: layer_norm(cfg.hidden_dim, LAYER_NORM_EPS, vs.pp("ln_1"))?,
+            attn: CausalSelfAttention::new(vs.pp("attn"), cfg, rotary, layer_idx)?,
+            ln_2: layer_norm(cfg.hidden_dim, LAYER_NORM_EPS, vs.pp("ln_2"))?,
+            mlp: Mlp::new(vs.pp("mlp"), cfg)?,
+        })
+    }
+
+    fn forward(
+        &self,
+        xs: &Tensor,
+        positions: &Tensor,
+        mask: Option<&Tensor>,
+        cache: Option<&mut Cache>,
+    ) -> Result<Tensor> {
+        let xs = (xs
+            + self
+                .attn
+                .forward(&self.ln_1.forward(xs)?, positions, mask, cache)?)?;
+        &xs + self.mlp.forward(&self.ln_2.forward(&xs)?)?
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Transformer {
     wte: Embedding,
+    wpe: PositionEmbedding,
+    h: Vec<Block>,
+    ln_f: LayerNorm,
     lm_head: Linear,
-    rng: rand::rngs::ThreadRng,
+    context_size: usize,
+    cache_eviction: CacheEviction,
 }
 
 impl Transformer {
     pub fn new(vs: VarBuilder, cfg: &Config) -> Result<Self> {
+        // Rolling shifts cached tokens to earlier positions, which only rotary keys can follow
+        if cfg.cache_eviction == CacheEviction::Rolling
+            && matches!(
+                cfg.position_embedding,
+                PositionEmbeddingType::Learned | PositionEmbeddingType::Sinusoidal
+            )
+        {
+            return Err(Error::Msg(format!(
+                "Rolling cache eviction needs rotary or no position embeddings, got {:?}",
+                cfg.position_embedding
+            )));
+        }
+        // One cos/sin cache, shared by every layer
+        let rotary = match cfg.position_embedding {
+            PositionEmbeddingType::Rotary => Some(RotaryEmbedding::new(cfg, vs.device())?),
+            _ => None,
+        };
+        let h = (0..cfg.hidden_layers)
+            .map(|i| Block::new(vs.pp(format!("h.{}", i)), cfg, rotary.clone(), i))
+            .collect::<Result<Vec<Block>>>()?;
         Ok(Self {
             wte: embedding(cfg.vocab_size, cfg.hidden_dim, vs.pp("wte"))?,
+            wpe: PositionEmbedding::new(vs.clone(), cfg)?,
+            h,
+            ln_f: layer_norm(cfg.hidden_dim, LAYER_NORM_EPS, vs.pp("ln_f"))?,
             lm_head: linear(cfg.hidden_dim, cfg.vocab_size, vs.pp("lm_head"))?,
-            rng: thread_rng(),
+            context_size: cfg.context_size,
+            cache_eviction: cfg.cache_eviction,
         })
     }
+
+    /// Logits for (B, T) tokens, where (B, T) `attention_mask` is 0 at (left) padding
+    pub fn forward_masked(&self, xs: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        self.forward_with_cache(xs, Some(attention_mask), None)
+    }
+
+    /// Logits for (B, T) tokens; with a cache, they continue the sequence stored in it
+    fn forward_with_cache(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        mut cache: Option<&mut Cache>,
+    ) -> Result<Tensor> {
+        let (b, t) = xs.dims2()?;
+        let offset = cache.as_ref().map_or(0, |cache| cache.seq_len());
+        if offset + t > self.context_size {
+            return Err(Error::Msg(format!(
+                "Cannot forward sequence of length {}, context size is only {}",
+                offset + t,
+                self.context_size
+            )));
+        }
+        // Once a cached sequence has padding, later steps need the mask too
+        let attention_mask = match (attention_mask, cache.as_ref().and_then(|c| c.mask())) {
+            (Some(mask), _) => Some(mask.clone()),
+            (None, Some(_)) => Some(Tensor::ones((b, t), DType::U32, xs.device())?),
+            (None, None) => None,
+        };
+        let key_mask = match (attention_mask, cache.as_deref_mut()) {
+            (Some(mask), Some(cache)) => Some(cache.append_mask(&mask)?),
+            (mask, _) => mask,
+        };
+        let (positions, mask) = match key_mask {
+            Some(key_mask) => {
+                let (positions, mask) = padded_positions_and_mask(&key_mask, t)?;
+                (positions, Some(mask))
+            }
+            None => {
+                let positions =
+                    Tensor::arange(0u32, (offset + t) as u32, xs.device())?.unsqueeze(0)?;
+                // A single new token may attend to everything before it
+                let mask = match t {
+                    1 => None,
+                    _ => {
+                        Some(causal_mask(t, offset, xs.device())?.reshape((1, 1, t, offset + t))?)
+                    }
+                };
+                (positions, mask)
+            }
+        };
+
+        let query_positions = positions.narrow(1, offset, t)?;
+        let mut hidden = self.wpe.forward(&self.wte.forward(xs)?, &query_positions)?;
+        for block in self.h.iter() {
+            hidden = block.forward(&hidden, &positions, mask.as_ref(), cache.as_deref_mut())?;
+        }
+        if let Some(cache) = cache {
+            cache.advance(t);
+        }
+        let hidden = self.ln_f.forward(&hidden)?;
+        self.lm_head.forward(&hidden)
+    }
 }
 
 impl Module for Transformer {
-    /// Returns logprobs
+    /// Returns logits of shape (B, T, vocab_size)
     fn forward(&self, xs: &candle_core::Tensor) -> Result<Tensor> {
-        // Remove this eventually. Here for reference
-        // println!("Input tensor dims: {:?}", tensor.shape());
-        // println!("Input values: {:?}", tensor.to_string());
-        // println!(
-        //     "Embeddings: {:?}",
-        //     self.token_embedding_table.embeddings().to_string()
-        // );
-        //let logits = self.token_embedding_table.forward(&xs.flatten_all()?)?;
-        let tok_emb = self.wte.forward(&xs)?;
-        let logits = self.lm_head.forward(&tok_emb)?;
-        Ok(logits)
+        self.forward_with_cache(xs, None, None)
     }
 }
 
 impl Model for Transformer {
-    fn from_config(
-        vs: VarBuilder,
-        cfg: &crate::config::pretrained_config::PretrainedConfig,
-    ) -> Result<Self> {
-        Ok(Self {
-            wte: embedding(
-                cfg.vocab_size as usize,
-                cfg.hidden_size as usize,
-                vs.pp("wte"),
-            )?,
-            lm_head: linear(
-                cfg.hidden_size as usize,
-                cfg.vocab_size as usize,
-                vs.pp("lm_head"),
-            )?,
-            rng: thread_rng(),
-        })
+    fn from_config(vs: VarBuilder, cfg: &PretrainedConfig) -> Result<Self> {
+        Self::new(vs, &cfg.into())
+    }
+
+    fn new_cache(&self) -> Cache {
+        Cache::new(self.h.len())
+    }
+
+    fn forward_step(&self, xs: &Tensor, cache: &mut Cache) -> Result<Tensor> {
+        self.forward_with_cache(xs, None, Some(cache))
     }
 
-    fn generate(&mut self, idx: &Tensor, max_new_tokens: usize) -> Result<Tensor> {
-        let mut preds = idx.clone();
-        for _ in 0..max_new_tokens {
-            let logits = self.forward(&preds)?;
-            // Get logprobs for last time step
-            let logits = logits.i((.., logits.dim(1)? - 1, ..))?;
-            //let logprobs = ops::softmax_last_dim(&logits)?;
-            let logprobs = ops::softmax(&logits, 1)?;
-
-            // Forced to do this because Candle doesn't have `torch.multinomial` built in.
-            // May need to PR this in myself eventually.
-            let prs: Vec<Vec<f32>> = logprobs.to_dtype(candle_core::DType::F32)?.to_vec2()?;
-            let next_tokens: Result<Vec<u32>> = prs
-                .iter()
-                .map(|p| {
-                    let distr = rand::distributions::WeightedIndex::new(p).map_err(Error::wrap)?;
-                    let next_token = distr.sample(&mut self.rng) as u32;
-                    Ok(next_token)
-                })
-                .collect();
-            let next_tokens = next_tokens?;
-            let b = &next_tokens.len();
-            let next_tokens_tensor = Tensor::new(next_tokens, idx.device())?.reshape(&[*b, 1])?;
-            preds = Tensor::cat(&[preds, next_tokens_tensor], 1)?;
-        }
-        // TODO: Delete!
-        Ok(preds)
+    fn forward_step_masked(
+        &self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        cache: &mut Cache,
+    ) -> Result<Tensor> {
+        self.forward_with_cache(xs, attention_mask, Some(cache))
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    fn cache_eviction(&self) -> CacheEviction {
+        self.cache_eviction
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use candle_core::{DType, Device, Tensor};
+    use candle_core::{DType, Device, IndexOp, Module, Tensor};
     use candle_nn::{VarBuilder, VarMap};
 
     use super::Model;
     use super::{Config, Transformer};
+    use crate::config::pretrained_config::{CacheEviction, PositionEmbeddingType};
+    use crate::generation::{StopReason, StoppingCriteria};
+    use crate::sampling::{Sampler, SamplingConfig};
+    use crate::util::seeded_var_builder;
+
+    fn tiny_config() -> Config {
+        Config {
+            vocab_size: 4,
+            context_size: 128,
+            hidden_dim: 32,
+            intermediate_dim: 128,
+            num_attention_heads: 4,
+            num_key_value_heads: 4,
+            hidden_layers: 2,
+            position_embedding: PositionEmbeddingType::Learned,
+            rope_theta: 10000.0,
+            rotary_pct: 1.0,
+            rope_scaling: None,
+            cache_eviction: CacheEviction::Recompute,
+        }
+    }
 
     #[test]
     fn test_generate() {
@@ -111,17 +470,375 @@ mod tests {
 
         let varmap = VarMap::new();
         let vs = VarBuilder::from_varmap(&varmap, candle_core::DType::F16, &device);
-        let mut model = Transformer::new(
-            vs,
-            &Config {
-                vocab_size: 4,
-                hidden_dim: 32,
-            },
-        )
-        .unwrap();
+        let model = Transformer::new(vs, &tiny_config()).unwrap();
 
         // Generate complete gibberish
-        let preds = model.generate(&start_idx, 100).unwrap();
+        let preds = model
+            .generate(
+                &start_idx,
+                100,
+                &mut Sampler::new(&SamplingConfig::default()),
+            )
+            .unwrap();
         println!("Tokens: {:?}", preds.to_vec2::<u32>().unwrap())
     }
+
+    #[test]
+    fn test_causal() {
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let model = Transformer::new(vs, &tiny_config()).unwrap();
+
+        // Changing the last token must not change logits at earlier positions
+        let a = Tensor::new(&[[0u32, 1, 2, 3]], &device).unwrap();
+        let b = Tensor::new(&[[0u32, 1, 2, 0]], &device).unwrap();
+        let logits_a = model.forward(&a).unwrap();
+        let logits_b = model.forward(&b).unwrap();
+        assert_eq!(logits_a.dims(), [1, 4, 4]);
+        let prefix_a: Vec<Vec<f32>> = logits_a.i((0, ..3)).unwrap().to_vec2().unwrap();
+        let prefix_b: Vec<Vec<f32>> = logits_b.i((0, ..3)).unwrap().to_vec2().unwrap();
+        assert_eq!(prefix_a, prefix_b);
+    }
+
+    #[test]
+    fn test_context_size() {
+        let device = Device::Cpu;
+        for position_embedding in [
+            PositionEmbeddingType::Learned,
+            PositionEmbeddingType::Sinusoidal,
+            PositionEmbeddingType::Rotary,
+            PositionEmbeddingType::None,
+        ] {
+            let varmap = VarMap::new();
+            let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+            let cfg = Config {
+                context_size: 8,
+                position_embedding,
+                ..tiny_config()
+            };
+            let model = Transformer::new(vs, &cfg).unwrap();
+
+            let fits = Tensor::zeros((2, 8), DType::U32, &device).unwrap();
+            assert_eq!(model.forward(&fits).unwrap().dims(), [2, 8, 4]);
+
+            let too_long = Tensor::zeros((2, 9), DType::U32, &device).unwrap();
+            assert!(model.forward(&too_long).is_err());
+        }
+    }
+
+    #[test]
+    fn test_grouped_query_attention() {
+        let device = Device::Cpu;
+        let idx = Tensor::new(&[[0u32, 1, 2, 3]], &device).unwrap();
+        // Grouped-query, then multi-query
+        for num_key_value_heads in [2, 1] {
+            let varmap = VarMap::new();
+            let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+            let cfg = Config {
+                num_key_value_heads,
+                ..tiny_config()
+            };
+            let model = Transformer::new(vs, &cfg).unwrap();
+            assert_eq!(model.forward(&idx).unwrap().dims(), [1, 4, 4]);
+
+            // K/V projections only cover the shared heads: 32 / 4 = 8 dims per head
+            let vars = varmap.data().lock().unwrap();
+            let k_proj = vars.get("h.0.attn.k_proj.weight").unwrap();
+            assert_eq!(k_proj.dims(), [8 * num_key_value_heads, 32]);
+        }
+
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let cfg = Config {
+            num_key_value_heads: 3,
+            ..tiny_config()
+        };
+        assert!(Transformer::new(vs, &cfg).is_err());
+    }
+
+    #[test]
+    fn test_cached_forward() {
+        let device = Device::Cpu;
+        let idx = Tensor::new(&[[0u32, 1, 2, 3, 2, 1], [3, 3, 1, 0, 0, 2]], &device).unwrap();
+        for position_embedding in [
+            PositionEmbeddingType::Learned,
+            PositionEmbeddingType::Rotary,
+        ] {
+            let varmap = VarMap::new();
+            let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+            let cfg = Config {
+                num_key_value_heads: 2,
+                position_embedding,
+                ..tiny_config()
+            };
+            let model = Transformer::new(vs, &cfg).unwrap();
+            let uncached = model.forward(&idx).unwrap();
+
+            // Prefill three tokens, then feed the rest one at a time
+            let mut cache = model.new_cache();
+            let mut steps = vec![model
+                .forward_step(&idx.i((.., ..3)).unwrap(), &mut cache)
+                .unwrap()];
+            for pos in 3..6 {
+                steps.push(
+                    model
+                        .forward_step(&idx.i((.., pos..pos + 1)).unwrap(), &mut cache)
+                        .unwrap(),
+                );
+            }
+            assert_eq!(cache.seq_len(), 6);
+            let cached = Tensor::cat(&steps, 1).unwrap();
+
+            let max_diff: f32 = (cached - &uncached)
+                .unwrap()
+                .abs()
+                .unwrap()
+                .max_keepdim(2)
+                .unwrap()
+                .flatten_all()
+                .unwrap()
+                .max(0)
+                .unwrap()
+                .to_scalar()
+                .unwrap();
+            assert!(max_diff < 1e-5, "max diff {}", max_diff);
+        }
+    }
+
+    #[test]
+    fn test_generate_past_context() {
+        let device = Device::Cpu;
+        let start_idx = Tensor::zeros((2, 3), DType::U32, &device).unwrap();
+        for cache_eviction in [CacheEviction::Recompute, CacheEviction::Rolling] {
+            let varmap = VarMap::new();
+            let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+            let cfg = Config {
+                context_size: 8,
+                position_embedding: PositionEmbeddingType::Rotary,
+                cache_eviction,
+                ..tiny_config()
+            };
+            let model = Transformer::new(vs, &cfg).unwrap();
+            let preds = model
+                .generate(
+                    &start_idx,
+                    50,
+                    &mut Sampler::new(&SamplingConfig::default()),
+                )
+                .unwrap();
+            assert_eq!(preds.dims(), [2, 53]);
+        }
+    }
+
+    #[test]
+    fn test_rolling_window() {
+        let device = Device::Cpu;
+        let idx = Tensor::new(&[[0u32, 1, 2, 3, 2], [3, 3, 1, 0, 0]], &device).unwrap();
+        // One layer: cached keys and values depend only on their own token, so stepping past
+        // the window must match running the cropped window from scratch
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let cfg = Config {
+            context_size: 4,
+            hidden_layers: 1,
+            position_embedding: PositionEmbeddingType::Rotary,
+            cache_eviction: CacheEviction::Rolling,
+            ..tiny_config()
+        };
+        let model = Transformer::new(vs, &cfg).unwrap();
+        let cropped = model.forward(&idx.i((.., 1..)).unwrap()).unwrap();
+
+        let mut cache = model.new_cache();
+        model
+            .forward_step(&idx.i((.., ..4)).unwrap(), &mut cache)
+            .unwrap();
+        let input = cache.window(&idx, 1, 4, CacheEviction::Rolling).unwrap();
+        let rolled = model.forward_step(&input, &mut cache).unwrap();
+        let max_diff: f32 = (rolled - cropped.i((.., 3..)).unwrap())
+            .unwrap()
+            .abs()
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .max(0)
+            .unwrap()
+            .to_scalar()
+            .unwrap();
+        assert!(max_diff < 1e-5, "max diff {}", max_diff);
+
+        // Learned positions can't follow the window
+        let cfg = Config {
+            cache_eviction: CacheEviction::Rolling,
+            ..tiny_config()
+        };
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        assert!(Transformer::new(vs, &cfg).is_err());
+    }
+
+    #[test]
+    fn test_generate_until_eos() {
+        let device = Device::Cpu;
+        let start_idx = Tensor::new(&[[0u32], [1]], &device).unwrap();
+        let varmap = VarMap::new();
+        let vs = seeded_var_builder(&varmap, 1337, DType::F32, &device);
+        let model = Transformer::new(vs, &tiny_config()).unwrap();
+        let greedy = SamplingConfig {
+            greedy: true,
+            ..Default::default()
+        };
+        let full: Vec<Vec<u32>> = model
+            .generate(&start_idx, 10, &mut Sampler::new(&greedy))
+            .unwrap()
+            .to_vec2()
+            .unwrap();
+
+        // Each row ends at its own first EOS; greedy picks are otherwise unchanged
+        let eos = full[0][3];
+        let mut stopping = StoppingCriteria::default();
+        stopping.eos_token_id = Some(eos);
+        let generation = model
+            .generate_until(&start_idx, 10, &mut Sampler::new(&greedy), &stopping)
+            .unwrap();
+        assert!(generation.ids[0].len() <= 4);
+        for ((ids, reason), full) in generation
+            .ids
+            .iter()
+            .zip(generation.stop_reasons.iter())
+            .zip(full.iter())
+        {
+            assert_eq!(ids[..], full[..ids.len()]);
+            match reason {
+                StopReason::Eos => assert_eq!(ids.last(), Some(&eos)),
+                _ => assert_eq!(ids.len(), 11),
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_batch() {
+        let device = Device::Cpu;
+        let prompts = vec![vec![1u32, 2, 3, 1], vec![2], vec![3, 3]];
+        let greedy = SamplingConfig {
+            greedy: true,
+            ..Default::default()
+        };
+        for position_embedding in [
+            PositionEmbeddingType::Learned,
+            PositionEmbeddingType::Sinusoidal,
+            PositionEmbeddingType::Rotary,
+        ] {
+            let varmap = VarMap::new();
+            let vs = seeded_var_builder(&varmap, 1337, DType::F32, &device);
+            let model = Transformer::new(
+                vs,
+                &Config {
+                    position_embedding,
+                    ..tiny_config()
+                },
+            )
+            .unwrap();
+
+            // Padding is invisible: each row matches generating its prompt alone
+            let batch = model
+                .generate_batch(
+                    &prompts,
+                    0,
+                    &device,
+                    8,
+                    &mut Sampler::new(&greedy),
+                    &StoppingCriteria::default(),
+                )
+                .unwrap();
+            for (prompt, ids) in prompts.iter().zip(batch.ids.iter()) {
+                let alone: Vec<Vec<u32>> = model
+                    .generate(
+                        &Tensor::new(prompt.as_slice(), &device)
+                            .unwrap()
+                            .unsqueeze(0)
+                            .unwrap(),
+                        8,
+                        &mut Sampler::new(&greedy),
+                    )
+                    .unwrap()
+                    .to_vec2()
+                    .unwrap();
+                assert_eq!(ids, &alone[0]);
+            }
+        }
+
+        let varmap = VarMap::new();
+        let vs = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let model = Transformer::new(vs, &tiny_config()).unwrap();
+        assert!(model
+            .generate_batch(
+                &[vec![1], vec![]],
+                0,
+                &device,
+                8,
+                &mut Sampler::new(&greedy),
+                &StoppingCriteria::default(),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_generate_batch_penalty() {
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vs = seeded_var_builder(&varmap, 1337, DType::F32, &device);
+        let model = Transformer::new(vs, &tiny_config()).unwrap();
+        let penalized = SamplingConfig {
+            greedy: true,
+            repetition_penalty: Some(1000.0),
+            ..Default::default()
+        };
+        let alone = |prompt: &[u32]| -> Vec<u32> {
+            let idx = Tensor::new(prompt, &device).unwrap().unsqueeze(0).unwrap();
+            let ids: Vec<Vec<u32>> = model
+                .generate(&idx, 6, &mut Sampler::new(&penalized))
+                .unwrap()
+                .to_vec2()
+                .unwrap();
+            ids[0].clone()
+        };
+        // Pad with the token the short prompt picks first, so penalizing it would show
+        let prompts = vec![vec![1u32, 2, 3, 1], vec![2]];
+        let pad = alone(&prompts[1])[1];
+        let batch = model
+            .generate_batch(
+                &prompts,
+                pad,
+                &device,
+                6,
+                &mut Sampler::new(&penalized),
+                &StoppingCriteria::default(),
+            )
+            .unwrap();
+        for (prompt, ids) in prompts.iter().zip(batch.ids.iter()) {
+            assert_eq!(ids, &alone(prompt));
+        }
+    }
+
+    #[test]
+    fn test_seeded_generate() {
+        let device = Device::Cpu;
+        let start_idx = Tensor::zeros((2, 1), DType::U32, &device).unwrap();
+        let run = |seed: u64| -> Vec<Vec<u32>> {
+            let varmap = VarMap::new();
+            let vs = seeded_var_builder(&varmap, seed, DType::F32, &device);
+            let model = Transformer::new(vs, &tiny_config()).unwrap();
+            let mut sampler = Sampler::new(&SamplingConfig {
+                seed: Some(seed),
+                ..Default::default()
+            });
+            model
+                .generate(&start_idx, 50, &mut sampler)
+                .unwrap()
+                .to_vec2()
+                .unwrap()
+        };
+        assert_eq!(run(1337), run(1337));
+        assert_ne!(run(1337), run(1338));
+    }
 }
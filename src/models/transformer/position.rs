@@ -0,0 +1,91 @@
+use candle_core::{Device, Error, Result, Tensor};
+use candle_nn::{embedding, Embedding, Module, VarBuilder};
+
+use super::Config;
+use crate::config::pretrained_config::PositionEmbeddingType;
+
+/// Absolute position embeddings, added to the token embeddings
+#[derive(Debug, Clone)]
+pub enum PositionEmbedding {
+    Learned(Embedding),
+    /// Precomputed (context_size, hidden_dim) table
+    Sinusoidal(Tensor),
+    None,
+}
+
+impl PositionEmbedding {
+    pub fn new(vs: VarBuilder, cfg: &Config) -> Result<Self> {
+        match cfg.position_embedding {
+            PositionEmbeddingType::Learned => Ok(Self::Learned(embedding(
+                cfg.context_size,
+                cfg.hidden_dim,
+                vs.pp("wpe"),
+            )?)),
+            PositionEmbeddingType::Sinusoidal => Ok(Self::Sinusoidal(
+                sinusoidal_table(cfg.context_size, cfg.hidden_dim, vs.device())?
+                    .to_dtype(vs.dtype())?,
+            )),
+            // Rotary embeddings live in the attention layers instead
+            PositionEmbeddingType::Rotary | PositionEmbeddingType::None => Ok(Self::None),
+        }
+    }
+
+    /// Add position information to (B, T, C) token embeddings, given (B or 1, T) u32 positions
+    pub fn forward(&self, xs: &Tensor, positions: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Learned(wpe) => xs.broadcast_add(&wpe.forward(positions)?),
+            Self::Sinusoidal(table) => {
+                let (b, t) = positions.dims2()?;
+                let pe = table.index_select(&positions.flatten_all()?, 0)?;
+                xs.broadcast_add(&pe.reshape((b, t, table.dim(1)?))?)
+            }
+            Self::None => Ok(xs.clone()),
+        }
+    }
+}
+
+/// `PE[pos, 2i] = sin(pos / 10000^(2i/d))`, `PE[pos, 2i+1] = cos(pos / 10000^(2i/d))`
+pub fn sinusoidal_table(context_size: usize, dim: usize, device: &Device) -> Result<Tensor> {
+    if !dim.is_multiple_of(2) {
+        return Err(Error::Msg(format!(
+            "Sinusoidal embeddings need an even hidden_dim, got {}",
+            dim
+        )));
+    }
+    let table: Vec<f32> = (0..context_size)
+        .flat_map(|pos| {
+            (0..dim).map(move |i| {
+                let freq = 1.0 / 10000f64.powf((i - i % 2) as f64 / dim as f64);
+                let angle = pos as f64 * freq;
+                if i % 2 == 0 {
+                    angle.sin() as f32
+                } else {
+                    angle.cos() as f32
+                }
+            })
+        })
+        .collect();
+    Tensor::from_vec(table, (context_size, dim), device)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, IndexOp};
+
+    use super::sinusoidal_table;
+
+    #[test]
+    fn test_sinusoidal_table() {
+        let table = sinusoidal_table(4, 6, &Device::Cpu).unwrap();
+        assert_eq!(table.dims(), [4, 6]);
+
+        // Position 0 is sin(0) = 0, cos(0) = 1 everywhere
+        let first: Vec<f32> = table.i(0).unwrap().to_vec1().unwrap();
+        assert_eq!(first, vec![0.0, 1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        // Lowest dimension pair rotates at frequency 1
+        let third: Vec<f32> = table.i(2).unwrap().to_vec1().unwrap();
+        assert!((third[0] - 2f32.sin()).abs() < 1e-6);
+        assert!((third[1] - 2f32.cos()).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,218 @@
+use candle_core::{Device, Error, Result, Tensor, D};
+
+use super::Config;
+use crate::config::pretrained_config::RopeScaling;
+
+/// Rotary position embeddings (RoPE), applied to queries and keys inside attention.
+///
+/// Only the first `rotary_dim` dimensions of each head are rotated (GPT-NeoX style
+/// `rotary_pct`); the remainder pass through unchanged.
+#[derive(Debug, Clone)]
+pub struct RotaryEmbedding {
+    /// (context_size, rotary_dim)
+    cos: Tensor,
+    /// (context_size, rotary_dim)
+    sin: Tensor,
+    rotary_dim: usize,
+}
+
+impl RotaryEmbedding {
+    pub fn new(cfg: &Config, device: &Device) -> Result<Self> {
+        let head_dim = cfg.hidden_dim / cfg.num_attention_heads;
+        if !(0.0..=1.0).contains(&cfg.rotary_pct) {
+            return Err(Error::Msg(format!(
+                "rotary_pct must be in 0..1, got {}",
+                cfg.rotary_pct
+            )));
+        }
+        // Rotation works on pairs of dimensions, so round down to an even count
+        let rotary_dim = ((head_dim as f32 * cfg.rotary_pct) as usize) & !1;
+        if rotary_dim == 0 {
+            return Err(Error::Msg(format!(
+                "rotary_pct {} leaves no dimensions to rotate in heads of size {}",
+                cfg.rotary_pct, head_dim
+            )));
+        }
+
+        let (theta, position_scale) = match cfg.rope_scaling {
+            None => (cfg.rope_theta as f64, 1.0),
+            // Squash positions back into the range seen during training
+            Some(RopeScaling::Linear { factor }) => (cfg.rope_theta as f64, 1.0 / factor as f64),
+            // Stretch the base so low frequencies interpolate and high frequencies extrapolate
+            Some(RopeScaling::Ntk { factor }) => (
+                cfg.rope_theta as f64
+                    * (factor as f64).powf(rotary_dim as f64 / (rotary_dim as f64 - 2.0)),
+                1.0,
+            ),
+        };
+
+        let half = rotary_dim / 2;
+        let inv_freq: Vec<f64> = (0..half)
+            .map(|i| 1.0 / theta.powf(2.0 * i as f64 / rotary_dim as f64))
+            .collect();
+        // Frequencies are repeated for both halves to line up with `rotate_half`
+        let angles: Vec<f64> = (0..cfg.context_size)
+            .flat_map(|pos| {
+                let pos = pos as f64 * position_scale;
+                let inv_freq = &inv_freq;
+                (0..rotary_dim).map(move |i| pos * inv_freq[i % half])
+            })
+            .collect();
+        let cos: Vec<f32> = angles.iter().map(|a| a.cos() as f32).collect();
+        let sin: Vec<f32> = angles.iter().map(|a| a.sin() as f32).collect();
+        Ok(Self {
+            cos: Tensor::from_vec(cos, (cfg.context_size, rotary_dim), device)?,
+            sin: Tensor::from_vec(sin, (cfg.context_size, rotary_dim), device)?,
+            rotary_dim,
+        })
+    }
+
+    /// Rotate (B, nh, T, hd) queries or keys by their (B or 1, T) u32 positions
+    pub fn apply(&self, xs: &Tensor, positions: &Tensor) -> Result<Tensor> {
+        let (_b, _nh, _t, hd) = xs.dims4()?;
+        let dtype = xs.dtype();
+        let (pb, pt) = positions.dims2()?;
+        // (B, 1, T, rotary_dim), shared across heads
+        let gather = |table: &Tensor| -> Result<Tensor> {
+            table
+                .index_select(&positions.flatten_all()?, 0)?
+                .reshape((pb, 1, pt, self.rotary_dim))?
+                .to_dtype(dtype)
+        };
+        let cos = gather(&self.cos)?;
+        let sin = gather(&self.sin)?;
+
+        let x_rot = xs.narrow(D::Minus1, 0, self.rotary_dim)?;
+        let rotated = (x_rot.broadcast_mul(&cos)? + rotate_half(&x_rot)?.broadcast_mul(&sin)?)?;
+        if self.rotary_dim == hd {
+            Ok(rotated)
+        } else {
+            let x_pass = xs.narrow(D::Minus1, self.rotary_dim, hd - self.rotary_dim)?;
+            Tensor::cat(&[rotated, x_pass], D::Minus1)
+        }
+    }
+}
+
+/// `[x1, x2] -> [-x2, x1]` along the last dimension
+fn rotate_half(xs: &Tensor) -> Result<Tensor> {
+    let half = xs.dim(D::Minus1)? / 2;
+    let x1 = xs.narrow(D::Minus1, 0, half)?;
+    let x2 = xs.narrow(D::Minus1, half, half)?;
+    Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device, IndexOp, Tensor};
+
+    fn positions(t: u32) -> Tensor {
+        Tensor::arange(0u32, t, &Device::Cpu)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap()
+    }
+
+    use super::RotaryEmbedding;
+    use crate::config::pretrained_config::{CacheEviction, PositionEmbeddingType, RopeScaling};
+    use crate::models::transformer::Config;
+
+    fn rope_config(rotary_pct: f32) -> Config {
+        Config {
+            vocab_size: 4,
+            context_size: 16,
+            hidden_dim: 16,
+            intermediate_dim: 64,
+            num_attention_heads: 2,
+            num_key_value_heads: 2,
+            hidden_layers: 1,
+            position_embedding: PositionEmbeddingType::Rotary,
+            rope_theta: 10000.0,
+            rotary_pct,
+            rope_scaling: None,
+            cache_eviction: CacheEviction::Recompute,
+        }
+    }
+
+    fn dot(a: &Tensor, b: &Tensor) -> f32 {
+        (a * b).unwrap().sum_all().unwrap().to_scalar().unwrap()
+    }
+
+    #[test]
+    fn test_relative_positions() {
+        let device = Device::Cpu;
+        let rope = RotaryEmbedding::new(&rope_config(1.0), &device).unwrap();
+
+        // The same q and k at every position: q_m . k_n must only depend on m - n
+        let q = Tensor::randn(0f32, 1f32, (1, 1, 1, 8), &device).unwrap();
+        let k = Tensor::randn(0f32, 1f32, (1, 1, 1, 8), &device).unwrap();
+        let q = rope
+            .apply(&q.repeat((1, 1, 16, 1)).unwrap(), &positions(16))
+            .unwrap();
+        let k = rope
+            .apply(&k.repeat((1, 1, 16, 1)).unwrap(), &positions(16))
+            .unwrap();
+        let at = |m: usize, n: usize| dot(&q.i((0, 0, m)).unwrap(), &k.i((0, 0, n)).unwrap());
+        assert!((at(3, 1) - at(12, 10)).abs() < 1e-4);
+        assert!((at(5, 5) - at(0, 0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_partial_rotation() {
+        let device = Device::Cpu;
+        let rope = RotaryEmbedding::new(&rope_config(0.5), &device).unwrap();
+        let xs = Tensor::randn(0f32, 1f32, (2, 2, 5, 8), &device).unwrap();
+        let out = rope.apply(&xs, &positions(5)).unwrap();
+        assert_eq!(out.dims(), [2, 2, 5, 8]);
+
+        // Second half of each head is untouched
+        let pass_in: Vec<f32> = xs
+            .narrow(3, 4, 4)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        let pass_out: Vec<f32> = out
+            .narrow(3, 4, 4)
+            .unwrap()
+            .flatten_all()
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        assert_eq!(pass_in, pass_out);
+    }
+
+    #[test]
+    fn test_linear_scaling() {
+        let device = Device::Cpu;
+        let plain = RotaryEmbedding::new(&rope_config(1.0), &device).unwrap();
+        let scaled = RotaryEmbedding::new(
+            &Config {
+                rope_scaling: Some(RopeScaling::Linear { factor: 2.0 }),
+                ..rope_config(1.0)
+            },
+            &device,
+        )
+        .unwrap();
+
+        // Position 2 under 2x linear scaling is position 1 unscaled
+        let xs = Tensor::ones((1, 1, 3, 8), DType::F32, &device).unwrap();
+        let plain: Vec<f32> = plain
+            .apply(&xs, &positions(3))
+            .unwrap()
+            .i((0, 0, 1))
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        let scaled: Vec<f32> = scaled
+            .apply(&xs, &positions(3))
+            .unwrap()
+            .i((0, 0, 2))
+            .unwrap()
+            .to_vec1()
+            .unwrap();
+        for (p, s) in plain.iter().zip(scaled.iter()) {
+            assert!((p - s).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use candle_core::{DType, Error, Result, Tensor};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::tokenizer::{Tokenizer, TokenizerError};
+
+/// How to pick the next token from the model's logits
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+#[command(about = None, long_about = None, next_help_heading = "Sampling")]
+pub struct SamplingConfig {
+    /// Softmax temperature. 0 means greedy
+    #[arg(long, default_value_t = 1.0, value_parser = parse_temperature, allow_hyphen_values = true)]
+    pub temperature: f32,
+
+    /// Only sample from the k most likely tokens
+    #[arg(long)]
+    pub top_k: Option<usize>,
+
+    /// Nucleus sampling: only sample from the smallest set of tokens with this much probability mass
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// Drop tokens less likely than this fraction of the most likely token
+    #[arg(long)]
+    pub min_p: Option<f32>,
+
+    /// Locally typical sampling mass
+    #[arg(long)]
+    pub typical_p: Option<f32>,
+
+    /// Always take the most likely token
+    #[arg(long)]
+    pub greedy: bool,
+
+    /// Seed for reproducible samples
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// CTRL-style penalty: divide positive logits (multiply negative ones) of tokens seen so far
+    #[arg(long)]
+    pub repetition_penalty: Option<f32>,
+
+    /// Subtract this times the number of times a token was generated
+    #[arg(long, allow_hyphen_values = true)]
+    pub frequency_penalty: Option<f32>,
+
+    /// Subtract this from every token generated at least once
+    #[arg(long, allow_hyphen_values = true)]
+    pub presence_penalty: Option<f32>,
+
+    /// Record each generated token's log-prob under the model, plus this many top alternatives
+    #[arg(long, value_name = "N")]
+    pub logprobs: Option<usize>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            typical_p: None,
+            greedy: false,
+            seed: None,
+            repetition_penalty: None,
+            frequency_penalty: None,
+            presence_penalty: None,
+            logprobs: None,
+        }
+    }
+}
+
+/// One step of the logits pipeline, applied to a single row before sampling.
+///
+/// Filtered tokens are set to `f32::NEG_INFINITY`.
+pub trait LogitsProcessor {
+    /// `prompt` and `generated` together are everything in the row so far
+    fn process(&self, prompt: &[u32], generated: &[u32], logits: &mut [f32]) -> Result<()>;
+}
+
+/// Lets one processor, e.g. a decoding constraint, be shared with other generation code
+impl<P: LogitsProcessor + ?Sized> LogitsProcessor for Rc<P> {
+    fn process(&self, prompt: &[u32], generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        (**self).process(prompt, generated, logits)
+    }
+}
+
+/// Repetition penalty from CTRL (Keskar et al. 2019), over prompt and generated tokens
+pub struct RepetitionPenalty(pub f32);
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&self, prompt: &[u32], generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        let mut seen = vec![false; logits.len()];
+        for &id in prompt.iter().chain(generated.iter()) {
+            if let Some(seen) = seen.get_mut(id as usize) {
+                *seen = true;
+            }
+        }
+        for (l, _) in logits.iter_mut().zip(seen).filter(|(_, seen)| *seen) {
+            *l = if *l > 0.0 { *l / self.0 } else { *l * self.0 };
+        }
+        Ok(())
+    }
+}
+
+/// OpenAI-style frequency and presence penalties over the generated tokens
+pub struct FrequencyPresencePenalty {
+    pub frequency: f32,
+    pub presence: f32,
+}
+
+impl LogitsProcessor for FrequencyPresencePenalty {
+    fn process(&self, _prompt: &[u32], generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for &id in generated {
+            *counts.entry(id).or_default() += 1;
+        }
+        for (id, count) in counts {
+            if let Some(l) = logits.get_mut(id as usize) {
+                *l -= count as f32 * self.frequency + self.presence;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Constant added to the logits of specific tokens
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitBias {
+    /// Resolve `(token, bias)` pairs through the tokenizer vocab
+    pub fn from_tokens(
+        bias: &[(String, f32)],
+        tokenizer: &Tokenizer,
+    ) -> std::result::Result<Self, TokenizerError> {
+        bias.iter()
+            .map(|(token, b)| {
+                tokenizer
+                    .token_to_id(token)
+                    .map(|id| (id, *b))
+                    .ok_or_else(|| {
+                        TokenizerError::InvalidInput(format!(
+                            "{:?} is not in the vocabulary",
+                            token
+                        ))
+                    })
+            })
+            .collect::<std::result::Result<HashMap<u32, f32>, TokenizerError>>()
+            .map(Self)
+    }
+}
+
+impl LogitsProcessor for LogitBias {
+    fn process(&self, _prompt: &[u32], _generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        for (&id, &b) in self.0.iter() {
+            if let Some(l) = logits.get_mut(id as usize) {
+                *l += b;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a `TOKEN=BIAS` command line argument
+pub fn parse_logit_bias(arg: &str) -> std::result::Result<(String, f32), String> {
+    let (token, bias) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected TOKEN=BIAS, got {:?}", arg))?;
+    let bias = bias.parse::<f32>().map_err(|e| e.to_string())?;
+    Ok((token.to_string(), bias))
+}
+
+/// Parse `--temperature`, which can't be negative
+pub fn parse_temperature(arg: &str) -> std::result::Result<f32, String> {
+    let temperature = arg.parse::<f32>().map_err(|e| e.to_string())?;
+    if temperature >= 0.0 {
+        Ok(temperature)
+    } else {
+        Err(format!("expected a temperature of at least 0, got {}", arg))
+    }
+}
+
+/// Divide logits by a positive temperature. 0 is greedy, which `Sampler` handles itself
+pub struct Temperature(pub f32);
+
+impl LogitsProcessor for Temperature {
+    fn process(&self, _prompt: &[u32], _generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        // A negative one would make the least likely tokens the most likely
+        if self.0.is_nan() || self.0 <= 0.0 {
+            return Err(Error::Msg(format!(
+                "Temperature must be positive, got {}",
+                self.0
+            )));
+        }
+        logits.iter_mut().for_each(|l| *l /= self.0);
+        Ok(())
+    }
+}
+
+pub struct TopK(pub usize);
+
+impl LogitsProcessor for TopK {
+    fn process(&self, _prompt: &[u32], _generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        if self.0 == 0 || self.0 >= logits.len() {
+            return Ok(());
+        }
+        let order = argsort_desc(logits);
+        order[self.0..]
+            .iter()
+            .for_each(|&i| logits[i] = f32::NEG_INFINITY);
+        Ok(())
+    }
+}
+
+pub struct TopP(pub f32);
+
+impl LogitsProcessor for TopP {
+    fn process(&self, _prompt: &[u32], _generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        let probs = softmax(logits);
+        let order = argsort_desc(&probs);
+        let mut cumulative = 0.0;
+        // Always keep the first token, even if it alone exceeds p
+        for &i in order.iter() {
+            if cumulative >= self.0 {
+                logits[i] = f32::NEG_INFINITY;
+            }
+            cumulative += probs[i];
+        }
+        Ok(())
+    }
+}
+
+pub struct MinP(pub f32);
+
+impl LogitsProcessor for MinP {
+    fn process(&self, _prompt: &[u32], _generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        let probs = softmax(logits);
+        let threshold = self.0 * probs.iter().cloned().fold(0.0, f32::max);
+        logits
+            .iter_mut()
+            .zip(probs.iter())
+            .filter(|(_, &p)| p < threshold)
+            .for_each(|(l, _)| *l = f32::NEG_INFINITY);
+        Ok(())
+    }
+}
+
+/// Locally typical sampling (Meister et al. 2022): keep tokens whose surprisal is closest to
+/// the distribution's entropy, up to `typical_p` probability mass
+pub struct Typical(pub f32);
+
+impl LogitsProcessor for Typical {
+    fn process(&self, _prompt: &[u32], _generated: &[u32], logits: &mut [f32]) -> Result<()> {
+        let probs = softmax(logits);
+        let entropy: f32 = probs
+            .iter()
+            .filter(|&&p| p > 0.0)
+            .map(|&p| -p * p.ln())
+            .sum();
+        // Negated distance from the entropy, so the most typical sort first
+        let distance: Vec<f32> = probs
+            .iter()
+            .map(|&p| {
+                if p > 0.0 {
+                    -(-p.ln() - entropy).abs()
+                } else {
+                    f32::NEG_INFINITY
+                }
+            })
+            .collect();
+        let mut cumulative = 0.0;
+        for i in argsort_desc(&distance) {
+            if cumulative >= self.0 {
+                logits[i] = f32::NEG_INFINITY;
+            }
+            cumulative += probs[i];
+        }
+        Ok(())
+    }
+}
+
+/// Runs the processor chain on each row of logits, then picks a token.
+///
+/// Processors (penalties, biases) see the raw logits; warpers (temperature, top-k, ...) run
+/// after them and shape the distribution that is actually sampled.
+pub struct Sampler {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+    warpers: Vec<Box<dyn LogitsProcessor>>,
+    greedy: bool,
+    logprobs: Option<usize>,
+    rng: StdRng,
+}
+
+impl Sampler {
+    pub fn new(cfg: &SamplingConfig) -> Self {
+        let greedy = cfg.greedy || cfg.temperature == 0.0;
+        let mut processors: Vec<Box<dyn LogitsProcessor>> = Vec::new();
+        if let Some(penalty) = cfg.repetition_penalty {
+            processors.push(Box::new(RepetitionPenalty(penalty)));
+        }
+        if cfg.frequency_penalty.is_some() || cfg.presence_penalty.is_some() {
+            processors.push(Box::new(FrequencyPresencePenalty {
+                frequency: cfg.frequency_penalty.unwrap_or(0.0),
+                presence: cfg.presence_penalty.unwrap_or(0.0),
+            }));
+        }
+        let mut warpers: Vec<Box<dyn LogitsProcessor>> = Vec::new();
+        if !greedy {
+            if cfg.temperature != 1.0 {
+                warpers.push(Box::new(Temperature(cfg.temperature)));
+            }
+            if let Some(k) = cfg.top_k {
+                warpers.push(Box::new(TopK(k)));
+            }
+            if let Some(p) = cfg.top_p {
+                warpers.push(Box::new(TopP(p)));
+            }
+            if let Some(p) = cfg.min_p {
+                warpers.push(Box::new(MinP(p)));
+            }
+            if let Some(p) = cfg.typical_p {
+                warpers.push(Box::new(Typical(p)));
+            }
+        }
+        Self {
+            processors,
+            warpers,
+            greedy,
+            logprobs: cfg.logprobs,
+            rng: match cfg.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+        }
+    }
+
+    /// Number of alternatives to record with each token's log-prob, if recording at all
+    pub fn logprobs(&self) -> Option<usize> {
+        self.logprobs
+    }
+
+    /// Append a processor, to run after the other processors but before any warper
+    pub fn push(&mut self, processor: Box<dyn LogitsProcessor>) {
+        self.processors.push(processor);
+    }
+
+    /// Pick one token per row of (B, vocab_size) `logits`, given the (B, T) tokens so far,
+    /// of which the first `prompt_len` are the prompt
+    pub fn sample(
+        &mut self,
+        logits: &Tensor,
+        input_ids: &Tensor,
+        prompt_len: usize,
+    ) -> Result<Vec<u32>> {
+        let input_ids: Vec<Vec<u32>> = input_ids.to_vec2()?;
+        let history: Vec<(&[u32], &[u32])> = input_ids
+            .iter()
+            .map(|ids| ids.split_at(prompt_len))
+            .collect();
+        self.sample_rows(logits, &history)
+    }
+
+    /// `sample` with each row's `(prompt, generated)` history given separately, e.g. so
+    /// padding isn't counted as part of the prompt
+    pub fn sample_rows(
+        &mut self,
+        logits: &Tensor,
+        history: &[(&[u32], &[u32])],
+    ) -> Result<Vec<u32>> {
+        let logits: Vec<Vec<f32>> = logits.to_dtype(DType::F32)?.to_vec2()?;
+        logits
+            .into_iter()
+            .zip(history.iter())
+            .map(|(row, (prompt, generated))| {
+                let probs = self.probs(row, prompt, generated)?;
+                self.sample_probs(&probs)
+            })
+            .collect()
+    }
+
+    /// The distribution `sample` draws from for one row of logits: one-hot when greedy
+    pub fn probs(
+        &self,
+        mut logits: Vec<f32>,
+        prompt: &[u32],
+        generated: &[u32],
+    ) -> Result<Vec<f32>> {
+        for processor in self.processors.iter().chain(self.warpers.iter()) {
+            processor.process(prompt, generated, &mut logits)?;
+        }
+        if self.greedy {
+            let mut probs = vec![0.0; logits.len()];
+            probs[argsort_desc(&logits)[0]] = 1.0;
+            Ok(probs)
+        } else {
+            Ok(softmax(&logits))
+        }
+    }
+
+    /// Draw a token from `probs`, which need not be normalized
+    pub fn sample_probs(&mut self, probs: &[f32]) -> Result<u32> {
+        // Forced to do this because Candle doesn't have `torch.multinomial` built in.
+        let distr = WeightedIndex::new(probs).map_err(Error::wrap)?;
+        Ok(distr.sample(&mut self.rng) as u32)
+    }
+
+    /// Uniform draw from [0, 1), from the same seeded stream as the samples
+    pub fn uniform(&mut self) -> f32 {
+        self.rng.gen()
+    }
+}
+
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|&l| (l - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// Indices from largest to smallest value
+pub(crate) fn argsort_desc(values: &[f32]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, Tensor};
+
+    use super::*;
+
+    fn kept(logits: &[f32]) -> Vec<bool> {
+        logits.iter().map(|l| l.is_finite()).collect()
+    }
+
+    #[test]
+    fn test_filters() {
+        // Probabilities roughly [0.64, 0.24, 0.09, 0.03]
+        let logits = [3.0f32, 2.0, 1.0, 0.0];
+
+        let mut top_k = logits;
+        TopK(2).process(&[], &[], &mut top_k).unwrap();
+        assert_eq!(kept(&top_k), [true, true, false, false]);
+
+        let mut top_p = logits;
+        TopP(0.8).process(&[], &[], &mut top_p).unwrap();
+        assert_eq!(kept(&top_p), [true, true, false, false]);
+
+        let mut min_p = logits;
+        MinP(0.1).process(&[], &[], &mut min_p).unwrap();
+        assert_eq!(kept(&min_p), [true, true, true, false]);
+
+        // Top token alone is never filtered out
+        let mut tiny_p = logits;
+        TopP(0.01).process(&[], &[], &mut tiny_p).unwrap();
+        assert_eq!(kept(&tiny_p), [true, false, false, false]);
+
+        // Entropy is ~0.95 nats: token 1 (surprisal ~1.44) is the most typical, then token 0
+        let mut typical = logits;
+        Typical(0.5).process(&[], &[], &mut typical).unwrap();
+        assert_eq!(kept(&typical), [true, true, false, false]);
+    }
+
+    #[test]
+    fn test_greedy() {
+        let device = Device::Cpu;
+        let logits = Tensor::new(&[[0.1f32, 5.0, 0.2], [3.0, 0.0, 0.1]], &device).unwrap();
+        let input_ids = Tensor::new(&[[0u32], [0]], &device).unwrap();
+
+        let mut greedy = Sampler::new(&SamplingConfig {
+            greedy: true,
+            ..Default::default()
+        });
+        assert_eq!(greedy.sample(&logits, &input_ids, 1).unwrap(), [1, 0]);
+
+        // Top-1 sampling can only pick the argmax too
+        let mut top_1 = Sampler::new(&SamplingConfig {
+            top_k: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(top_1.sample(&logits, &input_ids, 1).unwrap(), [1, 0]);
+
+        // Only 0 means greedy; below that is an error, not greedy or inverted sampling
+        let mut negative = Sampler::new(&SamplingConfig {
+            temperature: -1.0,
+            ..Default::default()
+        });
+        assert!(negative.sample(&logits, &input_ids, 1).is_err());
+        assert!(parse_temperature("0").is_ok() && parse_temperature("-0.5").is_err());
+    }
+
+    #[test]
+    fn test_penalties() {
+        let prompt = [0u32];
+        let generated = [1u32, 1, 2];
+
+        let mut repetition = [2.0f32, -2.0, 2.0, 2.0];
+        RepetitionPenalty(2.0)
+            .process(&prompt, &generated, &mut repetition)
+            .unwrap();
+        assert_eq!(repetition, [1.0, -4.0, 1.0, 2.0]);
+
+        // The prompt doesn't count towards frequency or presence
+        let mut penalized = [0.0f32; 4];
+        FrequencyPresencePenalty {
+            frequency: 0.5,
+            presence: 1.0,
+        }
+        .process(&prompt, &generated, &mut penalized)
+        .unwrap();
+        assert_eq!(penalized, [0.0, -2.0, -1.5, 0.0]);
+
+        let mut biased = [0.0f32; 4];
+        LogitBias([(3, 5.0)].into_iter().collect())
+            .process(&prompt, &generated, &mut biased)
+            .unwrap();
+        assert_eq!(biased, [0.0, 0.0, 0.0, 5.0]);
+
+        assert_eq!(parse_logit_bias("==-1.5").unwrap(), ("=".to_string(), -1.5));
+        assert!(parse_logit_bias("e").is_err());
+    }
+}
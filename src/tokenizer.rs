@@ -1,16 +1,35 @@
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use thiserror::Error;
 
+use self::added_vocabulary::{AddedToken, AddedVocabulary, SpecialTokens};
+use self::decoders::{Decoder, DecoderWrapper, Sequence as DecoderSequence};
 use self::models::{Model, ModelWrapper};
-use self::trainer::{Trainer, TrainerError};
+use self::normalizers::{Normalizer, NormalizerWrapper};
+use self::pre_tokenizers::{PreTokenizer, PreTokenizerWrapper};
+use self::trainer::{Trainer, TrainerError, TrainerWrapper};
 
+pub mod added_vocabulary;
+pub mod decoders;
+pub mod hf;
 pub mod models;
+pub mod normalizers;
+pub mod pre_tokenizers;
+pub mod tiktoken;
 pub mod trainer;
 
+/// Conventional beginning-of-sequence spellings, in order of preference
+const BOS_TOKENS: [&str; 3] = ["<|startoftext|>", "<s>", "<bos>"];
+/// Conventional end-of-sequence spellings, in order of preference
+const EOS_TOKENS: [&str; 3] = ["<|endoftext|>", "</s>", "<eos>"];
+/// Conventional padding spellings, in order of preference
+const PAD_TOKENS: [&str; 3] = ["<pad>", "[PAD]", "<|pad|>"];
+/// Conventional unknown-token spellings, in order of preference
+const UNK_TOKENS: [&str; 3] = ["<unk>", "[UNK]", "<|unk|>"];
+
 #[derive(Error, Debug)]
 pub enum TokenizerError {
     #[error("Invalid input: {0}")]
@@ -59,41 +78,304 @@ impl Token {
     }
 }
 
+/// A model plus the added tokens matched before it, a normalizer, a pre-tokenizer and a
+/// decoder.
+///
+/// Added tokens keep their model id if the model has them (e.g. reserved at training),
+/// and otherwise get ids after the model's vocab. They are matched on the raw text, and the
+/// normalizer only sees the text between them. The pre-tokenizer then splits normalized text
+/// into pieces the model tokenizes separately.
 pub struct Tokenizer {
     model_wrapper: ModelWrapper,
+    normalizer: Option<NormalizerWrapper>,
+    pre_tokenizer: Option<PreTokenizerWrapper>,
+    decoder: Option<DecoderWrapper>,
+    added_vocabulary: AddedVocabulary,
+    special_tokens: SpecialTokens,
 }
 
 impl Tokenizer {
     pub fn new(model_wrapper: ModelWrapper) -> Self {
-        Self { model_wrapper }
+        Self {
+            decoder: model_wrapper.default_decoder(),
+            model_wrapper,
+            normalizer: None,
+            pre_tokenizer: None,
+            added_vocabulary: AddedVocabulary::default(),
+            special_tokens: SpecialTokens::default(),
+        }
+    }
+    /// Normalize text before tokenizing, both when encoding and training
+    pub fn with_normalizer(mut self, normalizer: Option<NormalizerWrapper>) -> Self {
+        self.normalizer = normalizer;
+        self
+    }
+    pub fn normalizer(&self) -> Option<&NormalizerWrapper> {
+        self.normalizer.as_ref()
+    }
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        match &self.normalizer {
+            Some(normalizer) => normalizer.normalize(text),
+            None => Ok(text.to_string()),
+        }
+    }
+    /// Split normalized text into pieces before tokenizing, both when encoding and training.
+    /// Also sets a decoder to undo it, unless the model already has one
+    pub fn with_pre_tokenizer(
+        mut self,
+        pre_tokenizer: Option<PreTokenizerWrapper>,
+    ) -> Result<Self, TokenizerError> {
+        if let Some(pre_tokenizer) = &pre_tokenizer {
+            // Its bytes would be spelled with byte chars twice, which nothing decodes
+            if pre_tokenizer.is_byte_level()
+                && matches!(self.model_wrapper, ModelWrapper::ByteLevelBpe(_))
+            {
+                return Err(TokenizerError::InvalidInput(
+                    "Byte-level BPE already does byte-level pre-tokenization".into(),
+                ));
+            }
+            // Undo the pre-tokenizer's markers before the model's decoder joins the tokens
+            if let Some(pre_decoder) = pre_tokenizer.default_decoder() {
+                self.decoder = match self.decoder.take() {
+                    Some(decoder) if decoder != pre_decoder => {
+                        Some(DecoderWrapper::Sequence(DecoderSequence {
+                            decoders: vec![pre_decoder, decoder],
+                        }))
+                    }
+                    _ => Some(pre_decoder),
+                };
+            }
+        }
+        self.pre_tokenizer = pre_tokenizer;
+        Ok(self)
+    }
+    pub fn pre_tokenizer(&self) -> Option<&PreTokenizerWrapper> {
+        self.pre_tokenizer.as_ref()
+    }
+    /// Normalize and pre-tokenize text between added tokens, into `(offset, piece)`
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<(usize, String)>, TokenizerError> {
+        let normalized = self.normalize(text)?;
+        match &self.pre_tokenizer {
+            Some(pre_tokenizer) => pre_tokenizer.pre_tokenize(&normalized),
+            None => Ok(vec![(0, normalized)]),
+        }
+    }
+    /// Replace the decoder picked for the model
+    pub fn with_decoder(mut self, decoder: Option<DecoderWrapper>) -> Self {
+        self.decoder = decoder;
+        self
+    }
+    /// Add a token matched before the model, returning its id
+    pub fn add_token(&mut self, content: &str, special: bool) -> u32 {
+        if let Some(token) = self.added_vocabulary.get(content) {
+            return token.id;
+        }
+        let id = self
+            .model_wrapper
+            .token_to_id(content)
+            .unwrap_or(self.get_vocab_size() as u32);
+        self.added_vocabulary.insert(AddedToken {
+            id,
+            content: content.to_string(),
+            special,
+        });
+        id
+    }
+    /// Add tokens with an explicit id, e.g. from a file. An id the model already uses must
+    /// be for the same content
+    pub fn with_added_tokens(mut self, tokens: Vec<AddedToken>) -> Result<Self, TokenizerError> {
+        for token in &tokens {
+            match self.model_wrapper.id_to_token(token.id) {
+                Some(existing) if existing != token.content => {
+                    return Err(TokenizerError::InvalidInput(format!(
+                        "Added token {:?} has id {}, which the model uses for {:?}",
+                        token.content, token.id, existing
+                    )))
+                }
+                _ => {}
+            }
+        }
+        tokens
+            .into_iter()
+            .for_each(|t| self.added_vocabulary.insert(t));
+        Ok(self)
+    }
+    /// Set which tokens are BOS, EOS, PAD and UNK, adding them as special tokens
+    pub fn with_special_tokens(mut self, special_tokens: SpecialTokens) -> Self {
+        for token in special_tokens.iter() {
+            self.add_token(token, true);
+        }
+        self.special_tokens = special_tokens;
+        self
+    }
+    pub fn added_tokens(&self) -> &[AddedToken] {
+        self.added_vocabulary.tokens()
+    }
+    pub fn special_tokens(&self) -> &SpecialTokens {
+        &self.special_tokens
+    }
+    /// Whether `id` is a control token rather than text
+    pub fn is_special(&self, id: u32) -> bool {
+        self.added_vocabulary
+            .get_by_id(id)
+            .is_some_and(|t| t.special)
     }
     pub fn encode(&self, input: &str) -> Result<Encoding, TokenizerError> {
-        Ok(self.model_wrapper.tokenize(input)?.into())
+        let mut tokens = Vec::new();
+        for (offset, piece, added) in self.added_vocabulary.split(input) {
+            match added {
+                Some(added) => tokens.push(Token::new(
+                    added.id,
+                    added.content.clone(),
+                    (offset, offset + piece.len()),
+                )),
+                // Offsets within the piece are into its normalized form
+                None => {
+                    for (o, p) in self.pre_tokenize(piece)? {
+                        self.tokenize_piece(&p, offset + o, &mut tokens)?;
+                    }
+                }
+            }
+        }
+        Ok(tokens.into())
+    }
+    /// Tokenize text between added tokens. With an UNK token, characters the model can't
+    /// handle become UNK and the runs between them are tokenized on their own
+    fn tokenize_piece(
+        &self,
+        piece: &str,
+        offset: usize,
+        tokens: &mut Vec<Token>,
+    ) -> Result<(), TokenizerError> {
+        let shifted = |tokens: Vec<Token>, offset: usize| {
+            tokens.into_iter().map(move |t| Token {
+                offsets: (t.offsets.0 + offset, t.offsets.1 + offset),
+                ..t
+            })
+        };
+        let unk = match (self.model_wrapper.tokenize(piece), self.unk_token_id()) {
+            (Ok(model_tokens), _) => {
+                tokens.extend(shifted(model_tokens, offset));
+                return Ok(());
+            }
+            (Err(TokenizerError::UnsupportedCharacter(_)), Some(unk)) => unk,
+            (Err(e), _) => return Err(e),
+        };
+        let unk_value = self.id_to_token(unk).unwrap_or_default();
+        let mut start = 0;
+        for (i, c) in piece.char_indices() {
+            if self
+                .model_wrapper
+                .tokenize(c.encode_utf8(&mut [0; 4]))
+                .is_ok()
+            {
+                continue;
+            }
+            if start < i {
+                let run = self.model_wrapper.tokenize(&piece[start..i])?;
+                tokens.extend(shifted(run, offset + start));
+            }
+            let end = i + c.len_utf8();
+            tokens.push(Token::new(
+                unk,
+                unk_value.clone(),
+                (offset + i, offset + end),
+            ));
+            start = end;
+        }
+        if start < piece.len() {
+            let run = self.model_wrapper.tokenize(&piece[start..])?;
+            tokens.extend(shifted(run, offset + start));
+        }
+        Ok(())
     }
     pub fn decode(&self, ids: &[u32]) -> Result<String, TokenizerError> {
-        let res: Result<String, TokenizerError> = ids
+        let bytes = self.decode_bytes(ids)?;
+        Ok(match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        })
+    }
+    /// Like `decode`, but without replacing partial characters by U+FFFD.
+    ///
+    /// Added tokens go through the decoder along with model tokens, so it can join them the
+    /// same way (e.g. WordPiece's spaces).
+    pub fn decode_bytes(&self, ids: &[u32]) -> Result<Vec<u8>, TokenizerError> {
+        let tokens = ids
             .iter()
-            .map(|id| {
-                self.model_wrapper
-                    .id_to_token(*id)
-                    .ok_or(TokenizerError::UnsupportedCharacter(
-                        "Unknown not handled yet!".into(),
-                    ))
+            .map(|&id| match self.added_vocabulary.get_by_id(id) {
+                Some(added) => Ok(added.content.clone()),
+                None => self.model_wrapper.id_to_token(id).ok_or_else(|| {
+                    TokenizerError::InvalidInput(format!("Id {} is not in the vocab", id))
+                }),
             })
-            .collect();
-        res
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(match &self.decoder {
+            Some(decoder) => decoder.decode_bytes(&tokens),
+            None => tokens.concat().into_bytes(),
+        })
     }
+    /// The model's vocab plus added tokens
     pub fn get_vocab(&self) -> HashMap<String, u32> {
-        self.model_wrapper.get_vocab()
+        let mut vocab = self.model_wrapper.get_vocab();
+        for token in self.added_vocabulary.tokens() {
+            vocab.insert(token.content.clone(), token.id);
+        }
+        vocab
     }
     pub fn get_vocab_size(&self) -> usize {
-        self.model_wrapper.get_vocab_size()
+        let model_size = self.model_wrapper.get_vocab_size();
+        let added = self
+            .added_vocabulary
+            .tokens()
+            .iter()
+            .filter(|t| self.model_wrapper.id_to_token(t.id).is_none())
+            .count();
+        model_size + added
     }
     pub fn token_to_id(&self, token: &str) -> Option<u32> {
-        self.model_wrapper.token_to_id(token)
+        match self.added_vocabulary.get(token) {
+            Some(added) => Some(added.id),
+            None => self.model_wrapper.token_to_id(token),
+        }
     }
     pub fn id_to_token(&self, id: u32) -> Option<String> {
-        self.model_wrapper.id_to_token(id)
+        match self.added_vocabulary.get_by_id(id) {
+            Some(added) => Some(added.content.clone()),
+            None => self.model_wrapper.id_to_token(id),
+        }
+    }
+    /// Decoder for ids that arrive one at a time, e.g. while generating
+    pub fn decode_stream(&self) -> DecodeStream<'_> {
+        DecodeStream {
+            tokenizer: self,
+            ids: Vec::new(),
+            prefix_offset: 0,
+            read_offset: 0,
+        }
+    }
+    /// Id of the token for a role, falling back to conventional spellings if none was set
+    fn role_token_id(&self, role: &Option<String>, conventional: &[&str]) -> Option<u32> {
+        match role {
+            Some(token) => self.token_to_id(token),
+            None => conventional.iter().find_map(|t| self.token_to_id(t)),
+        }
+    }
+    /// Id of the beginning-of-sequence token, if the vocab has one
+    pub fn bos_token_id(&self) -> Option<u32> {
+        self.role_token_id(&self.special_tokens.bos, &BOS_TOKENS)
+    }
+    /// Id of the end-of-sequence token, if the vocab has one
+    pub fn eos_token_id(&self) -> Option<u32> {
+        self.role_token_id(&self.special_tokens.eos, &EOS_TOKENS)
+    }
+    /// Id of the padding token, if the vocab has one
+    pub fn pad_token_id(&self) -> Option<u32> {
+        self.role_token_id(&self.special_tokens.pad, &PAD_TOKENS)
+    }
+    /// Id of the token standing in for untokenizable text, if the vocab has one
+    pub fn unk_token_id(&self) -> Option<u32> {
+        self.role_token_id(&self.special_tokens.unk, &UNK_TOKENS)
     }
     pub fn train<I, S>(&mut self, sequences: I) -> Result<&mut Self, TrainerError>
     where
@@ -101,47 +383,335 @@ impl Tokenizer {
         S: AsRef<str>,
     {
         let mut trainer = self.model_wrapper.get_trainer();
-        // Ignore processing for now
-        trainer.feed(sequences, |p| Ok(vec![p.to_string()]))?;
+        let reserved = self.reserve_added_tokens(&mut trainer);
+        trainer.feed(sequences, |p| Ok(self.pieces(p)?))?;
         trainer.train(&mut self.model_wrapper)?;
+        self.register_added_tokens(reserved);
         Ok(self)
     }
+    /// What the trainer sees of a sequence: the same pieces encoding hands the model
+    fn pieces(&self, text: &str) -> Result<Vec<String>, TokenizerError> {
+        Ok(self
+            .pre_tokenize(text)?
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect())
+    }
+    /// Have the trainer keep our added tokens in the vocab. Returns everything it reserves
+    fn reserve_added_tokens(&self, trainer: &mut TrainerWrapper) -> Vec<String> {
+        let reserved = trainer.special_tokens_mut();
+        for token in self.added_vocabulary.tokens() {
+            if !reserved.contains(&token.content) {
+                reserved.push(token.content.clone());
+            }
+        }
+        reserved.clone()
+    }
+    /// After training, point added tokens at their new ids and add the trainer's reserved
+    /// tokens as special tokens
+    fn register_added_tokens(&mut self, reserved: Vec<String>) {
+        let previous = std::mem::take(&mut self.added_vocabulary);
+        for content in reserved {
+            let special = previous.get(&content).is_none_or(|t| t.special);
+            self.add_token(&content, special);
+        }
+    }
     pub fn train_from_files(&mut self, files: Vec<PathBuf>) -> Result<&mut Self, TrainerError> {
+        let trainer = self.model_wrapper.get_trainer();
+        self.train_from_files_with(trainer, files)
+    }
+    /// Train on every file with a configured trainer, which must match the model
+    pub fn train_from_files_with(
+        &mut self,
+        mut trainer: TrainerWrapper,
+        files: Vec<PathBuf>,
+    ) -> Result<&mut Self, TrainerError> {
+        let reserved = self.reserve_added_tokens(&mut trainer);
+        let processor =
+            |p: &str| -> Result<Vec<String>, Box<dyn std::error::Error>> { Ok(self.pieces(p)?) };
         // Ingest files
         for path in files {
             let file = File::open(&path).map_err(TrainerError::IoError)?;
             let reader = BufReader::with_capacity(1_000_000, file);
-            let line_iter = reader.lines().filter_map(|line_result| {
-                match line_result {
-                    Ok(line) => Some(line), // Convert line into T here, if necessary
-                    Err(_) => None,         // Skip or handle errors differently
+            // Stop at the first unreadable line, and report it once the trainer is done
+            let mut read_error = None;
+            let line_iter = reader.lines().map_while(|line_result| match line_result {
+                Ok(line) => Some(line),
+                Err(e) => {
+                    read_error = Some(e);
+                    None
                 }
             });
-            let mut trainer = self.model_wrapper.get_trainer();
-            let fake_processor = |p: &str| Ok(vec![p.to_string()]);
-            trainer.feed(line_iter, fake_processor)?;
-            // Kludgy hack to get over newlines
-            trainer.feed(vec!["\n"].iter(), fake_processor)?;
-            trainer.train(&mut self.model_wrapper)?;
+            trainer.feed(line_iter, processor)?;
+            if let Some(e) = read_error {
+                return Err(TrainerError::IoError(e));
+            }
         }
+        // Kludgy hack to get over newlines
+        trainer.feed(["\n"].iter(), processor)?;
+        trainer.train(&mut self.model_wrapper)?;
+        self.register_added_tokens(reserved);
         Ok(self)
     }
-    /// Just persist model for now
-    pub fn save(
+    /// Persist the model, plus any added tokens, special token roles, normalizer and
+    /// pre-tokenizer
+    pub fn save(&self, folder: &Path, name: Option<&str>) -> Result<Vec<PathBuf>, std::io::Error> {
+        let fname = match name {
+            Some(n) => format!("{}.json", n),
+            None => "vocab.json".to_string(),
+        };
+        let mut json = serde_json::to_value(&self.model_wrapper)?;
+        if !self.added_vocabulary.tokens().is_empty() {
+            json["added_tokens"] = serde_json::to_value(self.added_vocabulary.tokens())?;
+        }
+        if !self.special_tokens.is_empty() {
+            json["special_tokens"] = serde_json::to_value(&self.special_tokens)?;
+        }
+        if let Some(normalizer) = &self.normalizer {
+            json["normalizer"] = serde_json::to_value(normalizer)?;
+        }
+        if let Some(pre_tokenizer) = &self.pre_tokenizer {
+            json["pre_tokenizer"] = serde_json::to_value(pre_tokenizer)?;
+        }
+        let path = folder.join(fname);
+        fs::write(&path, json.to_string())?;
+        Ok(vec![path])
+    }
+    /// Persist in the Hugging Face `tokenizer.json` format
+    pub fn save_hf(
         &self,
-        folder: &PathBuf,
+        folder: &Path,
         name: Option<&str>,
-    ) -> Result<Vec<PathBuf>, std::io::Error> {
-        self.model_wrapper.save(folder, name)
+    ) -> Result<Vec<PathBuf>, TokenizerError> {
+        let fname = match name {
+            Some(n) => format!("{}.json", n),
+            None => "tokenizer.json".to_string(),
+        };
+        let path = folder.join(fname);
+        fs::write(&path, hf::to_json(self)?)?;
+        Ok(vec![path])
     }
+    /// Load either our own format or a Hugging Face `tokenizer.json`
     pub fn from_file(path: &PathBuf) -> Result<Self, TokenizerError> {
         let bytes = fs::read(path).map_err(TokenizerError::IoError)?;
         let json_string = String::from_utf8(bytes)
             .map_err(|_| TokenizerError::InvalidInput("Cannot read file".into()))?;
-        let model = serde_json::from_str::<ModelWrapper>(&json_string)
+        let mut json: serde_json::Value = serde_json::from_str(&json_string)
             .map_err(|_| TokenizerError::InvalidInput("File cannot be parsed".into()))?;
-        Ok(Self {
-            model_wrapper: model,
-        })
+        if json.get("model").is_some() {
+            return hf::from_json(&json_string);
+        }
+        let cannot_parse = |_| TokenizerError::InvalidInput("File cannot be parsed".into());
+        let mut take = |key: &str| json.as_object_mut().and_then(|map| map.remove(key));
+        let added_tokens: Vec<AddedToken> = match take("added_tokens") {
+            Some(value) => serde_json::from_value(value).map_err(cannot_parse)?,
+            None => Vec::new(),
+        };
+        let special_tokens: SpecialTokens = match take("special_tokens") {
+            Some(value) => serde_json::from_value(value).map_err(cannot_parse)?,
+            None => SpecialTokens::default(),
+        };
+        let normalizer: Option<NormalizerWrapper> = match take("normalizer") {
+            Some(value) => Some(serde_json::from_value(value).map_err(cannot_parse)?),
+            None => None,
+        };
+        let pre_tokenizer: Option<PreTokenizerWrapper> = match take("pre_tokenizer") {
+            Some(value) => Some(serde_json::from_value(value).map_err(cannot_parse)?),
+            None => None,
+        };
+        let model = serde_json::from_value::<ModelWrapper>(json).map_err(cannot_parse)?;
+        Self::new(model)
+            .with_added_tokens(added_tokens)?
+            .with_special_tokens(special_tokens)
+            .with_normalizer(normalizer)
+            .with_pre_tokenizer(pre_tokenizer)
+    }
+    /// Load a `tiktoken` rank file, which doesn't record its pre-tokenization `pattern`
+    pub fn from_tiktoken(path: &Path, pattern: &str) -> Result<Self, TokenizerError> {
+        tiktoken::from_file(path, pattern)
+    }
+}
+
+/// Incremental detokenizer.
+///
+/// Text is only emitted once it no longer ends in a partial UTF-8 character, since tokens
+/// that split a character decode to U+FFFD on their own.
+pub struct DecodeStream<'a> {
+    tokenizer: &'a Tokenizer,
+    ids: Vec<u32>,
+    /// Start of the ids re-decoded each step, for context at token boundaries
+    prefix_offset: usize,
+    /// End of the ids whose text has already been emitted
+    read_offset: usize,
+}
+
+impl DecodeStream<'_> {
+    /// Add one id, returning any text that is now complete
+    pub fn step(&mut self, id: u32) -> Result<Option<String>, TokenizerError> {
+        let prefix = self
+            .tokenizer
+            .decode(&self.ids[self.prefix_offset..self.read_offset])?;
+        // Only kept once it decodes, so a bad id doesn't break every later step
+        let text = self
+            .tokenizer
+            .decode(&[&self.ids[self.prefix_offset..], &[id]].concat())?;
+        self.ids.push(id);
+        if text.len() > prefix.len() && !text.ends_with(char::REPLACEMENT_CHARACTER) {
+            let new_text = text[prefix.len()..].to_string();
+            self.prefix_offset = self.read_offset;
+            self.read_offset = self.ids.len();
+            Ok(Some(new_text))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use std::env::temp_dir;
+
+    use super::added_vocabulary::SpecialTokens;
+    use super::models::bpe::Bpe;
+    use super::models::byte_level::ByteLevelBpe;
+    use super::models::character::Character;
+    use super::models::wordpiece::WordPiece;
+    use super::normalizers::{Lowercase, Nfc, NormalizerWrapper, Sequence};
+    use super::pre_tokenizers::{ByteLevel, Metaspace, PreTokenizerWrapper};
+    use super::Tokenizer;
+
+    #[test]
+    fn test_decode_stream() {
+        let vocab: HashMap<String, u32> = ["h", "é", "\u{FFFD}"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.to_string(), i as u32))
+            .collect();
+        let tokenizer = Tokenizer::new(Character::new(vocab).into());
+        let mut stream = tokenizer.decode_stream();
+        assert_eq!(stream.step(0).unwrap(), Some("h".into()));
+        assert_eq!(stream.step(1).unwrap(), Some("é".into()));
+        // Looks like half a character, so held back until something completes it
+        assert_eq!(stream.step(2).unwrap(), None);
+        assert_eq!(stream.step(0).unwrap(), Some("\u{FFFD}h".into()));
+        assert!(stream.step(7).is_err());
+        assert_eq!(stream.step(1).unwrap(), Some("é".into()));
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        let vocab: HashMap<String, u32> = [("a".into(), 0), ("b".into(), 1)].into();
+        let special_tokens = SpecialTokens {
+            eos: Some("</s>".into()),
+            unk: Some("<unk>".into()),
+            ..Default::default()
+        };
+        let mut tokenizer =
+            Tokenizer::new(Character::new(vocab).into()).with_special_tokens(special_tokens);
+        assert_eq!(
+            (tokenizer.eos_token_id(), tokenizer.unk_token_id()),
+            (Some(2), Some(3))
+        );
+        assert_eq!(tokenizer.get_vocab_size(), 4);
+
+        // Matched before the model, with unknown characters falling back to <unk>
+        let encoding = tokenizer.encode("ab</s>cb").unwrap();
+        assert_eq!(encoding.ids, [0, 1, 2, 3, 1]);
+        assert_eq!(encoding.offsets[2], (2, 6));
+        assert_eq!(tokenizer.decode(&encoding.ids).unwrap(), "ab</s><unk>b");
+        assert!(tokenizer.is_special(2) && !tokenizer.is_special(1));
+
+        // Reserved at the start of the vocab when training
+        tokenizer.train(["cab"].iter()).unwrap();
+        assert_eq!(tokenizer.get_vocab_size(), 5);
+        assert_eq!(tokenizer.encode("c</s>").unwrap().ids, [4, 0]);
+
+        let paths = tokenizer.save(&temp_dir(), Some("special-test")).unwrap();
+        let loaded = Tokenizer::from_file(&paths[0]).unwrap();
+        assert_eq!(loaded.special_tokens(), tokenizer.special_tokens());
+        assert_eq!(loaded.encode("c</s>é").unwrap().ids, [4, 0, 1]);
+    }
+
+    #[test]
+    fn test_decode_added_tokens() {
+        let mut tokenizer = Tokenizer::new(WordPiece::new(HashMap::new()).into());
+        tokenizer.train(["hug bug hug"].iter()).unwrap();
+        // The unknown word keeps the spaces around it
+        let ids = tokenizer.encode("hug zzz bug").unwrap().ids;
+        assert_eq!(ids[1], tokenizer.unk_token_id().unwrap());
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "hug [UNK] bug");
+    }
+
+    #[test]
+    fn test_train_from_files() {
+        let path = temp_dir().join("invalid-utf8-test.txt");
+        std::fs::write(&path, b"ab\n\xff\ncd\n").unwrap();
+        let mut tokenizer = Tokenizer::new(Character::new(HashMap::new()).into());
+        // Not silently trained on just the lines before it
+        assert!(tokenizer.train_from_files(vec![path]).is_err());
+    }
+
+    #[test]
+    fn test_normalizer() {
+        let normalizer = NormalizerWrapper::Sequence(Sequence {
+            normalizers: vec![
+                NormalizerWrapper::Nfc(Nfc),
+                NormalizerWrapper::Lowercase(Lowercase),
+            ],
+        });
+        let mut tokenizer =
+            Tokenizer::new(Character::new(HashMap::new()).into()).with_normalizer(Some(normalizer));
+        // Composed and decomposed accents are one character
+        tokenizer.train(["É", "e\u{301}"].iter()).unwrap();
+        assert_eq!(tokenizer.get_vocab_size(), 1);
+        assert_eq!(tokenizer.encode("E\u{301}é").unwrap().ids, [0, 0]);
+
+        let paths = tokenizer
+            .save(&temp_dir(), Some("normalizer-test"))
+            .unwrap();
+        let loaded = Tokenizer::from_file(&paths[0]).unwrap();
+        assert_eq!(loaded.encode("É").unwrap().ids, [0]);
+    }
+
+    #[test]
+    fn test_pre_tokenizer() {
+        let pre_tokenizer = PreTokenizerWrapper::Metaspace(Metaspace::default());
+        let mut tokenizer = Tokenizer::new(Bpe::new(HashMap::new(), Vec::new()).unwrap().into())
+            .with_pre_tokenizer(Some(pre_tokenizer))
+            .unwrap();
+        tokenizer.train(["hi hi hi"].iter()).unwrap();
+        // Trained on the same pieces it encodes, so no merge spans a space
+        assert_eq!(tokenizer.get_vocab_size(), 5);
+        let encoding = tokenizer.encode("hi hi").unwrap();
+        let hi = tokenizer.token_to_id("▁hi").unwrap();
+        assert_eq!(encoding.ids, [hi, hi]);
+        assert_eq!(encoding.offsets[1].0, 2);
+        assert_eq!(tokenizer.decode(&encoding.ids).unwrap(), "hi hi");
+
+        let paths = tokenizer
+            .save(&temp_dir(), Some("pre-tokenizer-test"))
+            .unwrap();
+        let loaded = Tokenizer::from_file(&paths[0]).unwrap();
+        assert_eq!(loaded.encode("hi hi").unwrap().ids, encoding.ids);
+        assert_eq!(loaded.decode(&encoding.ids).unwrap(), "hi hi");
+
+        // Byte-level BPE would spell bytes twice
+        let byte_level = ByteLevelBpe::new(Bpe::new(HashMap::new(), Vec::new()).unwrap());
+        let pre_tokenizer = PreTokenizerWrapper::ByteLevel(ByteLevel::default());
+        assert!(Tokenizer::new(byte_level.into())
+            .with_pre_tokenizer(Some(pre_tokenizer))
+            .is_err());
+
+        // Chained with the model's own decoder
+        let vocab: HashMap<String, u32> =
+            [("▁a".into(), 0), ("##b".into(), 1), ("▁c".into(), 2)].into();
+        let pre_tokenizer = PreTokenizerWrapper::Metaspace(Metaspace::default());
+        let tokenizer = Tokenizer::new(WordPiece::new(vocab).into())
+            .with_pre_tokenizer(Some(pre_tokenizer))
+            .unwrap();
+        assert_eq!(tokenizer.decode(&[0, 1]).unwrap(), "ab");
+        assert_eq!(tokenizer.decode(&[0, 1, 2]).unwrap(), "ab c");
     }
 }
@@ -0,0 +1,101 @@
+//! Tokens matched in the input before the model sees it, like `<|endoftext|>`.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AddedToken {
+    pub id: u32,
+    pub content: String,
+    /// Control tokens like BOS or EOS, which never stand for text
+    #[serde(default)]
+    pub special: bool,
+}
+
+/// Which special tokens play which role, by content
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpecialTokens {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bos: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eos: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad: Option<String>,
+    /// Stands in for text the model can't tokenize
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unk: Option<String>,
+}
+
+impl SpecialTokens {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        [&self.bos, &self.eos, &self.pad, &self.unk]
+            .into_iter()
+            .flatten()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AddedVocabulary {
+    tokens: Vec<AddedToken>,
+}
+
+impl AddedVocabulary {
+    pub fn new(tokens: Vec<AddedToken>) -> Self {
+        let mut vocabulary = Self::default();
+        tokens.into_iter().for_each(|t| vocabulary.insert(t));
+        vocabulary
+    }
+
+    pub fn tokens(&self) -> &[AddedToken] {
+        &self.tokens
+    }
+
+    pub fn get(&self, content: &str) -> Option<&AddedToken> {
+        self.tokens.iter().find(|t| t.content == content)
+    }
+
+    pub fn get_by_id(&self, id: u32) -> Option<&AddedToken> {
+        self.tokens.iter().find(|t| t.id == id)
+    }
+
+    /// Add a token, replacing any with the same content
+    pub fn insert(&mut self, token: AddedToken) {
+        match self.tokens.iter_mut().find(|t| t.content == token.content) {
+            Some(existing) => *existing = token,
+            None => self.tokens.push(token),
+        }
+    }
+
+    /// Split text into `(offset, piece, token)`: added tokens, and the text between them.
+    /// The longest token wins where several start at the same place
+    pub fn split<'a>(&self, text: &'a str) -> Vec<(usize, &'a str, Option<&AddedToken>)> {
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < text.len() {
+            let token = self
+                .tokens
+                .iter()
+                .filter(|t| !t.content.is_empty() && text[i..].starts_with(&t.content))
+                .max_by_key(|t| t.content.len());
+            match token {
+                Some(token) => {
+                    if start < i {
+                        pieces.push((start, &text[start..i], None));
+                    }
+                    let end = i + token.content.len();
+                    pieces.push((i, &text[i..end], Some(token)));
+                    start = end;
+                    i = end;
+                }
+                None => i += text[i..].chars().next().map_or(1, char::len_utf8),
+            }
+        }
+        if start < text.len() {
+            pieces.push((start, &text[start..], None));
+        }
+        pieces
+    }
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+
+use super::models::byte_level::char_to_byte;
+
+pub trait Decoder {
+    /// Join tokens back into bytes, which may not be valid UTF-8 if a sequence ends
+    /// mid-character
+    fn decode_bytes(&self, tokens: &[String]) -> Vec<u8>;
+    /// Decode each token to the text it adds, so another decoder can run on the result
+    fn decode_chain(&self, tokens: &[String]) -> Vec<String>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DecoderWrapper {
+    ByteLevel(ByteLevel),
+    WordPiece(WordPiece),
+    Metaspace(Metaspace),
+    Sequence(Sequence),
+}
+
+impl Decoder for DecoderWrapper {
+    fn decode_bytes(&self, tokens: &[String]) -> Vec<u8> {
+        match self {
+            Self::ByteLevel(d) => d.decode_bytes(tokens),
+            Self::WordPiece(d) => d.decode_bytes(tokens),
+            Self::Metaspace(d) => d.decode_bytes(tokens),
+            Self::Sequence(d) => d.decode_bytes(tokens),
+        }
+    }
+    fn decode_chain(&self, tokens: &[String]) -> Vec<String> {
+        match self {
+            Self::ByteLevel(d) => d.decode_chain(tokens),
+            Self::WordPiece(d) => d.decode_chain(tokens),
+            Self::Metaspace(d) => d.decode_chain(tokens),
+            Self::Sequence(d) => d.decode_chain(tokens),
+        }
+    }
+}
+
+/// Undo the byte-to-char mapping of `models::byte_level`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ByteLevel;
+
+impl ByteLevel {
+    fn token_bytes(token: &str, bytes: &mut Vec<u8>) {
+        for c in token.chars() {
+            match char_to_byte().get(&c) {
+                Some(&b) => bytes.push(b),
+                // Not from the mapping, e.g. part of a special token
+                None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+    }
+}
+
+impl Decoder for ByteLevel {
+    fn decode_bytes(&self, tokens: &[String]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for token in tokens {
+            Self::token_bytes(token, &mut bytes);
+        }
+        bytes
+    }
+    /// Characters split across tokens come out as U+FFFD
+    fn decode_chain(&self, tokens: &[String]) -> Vec<String> {
+        tokens
+            .iter()
+            .map(|token| {
+                let mut bytes = Vec::new();
+                Self::token_bytes(token, &mut bytes);
+                String::from_utf8_lossy(&bytes).into_owned()
+            })
+            .collect()
+    }
+}
+
+/// Join WordPiece tokens with spaces, gluing continuation pieces to the piece before
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WordPiece {
+    pub prefix: String,
+}
+
+impl Decoder for WordPiece {
+    fn decode_bytes(&self, tokens: &[String]) -> Vec<u8> {
+        self.decode_chain(tokens).concat().into_bytes()
+    }
+    /// Words that already start with a space, e.g. from a `Metaspace` decoder earlier in a
+    /// sequence, don't get another
+    fn decode_chain(&self, tokens: &[String]) -> Vec<String> {
+        tokens
+            .iter()
+            .enumerate()
+            .map(
+                |(i, token)| match token.strip_prefix(self.prefix.as_str()) {
+                    Some(rest) if i > 0 => rest.to_string(),
+                    _ if i == 0 || token.starts_with(char::is_whitespace) => token.clone(),
+                    _ => format!(" {}", token),
+                },
+            )
+            .collect()
+    }
+}
+
+/// Turn `Metaspace` pre-tokenizer markers back into spaces
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Metaspace {
+    pub replacement: char,
+    /// Drop the space the pre-tokenizer put in front
+    pub prepend_space: bool,
+}
+
+impl Decoder for Metaspace {
+    fn decode_bytes(&self, tokens: &[String]) -> Vec<u8> {
+        self.decode_chain(tokens).concat().into_bytes()
+    }
+    fn decode_chain(&self, tokens: &[String]) -> Vec<String> {
+        tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| {
+                let text = token.replace(self.replacement, " ");
+                match text.strip_prefix(' ') {
+                    Some(rest) if i == 0 && self.prepend_space => rest.to_string(),
+                    _ => text,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Several decoders, applied in order. Only the last one turns tokens into bytes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sequence {
+    pub decoders: Vec<DecoderWrapper>,
+}
+
+impl Decoder for Sequence {
+    fn decode_bytes(&self, tokens: &[String]) -> Vec<u8> {
+        match self.decoders.split_last() {
+            Some((last, rest)) => {
+                let tokens = rest
+                    .iter()
+                    .fold(tokens.to_vec(), |tokens, d| d.decode_chain(&tokens));
+                last.decode_bytes(&tokens)
+            }
+            None => tokens.concat().into_bytes(),
+        }
+    }
+    fn decode_chain(&self, tokens: &[String]) -> Vec<String> {
+        self.decoders
+            .iter()
+            .fold(tokens.to_vec(), |tokens, d| d.decode_chain(&tokens))
+    }
+}
@@ -0,0 +1,772 @@
+//! Hugging Face `tokenizers` `tokenizer.json` format.
+//!
+//! Only the parts our models can honor are read; anything that would change tokenization
+//! but isn't supported (e.g. SentencePiece's precompiled normalizer, or a post-processor
+//! adding BERT's `[CLS]`) is an error rather than silently ignored.
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use super::added_vocabulary::{AddedToken, SpecialTokens};
+use super::decoders::{
+    ByteLevel, DecoderWrapper, Metaspace as MetaspaceDecoder, Sequence as DecoderSequence,
+    WordPiece as WordPieceDecoder,
+};
+use super::models::bpe::{Bpe, Merges};
+use super::models::byte_level::{ByteLevelBpe, GPT2_PATTERN};
+use super::models::unigram::Unigram;
+use super::models::wordpiece::WordPiece;
+use super::models::ModelWrapper;
+use super::normalizers::{
+    Lowercase, Nfc, Nfd, Nfkc, Nfkd, NormalizerWrapper, Replace, Sequence, Strip, StripAccents,
+};
+use super::pre_tokenizers::{
+    ByteLevel as PreTokenizerByteLevel, Digits, Metaspace as PreTokenizerMetaspace,
+    PreTokenizerWrapper, Punctuation, Sequence as PreTokenizerSequence, Split, SplitBehavior,
+    Whitespace,
+};
+use super::{Tokenizer, TokenizerError};
+
+#[derive(Serialize, Deserialize)]
+struct HfTokenizer {
+    #[serde(default)]
+    version: Option<String>,
+    #[serde(default)]
+    truncation: Value,
+    #[serde(default)]
+    padding: Value,
+    #[serde(default)]
+    added_tokens: Vec<HfAddedToken>,
+    #[serde(default)]
+    normalizer: Value,
+    #[serde(default)]
+    pre_tokenizer: Value,
+    #[serde(default)]
+    post_processor: Value,
+    #[serde(default)]
+    decoder: Value,
+    model: HfModel,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HfAddedToken {
+    id: u32,
+    content: String,
+    #[serde(default)]
+    single_word: bool,
+    #[serde(default)]
+    lstrip: bool,
+    #[serde(default)]
+    rstrip: bool,
+    #[serde(default)]
+    normalized: bool,
+    #[serde(default)]
+    special: bool,
+}
+
+/// Merges are `"a b"` in older files and `["a", "b"]` in newer ones
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum HfMerge {
+    Pair(String, String),
+    Joined(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum HfModel {
+    #[serde(rename = "BPE")]
+    Bpe {
+        #[serde(default)]
+        dropout: Option<f32>,
+        #[serde(default)]
+        unk_token: Option<String>,
+        #[serde(default)]
+        continuing_subword_prefix: Option<String>,
+        #[serde(default)]
+        end_of_word_suffix: Option<String>,
+        #[serde(default)]
+        fuse_unk: bool,
+        #[serde(default)]
+        byte_fallback: bool,
+        #[serde(serialize_with = "serialize_by_id")]
+        vocab: HashMap<String, u32>,
+        merges: Vec<HfMerge>,
+    },
+    WordPiece {
+        unk_token: String,
+        continuing_subword_prefix: String,
+        max_input_chars_per_word: usize,
+        #[serde(serialize_with = "serialize_by_id")]
+        vocab: HashMap<String, u32>,
+    },
+    Unigram {
+        unk_id: Option<usize>,
+        vocab: Vec<(String, f64)>,
+        #[serde(default)]
+        byte_fallback: bool,
+    },
+}
+
+/// Vocabs are written in id order, as `tokenizers` does
+fn serialize_by_id<S: Serializer>(
+    vocab: &HashMap<String, u32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut entries: Vec<(&String, &u32)> = vocab.iter().collect();
+    entries.sort_by_key(|(_, id)| **id);
+    serializer.collect_map(entries)
+}
+
+fn unsupported(what: &str, value: &Value) -> TokenizerError {
+    TokenizerError::InvalidInput(format!("Unsupported {}: {}", what, value))
+}
+
+fn byte_level_options() -> Value {
+    json!({"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true})
+}
+
+/// How `tokenizers` writes byte-level BPE with its own regex, e.g. for `cl100k_base`
+fn split_byte_level(pattern: &str) -> [Value; 2] {
+    [
+        json!({"type": "Split", "pattern": {"Regex": pattern}, "behavior": "Isolated", "invert": false}),
+        json!({"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": false}),
+    ]
+}
+
+/// Items of a `pre_tokenizer`, with a top-level `Sequence` flattened
+fn pre_tokenizer_items(pre_tokenizer: &Value) -> Result<Vec<&Value>, TokenizerError> {
+    match pre_tokenizer["type"].as_str() {
+        None if pre_tokenizer.is_null() => Ok(Vec::new()),
+        Some("Sequence") => Ok(pre_tokenizer["pretokenizers"]
+            .as_array()
+            .ok_or_else(|| unsupported("pre-tokenizer", pre_tokenizer))?
+            .iter()
+            .collect()),
+        _ => Ok(vec![pre_tokenizer]),
+    }
+}
+
+/// Take trailing byte-level pre-tokenization off `items`, returning its regex: GPT-2's for a
+/// bare `ByteLevel`, or that of a `Split` followed by a `ByteLevel` without one
+fn take_byte_level(items: &mut Vec<&Value>) -> Option<String> {
+    let is_byte_level = |item: &Value, use_regex: bool| {
+        item["type"] == "ByteLevel"
+            && item["add_prefix_space"] != true
+            && (item["use_regex"] != false) == use_regex
+    };
+    match items[..] {
+        [.., split, byte_level]
+            if is_byte_level(byte_level, false)
+                && split["type"] == "Split"
+                && split["behavior"] == "Isolated"
+                && split["invert"] != true =>
+        {
+            let pattern = split["pattern"]["Regex"].as_str()?.to_string();
+            items.truncate(items.len() - 2);
+            Some(pattern)
+        }
+        [.., byte_level] if is_byte_level(byte_level, true) => {
+            items.pop();
+            Some(GPT2_PATTERN.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn regex_pattern(value: &Value, what: &str) -> Result<String, TokenizerError> {
+    match (
+        value["pattern"]["Regex"].as_str(),
+        value["pattern"]["String"].as_str(),
+    ) {
+        (Some(regex), _) => Ok(regex.to_string()),
+        (_, Some(literal)) => Ok(fancy_regex::escape(literal).into_owned()),
+        _ => Err(unsupported(what, value)),
+    }
+}
+
+/// Whether a `Metaspace` pre-tokenizer or decoder adds a space in front. `prepend_scheme`
+/// replaced `add_prefix_space`
+fn prepend_space(metaspace: &Value) -> bool {
+    match metaspace["prepend_scheme"].as_str() {
+        Some(scheme) => scheme != "never",
+        None => metaspace["add_prefix_space"] != false,
+    }
+}
+
+fn replacement(metaspace: &Value) -> Result<char, TokenizerError> {
+    let mut chars = metaspace["replacement"].as_str().unwrap_or("▁").chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(unsupported("metaspace replacement", metaspace)),
+    }
+}
+
+fn parse_pre_tokenizer(pre_tokenizer: &Value) -> Result<PreTokenizerWrapper, TokenizerError> {
+    let err = || unsupported("pre-tokenizer", pre_tokenizer);
+    Ok(match pre_tokenizer["type"].as_str() {
+        Some("WhitespaceSplit") => PreTokenizerWrapper::Whitespace(Whitespace),
+        // Runs of word chars and runs of other non-space chars
+        Some("Whitespace") => PreTokenizerWrapper::Sequence(PreTokenizerSequence {
+            pre_tokenizers: vec![
+                PreTokenizerWrapper::Whitespace(Whitespace),
+                PreTokenizerWrapper::Split(Split::new(r"[^\w\s]+", SplitBehavior::Isolated)?),
+            ],
+        }),
+        Some("BertPreTokenizer") => PreTokenizerWrapper::Sequence(PreTokenizerSequence {
+            pre_tokenizers: vec![
+                PreTokenizerWrapper::Whitespace(Whitespace),
+                PreTokenizerWrapper::Punctuation(Punctuation),
+            ],
+        }),
+        Some("Punctuation")
+            if pre_tokenizer["behavior"].as_str().unwrap_or("Isolated") == "Isolated" =>
+        {
+            PreTokenizerWrapper::Punctuation(Punctuation)
+        }
+        Some("Digits") => PreTokenizerWrapper::Digits(Digits {
+            individual_digits: pre_tokenizer["individual_digits"] == true,
+        }),
+        Some("ByteLevel") if pre_tokenizer["add_prefix_space"] != true => {
+            PreTokenizerWrapper::ByteLevel(PreTokenizerByteLevel {
+                use_regex: pre_tokenizer["use_regex"] != false,
+            })
+        }
+        Some("Split") if pre_tokenizer["invert"] != true => {
+            let behavior = match pre_tokenizer["behavior"].as_str() {
+                Some("Isolated") => SplitBehavior::Isolated,
+                Some("Removed") => SplitBehavior::Removed,
+                _ => return Err(err()),
+            };
+            let pattern = regex_pattern(pre_tokenizer, "pre-tokenizer")?;
+            PreTokenizerWrapper::Split(Split::new(&pattern, behavior)?)
+        }
+        // Only prepending to every piece, and splitting on the replacement
+        Some("Metaspace")
+            if pre_tokenizer["prepend_scheme"] != "first" && pre_tokenizer["split"] != false =>
+        {
+            PreTokenizerWrapper::Metaspace(PreTokenizerMetaspace {
+                replacement: replacement(pre_tokenizer)?,
+                prepend_space: prepend_space(pre_tokenizer),
+            })
+        }
+        Some("Sequence") => PreTokenizerWrapper::Sequence(PreTokenizerSequence {
+            pre_tokenizers: pre_tokenizer_items(pre_tokenizer)?
+                .into_iter()
+                .map(parse_pre_tokenizer)
+                .collect::<Result<_, _>>()?,
+        }),
+        _ => return Err(err()),
+    })
+}
+
+/// One pre-tokenizer for several items, if there are any
+fn parse_pre_tokenizers(items: Vec<&Value>) -> Result<Option<PreTokenizerWrapper>, TokenizerError> {
+    let mut pre_tokenizers: Vec<PreTokenizerWrapper> = items
+        .into_iter()
+        .map(parse_pre_tokenizer)
+        .collect::<Result<_, _>>()?;
+    Ok(match pre_tokenizers.len() {
+        0 => None,
+        1 => pre_tokenizers.pop(),
+        _ => Some(PreTokenizerWrapper::Sequence(PreTokenizerSequence {
+            pre_tokenizers,
+        })),
+    })
+}
+
+fn pre_tokenizer_to_json(pre_tokenizer: &PreTokenizerWrapper) -> Value {
+    match pre_tokenizer {
+        PreTokenizerWrapper::Whitespace(_) => json!({"type": "WhitespaceSplit"}),
+        PreTokenizerWrapper::Punctuation(_) => {
+            json!({"type": "Punctuation", "behavior": "Isolated"})
+        }
+        PreTokenizerWrapper::Digits(d) => {
+            json!({"type": "Digits", "individual_digits": d.individual_digits})
+        }
+        PreTokenizerWrapper::ByteLevel(b) => json!({
+            "type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": b.use_regex,
+        }),
+        PreTokenizerWrapper::Split(s) => json!({
+            "type": "Split", "pattern": {"Regex": s.pattern}, "behavior": s.behavior, "invert": false,
+        }),
+        PreTokenizerWrapper::Metaspace(m) => metaspace_to_json(m.replacement, m.prepend_space),
+        PreTokenizerWrapper::Sequence(s) => json!({
+            "type": "Sequence",
+            "pretokenizers": s.pre_tokenizers.iter().map(pre_tokenizer_to_json).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+fn metaspace_to_json(replacement: char, prepend_space: bool) -> Value {
+    let prepend_scheme = if prepend_space { "always" } else { "never" };
+    json!({"type": "Metaspace", "replacement": replacement, "prepend_scheme": prepend_scheme, "split": true})
+}
+
+fn parse_normalizer(normalizer: &Value) -> Result<Option<NormalizerWrapper>, TokenizerError> {
+    let flag = |key: &str| normalizer[key].as_bool().unwrap_or(false);
+    Ok(Some(match normalizer["type"].as_str() {
+        None if normalizer.is_null() => return Ok(None),
+        Some("NFC") => NormalizerWrapper::Nfc(Nfc),
+        Some("NFD") => NormalizerWrapper::Nfd(Nfd),
+        Some("NFKC") => NormalizerWrapper::Nfkc(Nfkc),
+        Some("NFKD") => NormalizerWrapper::Nfkd(Nfkd),
+        Some("Lowercase") => NormalizerWrapper::Lowercase(Lowercase),
+        Some("StripAccents") => NormalizerWrapper::StripAccents(StripAccents),
+        Some("Strip") => NormalizerWrapper::Strip(Strip {
+            left: flag("strip_left"),
+            right: flag("strip_right"),
+        }),
+        Some("Replace") => {
+            let pattern = regex_pattern(normalizer, "normalizer")?;
+            let content = normalizer["content"].as_str().unwrap_or_default();
+            NormalizerWrapper::Replace(Replace::new(&pattern, content)?)
+        }
+        Some("Sequence") => NormalizerWrapper::Sequence(Sequence {
+            normalizers: normalizer["normalizers"]
+                .as_array()
+                .ok_or_else(|| unsupported("normalizer", normalizer))?
+                .iter()
+                .map(|n| parse_normalizer(n)?.ok_or_else(|| unsupported("normalizer", normalizer)))
+                .collect::<Result<_, _>>()?,
+        }),
+        _ => return Err(unsupported("normalizer", normalizer)),
+    }))
+}
+
+fn normalizer_to_json(normalizer: &NormalizerWrapper) -> Value {
+    match normalizer {
+        NormalizerWrapper::Nfc(_) => json!({"type": "NFC"}),
+        NormalizerWrapper::Nfd(_) => json!({"type": "NFD"}),
+        NormalizerWrapper::Nfkc(_) => json!({"type": "NFKC"}),
+        NormalizerWrapper::Nfkd(_) => json!({"type": "NFKD"}),
+        NormalizerWrapper::Lowercase(_) => json!({"type": "Lowercase"}),
+        NormalizerWrapper::StripAccents(_) => json!({"type": "StripAccents"}),
+        NormalizerWrapper::Strip(s) => {
+            json!({"type": "Strip", "strip_left": s.left, "strip_right": s.right})
+        }
+        NormalizerWrapper::Replace(r) => {
+            json!({"type": "Replace", "pattern": {"Regex": r.pattern}, "content": r.content})
+        }
+        NormalizerWrapper::Sequence(s) => json!({
+            "type": "Sequence",
+            "normalizers": s.normalizers.iter().map(normalizer_to_json).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+fn parse_decoder(decoder: &Value) -> Result<Option<DecoderWrapper>, TokenizerError> {
+    match decoder["type"].as_str() {
+        None if decoder.is_null() => Ok(None),
+        Some("ByteLevel") => Ok(Some(DecoderWrapper::ByteLevel(ByteLevel))),
+        Some("WordPiece") => Ok(Some(DecoderWrapper::WordPiece(WordPieceDecoder {
+            prefix: decoder["prefix"].as_str().unwrap_or("##").to_string(),
+        }))),
+        Some("Metaspace") => Ok(Some(DecoderWrapper::Metaspace(MetaspaceDecoder {
+            replacement: replacement(decoder)?,
+            prepend_space: prepend_space(decoder),
+        }))),
+        Some("Sequence") => Ok(Some(DecoderWrapper::Sequence(DecoderSequence {
+            decoders: decoder["decoders"]
+                .as_array()
+                .ok_or_else(|| unsupported("decoder", decoder))?
+                .iter()
+                .map(|d| parse_decoder(d)?.ok_or_else(|| unsupported("decoder", decoder)))
+                .collect::<Result<_, _>>()?,
+        }))),
+        _ => Err(unsupported("decoder", decoder)),
+    }
+}
+
+fn decoder_to_json(decoder: &DecoderWrapper) -> Value {
+    match decoder {
+        DecoderWrapper::ByteLevel(_) => byte_level_options(),
+        DecoderWrapper::WordPiece(d) => {
+            json!({"type": "WordPiece", "prefix": d.prefix, "cleanup": false})
+        }
+        DecoderWrapper::Metaspace(d) => metaspace_to_json(d.replacement, d.prepend_space),
+        DecoderWrapper::Sequence(d) => json!({
+            "type": "Sequence",
+            "decoders": d.decoders.iter().map(decoder_to_json).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+pub fn from_json(json: &str) -> Result<Tokenizer, TokenizerError> {
+    let hf: HfTokenizer = serde_json::from_str(json)
+        .map_err(|e| TokenizerError::InvalidInput(format!("Invalid tokenizer.json: {}", e)))?;
+    let normalizer = parse_normalizer(&hf.normalizer)?;
+    let mut pre_tokenizers = pre_tokenizer_items(&hf.pre_tokenizer)?;
+    // GPT-2's only adjusts offsets; the others add template tokens
+    match hf.post_processor["type"].as_str() {
+        None if hf.post_processor.is_null() => {}
+        Some("ByteLevel") => {}
+        _ => return Err(unsupported("post-processor", &hf.post_processor)),
+    }
+    let mut special_tokens = SpecialTokens::default();
+
+    let model: ModelWrapper = match hf.model {
+        HfModel::Bpe {
+            vocab,
+            merges,
+            dropout,
+            unk_token,
+            continuing_subword_prefix,
+            end_of_word_suffix,
+            fuse_unk,
+            byte_fallback,
+        } => {
+            if continuing_subword_prefix.is_some() || end_of_word_suffix.is_some() || byte_fallback
+            {
+                return Err(TokenizerError::InvalidInput(
+                    "BPE affixes and byte fallback are not supported".into(),
+                ));
+            }
+            if dropout.is_some_and(|p| p > 0.0) || fuse_unk {
+                return Err(TokenizerError::InvalidInput(
+                    "BPE dropout and fused unknown tokens are not supported".into(),
+                ));
+            }
+            // Each unknown character becomes one, as our fallback does
+            special_tokens.unk = unk_token;
+            let merges: Merges = merges
+                .into_iter()
+                .map(|merge| match merge {
+                    HfMerge::Pair(a, b) => Ok((a, b)),
+                    HfMerge::Joined(joined) => joined
+                        .split_once(' ')
+                        .map(|(a, b)| (a.to_string(), b.to_string()))
+                        .ok_or_else(|| {
+                            TokenizerError::InvalidInput(format!("Invalid merge {:?}", joined))
+                        }),
+                })
+                .collect::<Result<_, _>>()?;
+            let bpe = Bpe::new(vocab, merges)?;
+            // Byte-level BPE does its own regex splitting, after anything before it
+            match take_byte_level(&mut pre_tokenizers) {
+                Some(pattern) => ByteLevelBpe::with_pattern(bpe, &pattern)?.into(),
+                None => bpe.into(),
+            }
+        }
+        HfModel::WordPiece {
+            unk_token,
+            continuing_subword_prefix,
+            max_input_chars_per_word,
+            vocab,
+        } => {
+            // WordPiece always splits on whitespace
+            if pre_tokenizers
+                .last()
+                .is_some_and(|p| p["type"] == "WhitespaceSplit")
+            {
+                pre_tokenizers.pop();
+            }
+            let mut model = WordPiece::new(vocab);
+            model.unk_token = unk_token;
+            model.continuing_subword_prefix = continuing_subword_prefix;
+            model.max_input_chars_per_word = max_input_chars_per_word;
+            model.into()
+        }
+        HfModel::Unigram {
+            unk_id,
+            vocab,
+            byte_fallback,
+        } => {
+            if byte_fallback {
+                return Err(TokenizerError::InvalidInput(
+                    "Unigram byte fallback is not supported".into(),
+                ));
+            }
+            Unigram::new(vocab, unk_id)?.into()
+        }
+    };
+
+    // Added tokens are matched as-is on the raw text
+    let added_tokens = hf
+        .added_tokens
+        .into_iter()
+        .map(|t| {
+            if t.single_word || t.lstrip || t.rstrip || (t.normalized && normalizer.is_some()) {
+                return Err(TokenizerError::InvalidInput(format!(
+                    "Added token {:?} needs matching options that are not supported",
+                    t.content
+                )));
+            }
+            Ok(AddedToken {
+                id: t.id,
+                content: t.content,
+                special: t.special,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    let pre_tokenizer = parse_pre_tokenizers(pre_tokenizers)?;
+    let decoder = parse_decoder(&hf.decoder)?;
+    Tokenizer::new(model)
+        .with_normalizer(normalizer)
+        .with_pre_tokenizer(pre_tokenizer)?
+        .with_decoder(decoder)
+        .with_added_tokens(added_tokens)
+        .map(|t| t.with_special_tokens(special_tokens))
+}
+
+pub fn to_json(tokenizer: &Tokenizer) -> Result<String, TokenizerError> {
+    let bpe = |bpe: &Bpe| HfModel::Bpe {
+        dropout: None,
+        unk_token: tokenizer.special_tokens().unk.clone(),
+        continuing_subword_prefix: None,
+        end_of_word_suffix: None,
+        fuse_unk: false,
+        byte_fallback: false,
+        vocab: bpe.vocab.clone(),
+        merges: bpe
+            .merges
+            .iter()
+            .map(|(a, b)| HfMerge::Pair(a.clone(), b.clone()))
+            .collect(),
+    };
+    // Ours runs first, then whatever the model does itself
+    let mut pre_tokenizers = match tokenizer.pre_tokenizer().map(pre_tokenizer_to_json) {
+        Some(Value::Object(mut sequence)) if sequence["type"] == "Sequence" => {
+            match sequence.remove("pretokenizers") {
+                Some(Value::Array(items)) => items,
+                _ => Vec::new(),
+            }
+        }
+        Some(pre_tokenizer) => vec![pre_tokenizer],
+        None => Vec::new(),
+    };
+    let model = match &tokenizer.model_wrapper {
+        // Without merges, BPE splits into characters too
+        ModelWrapper::Character(c) => HfModel::Bpe {
+            dropout: None,
+            unk_token: tokenizer.special_tokens().unk.clone(),
+            continuing_subword_prefix: None,
+            end_of_word_suffix: None,
+            fuse_unk: false,
+            byte_fallback: false,
+            vocab: c.vocab.clone(),
+            merges: Vec::new(),
+        },
+        ModelWrapper::Bpe(b) => bpe(b),
+        ModelWrapper::ByteLevelBpe(b) => {
+            if b.pattern() == GPT2_PATTERN {
+                pre_tokenizers.push(byte_level_options());
+            } else {
+                pre_tokenizers.extend(split_byte_level(b.pattern()));
+            }
+            bpe(&b.bpe)
+        }
+        ModelWrapper::Unigram(u) => HfModel::Unigram {
+            unk_id: u.unk_id,
+            vocab: u.vocab.clone(),
+            byte_fallback: false,
+        },
+        ModelWrapper::WordPiece(w) => {
+            pre_tokenizers.push(json!({"type": "WhitespaceSplit"}));
+            HfModel::WordPiece {
+                unk_token: w.unk_token.clone(),
+                continuing_subword_prefix: w.continuing_subword_prefix.clone(),
+                max_input_chars_per_word: w.max_input_chars_per_word,
+                vocab: w.vocab.clone(),
+            }
+        }
+    };
+    let decoder = tokenizer
+        .decoder
+        .as_ref()
+        .map_or(Value::Null, decoder_to_json);
+    let pre_tokenizer = match pre_tokenizers.len() {
+        0 => Value::Null,
+        1 => pre_tokenizers.remove(0),
+        _ => json!({"type": "Sequence", "pretokenizers": pre_tokenizers}),
+    };
+    let hf = HfTokenizer {
+        version: Some("1.0".into()),
+        truncation: Value::Null,
+        padding: Value::Null,
+        added_tokens: tokenizer
+            .added_tokens()
+            .iter()
+            .map(|t| HfAddedToken {
+                id: t.id,
+                content: t.content.clone(),
+                single_word: false,
+                lstrip: false,
+                rstrip: false,
+                normalized: false,
+                special: t.special,
+            })
+            .collect(),
+        normalizer: tokenizer
+            .normalizer()
+            .map_or(Value::Null, normalizer_to_json),
+        pre_tokenizer,
+        post_processor: Value::Null,
+        decoder,
+        model,
+    };
+    serde_json::to_string_pretty(&hf).map_err(|e| TokenizerError::OtherError(Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{from_json, to_json};
+    use crate::tokenizer::models::bpe::Bpe;
+    use crate::tokenizer::models::byte_level::{ByteLevelBpe, CL100K_PATTERN};
+    use crate::tokenizer::models::character::Character;
+    use crate::tokenizer::models::unigram::Unigram;
+    use crate::tokenizer::models::wordpiece::WordPiece;
+    use crate::tokenizer::models::ModelWrapper;
+    use crate::tokenizer::Tokenizer;
+
+    #[test]
+    fn test_gpt2_style() {
+        let json = r#"{
+            "version": "1.0",
+            "added_tokens": [{"id": 5, "content": "<|endoftext|>", "special": true}],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true},
+            "post_processor": {"type": "ByteLevel", "trim_offsets": false},
+            "decoder": {"type": "ByteLevel", "add_prefix_space": true, "trim_offsets": true, "use_regex": true},
+            "model": {
+                "type": "BPE", "dropout": null, "unk_token": null, "fuse_unk": false,
+                "vocab": {"h": 0, "i": 1, "Ġ": 2, "hi": 3, "Ġhi": 4, "<|endoftext|>": 5},
+                "merges": ["h i", "Ġ hi"]
+            }
+        }"#;
+        let tokenizer = from_json(json).unwrap();
+        let ids = tokenizer.encode("hi hi").unwrap().ids;
+        assert_eq!(ids, [3, 4]);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "hi hi");
+        assert_eq!(tokenizer.eos_token_id(), Some(5));
+
+        let with_normalizer = json.replace(
+            r#""normalizer": null"#,
+            r#""normalizer": {"type": "Sequence", "normalizers": [
+                {"type": "NFKC"},
+                {"type": "Replace", "pattern": {"String": "."}, "content": "i"}
+            ]}"#,
+        );
+        let tokenizer = from_json(&with_normalizer).unwrap();
+        assert_eq!(tokenizer.encode("ｈ. h.").unwrap().ids, [3, 4]);
+        // Round trips, with the literal pattern escaped
+        let tokenizer = from_json(&to_json(&tokenizer).unwrap()).unwrap();
+        assert_eq!(tokenizer.encode("h.").unwrap().ids, [3]);
+
+        let unsupported = json.replace(
+            r#""normalizer": null"#,
+            r#""normalizer": {"type": "Precompiled"}"#,
+        );
+        assert!(from_json(&unsupported).is_err());
+
+        // Features that would change the ids are errors rather than ignored
+        for (from, to) in [
+            (
+                r#""post_processor": {"type": "ByteLevel", "trim_offsets": false}"#,
+                r#""post_processor": {"type": "TemplateProcessing"}"#,
+            ),
+            (r#""dropout": null"#, r#""dropout": 0.1"#),
+            (r#""special": true"#, r#""special": true, "lstrip": true"#),
+        ] {
+            assert!(from_json(&json.replace(from, to)).is_err(), "{}", to);
+        }
+
+        // Unknown characters fall back to the model's unk token
+        let with_unk = json.replace(r#""unk_token": null"#, r#""unk_token": "i""#);
+        let tokenizer = from_json(&with_unk).unwrap();
+        assert_eq!(tokenizer.encode("hx").unwrap().ids, [0, 1]);
+        let loaded = from_json(&to_json(&tokenizer).unwrap()).unwrap();
+        assert_eq!(loaded.encode("hx").unwrap().ids, [0, 1]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let vocab = |tokens: &[&str]| -> HashMap<String, u32> {
+            tokens
+                .iter()
+                .enumerate()
+                .map(|(i, t)| (t.to_string(), i as u32))
+                .collect()
+        };
+        let bpe = || {
+            Bpe::new(
+                vocab(&["a", "b", "ab", "Ġ"]),
+                vec![("a".into(), "b".into())],
+            )
+        };
+        let unigram = Unigram::new(
+            vec![
+                ("<unk>".into(), 0.0),
+                ("a".into(), -1.0),
+                ("b".into(), -1.0),
+            ],
+            Some(0),
+        );
+        let models: Vec<(ModelWrapper, &str)> = vec![
+            (Character::new(vocab(&["a", "b", " "])).into(), "ab ba"),
+            (bpe().unwrap().into(), "abba"),
+            (ByteLevelBpe::new(bpe().unwrap()).into(), "ab ab"),
+            (
+                ByteLevelBpe::with_pattern(bpe().unwrap(), CL100K_PATTERN)
+                    .unwrap()
+                    .into(),
+                "ab ab",
+            ),
+            (unigram.unwrap().into(), "abc"),
+            (WordPiece::new(vocab(&["[UNK]", "a", "##b"])).into(), "ab a"),
+        ];
+        for (model, text) in models {
+            let mut tokenizer = Tokenizer::new(model);
+            // Past the end of the model vocab
+            tokenizer.add_token("<|end|>", true);
+            let text = format!("{}<|end|>", text);
+            let text = text.as_str();
+            let ids = tokenizer.encode(text).unwrap().ids;
+
+            let loaded = from_json(&to_json(&tokenizer).unwrap()).unwrap();
+            assert_eq!(loaded.get_vocab(), tokenizer.get_vocab());
+            assert_eq!(loaded.encode(text).unwrap().ids, ids);
+            assert_eq!(
+                loaded.decode(&ids).unwrap(),
+                tokenizer.decode(&ids).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_pre_tokenizers() {
+        let json = r###"{
+            "pre_tokenizer": {"type": "BertPreTokenizer"},
+            "decoder": {"type": "WordPiece", "prefix": "##", "cleanup": true},
+            "model": {
+                "type": "WordPiece", "unk_token": "[UNK]", "continuing_subword_prefix": "##",
+                "max_input_chars_per_word": 100,
+                "vocab": {"[UNK]": 0, "a": 1, "##b": 2, ",": 3}
+            }
+        }"###;
+        let tokenizer = from_json(json).unwrap();
+        // Punctuation splits words the model would otherwise see as one
+        let ids = tokenizer.encode("ab,a").unwrap().ids;
+        assert_eq!(ids, [1, 2, 3, 1]);
+        let loaded = from_json(&to_json(&tokenizer).unwrap()).unwrap();
+        assert_eq!(loaded.encode("ab,a").unwrap().ids, ids);
+
+        // SentencePiece-style, with the decoder taken from the file
+        let json = r#"{
+            "pre_tokenizer": {"type": "Metaspace", "replacement": "▁", "prepend_scheme": "always", "split": true},
+            "decoder": {"type": "Metaspace", "replacement": "▁", "prepend_scheme": "always", "split": true},
+            "model": {"type": "Unigram", "unk_id": 0, "vocab": [["<unk>", 0.0], ["▁a", -1.0], ["b", -1.0]]}
+        }"#;
+        let tokenizer = from_json(json).unwrap();
+        let ids = tokenizer.encode("ab a").unwrap().ids;
+        assert_eq!(ids, [1, 2, 1]);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "ab a");
+        let loaded = from_json(&to_json(&tokenizer).unwrap()).unwrap();
+        assert_eq!(loaded.decode(&ids).unwrap(), "ab a");
+
+        let first_only = json.replace("always", "first");
+        assert!(from_json(&first_only).is_err());
+    }
+}
@@ -7,11 +7,22 @@ use std::io;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
+use super::decoders::{
+    ByteLevel as ByteLevelDecoder, DecoderWrapper, WordPiece as WordPieceDecoder,
+};
 use super::trainer::TrainerWrapper;
 use super::TokenizerError;
 
+pub mod bpe;
+pub mod byte_level;
 pub mod character;
+pub mod unigram;
+pub mod wordpiece;
+use bpe::Bpe;
+use byte_level::ByteLevelBpe;
 use character::Character;
+use unigram::Unigram;
+use wordpiece::WordPiece;
 
 pub trait Model {
     type Trainer: Trainer;
@@ -27,6 +38,10 @@ pub trait Model {
 #[derive(Serialize, Deserialize)]
 pub enum ModelWrapper {
     Character(Character),
+    Bpe(Bpe),
+    ByteLevelBpe(ByteLevelBpe),
+    Unigram(Unigram),
+    WordPiece(WordPiece),
 }
 
 impl From<Character> for ModelWrapper {
@@ -35,41 +50,99 @@ impl From<Character> for ModelWrapper {
     }
 }
 
+impl From<Bpe> for ModelWrapper {
+    fn from(b: Bpe) -> Self {
+        Self::Bpe(b)
+    }
+}
+
+impl From<ByteLevelBpe> for ModelWrapper {
+    fn from(b: ByteLevelBpe) -> Self {
+        Self::ByteLevelBpe(b)
+    }
+}
+
+impl From<Unigram> for ModelWrapper {
+    fn from(u: Unigram) -> Self {
+        Self::Unigram(u)
+    }
+}
+
+impl From<WordPiece> for ModelWrapper {
+    fn from(w: WordPiece) -> Self {
+        Self::WordPiece(w)
+    }
+}
+
 impl Model for ModelWrapper {
     type Trainer = TrainerWrapper;
     fn tokenize(&self, tokens: &str) -> Result<Vec<Token>, TokenizerError> {
         match self {
             Self::Character(c) => c.tokenize(tokens),
+            Self::Bpe(b) => b.tokenize(tokens),
+            Self::ByteLevelBpe(b) => b.tokenize(tokens),
+            Self::Unigram(u) => u.tokenize(tokens),
+            Self::WordPiece(w) => w.tokenize(tokens),
         }
     }
     fn get_trainer(&self) -> Self::Trainer {
         match self {
             Self::Character(c) => TrainerWrapper::CharacterTrainer(c.get_trainer()),
+            Self::Bpe(b) => TrainerWrapper::BpeTrainer(b.get_trainer()),
+            Self::ByteLevelBpe(b) => TrainerWrapper::ByteLevelBpeTrainer(b.get_trainer()),
+            Self::Unigram(u) => TrainerWrapper::UnigramTrainer(u.get_trainer()),
+            Self::WordPiece(w) => TrainerWrapper::WordPieceTrainer(w.get_trainer()),
         }
     }
     fn get_vocab(&self) -> HashMap<String, u32> {
         match self {
             Self::Character(c) => c.get_vocab(),
+            Self::Bpe(b) => b.get_vocab(),
+            Self::ByteLevelBpe(b) => b.get_vocab(),
+            Self::Unigram(u) => u.get_vocab(),
+            Self::WordPiece(w) => w.get_vocab(),
         }
     }
     fn get_vocab_size(&self) -> usize {
         match self {
             Self::Character(c) => c.get_vocab_size(),
+            Self::Bpe(b) => b.get_vocab_size(),
+            Self::ByteLevelBpe(b) => b.get_vocab_size(),
+            Self::Unigram(u) => u.get_vocab_size(),
+            Self::WordPiece(w) => w.get_vocab_size(),
         }
     }
     fn id_to_token(&self, id: u32) -> Option<String> {
         match self {
             Self::Character(c) => c.id_to_token(id),
+            Self::Bpe(b) => b.id_to_token(id),
+            Self::ByteLevelBpe(b) => b.id_to_token(id),
+            Self::Unigram(u) => u.id_to_token(id),
+            Self::WordPiece(w) => w.id_to_token(id),
         }
     }
     fn token_to_id(&self, token: &str) -> Option<u32> {
         match self {
             Self::Character(c) => c.token_to_id(token),
+            Self::Bpe(b) => b.token_to_id(token),
+            Self::ByteLevelBpe(b) => b.token_to_id(token),
+            Self::Unigram(u) => u.token_to_id(token),
+            Self::WordPiece(w) => w.token_to_id(token),
         }
     }
 }
 
 impl ModelWrapper {
+    /// Decoder that undoes how this model spells its tokens, if they aren't plain text
+    pub fn default_decoder(&self) -> Option<DecoderWrapper> {
+        match self {
+            Self::ByteLevelBpe(_) => Some(DecoderWrapper::ByteLevel(ByteLevelDecoder)),
+            Self::WordPiece(w) => Some(DecoderWrapper::WordPiece(WordPieceDecoder {
+                prefix: w.continuing_subword_prefix.clone(),
+            })),
+            _ => None,
+        }
+    }
     pub fn save(&self, folder: &Path, name: Option<&str>) -> Result<Vec<PathBuf>, io::Error> {
         let fname = match name {
             Some(n) => format!("{}.json", n),
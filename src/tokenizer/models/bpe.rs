@@ -0,0 +1,202 @@
+use crate::tokenizer::{models::Model, Token, TokenizerError};
+use std::collections::HashMap;
+
+pub mod trainer;
+use serde::{Deserialize, Serialize};
+use trainer::BpeTrainer;
+
+use super::character::Vocab;
+
+type VocabR = HashMap<u32, String>;
+/// Merge rules, highest priority first
+pub type Merges = Vec<(String, String)>;
+
+/// Byte-pair encoding: starts from single characters and applies learned merges,
+/// lowest rank first.
+#[derive(Serialize)]
+pub struct Bpe {
+    pub vocab: Vocab,
+    pub merges: Merges,
+    #[serde(skip_serializing, skip_deserializing)]
+    vocab_r: VocabR,
+    /// Pair of ids to (rank, id of the merged token)
+    #[serde(skip_serializing, skip_deserializing)]
+    merge_map: HashMap<(u32, u32), (u32, u32)>,
+}
+
+impl Bpe {
+    pub fn new(vocab: Vocab, merges: Merges) -> Result<Self, TokenizerError> {
+        let id = |token: &str| {
+            vocab.get(token).copied().ok_or_else(|| {
+                TokenizerError::InvalidInput(format!("Merge token {:?} is not in the vocab", token))
+            })
+        };
+        let mut merge_map = HashMap::with_capacity(merges.len());
+        for (rank, (a, b)) in merges.iter().enumerate() {
+            let pair = (id(a)?, id(b)?);
+            let merged = id(&format!("{}{}", a, b))?;
+            merge_map.entry(pair).or_insert((rank as u32, merged));
+        }
+        let vocab_r: VocabR = vocab.iter().map(|(k, v)| (*v, k.clone())).collect();
+        Ok(Self {
+            vocab,
+            merges,
+            vocab_r,
+            merge_map,
+        })
+    }
+
+    /// Merge `(id, start, end)` symbols, applying every occurrence of the best-ranked pair
+    /// until none is left
+    pub(crate) fn merge(&self, symbols: &mut Vec<(u32, usize, usize)>) {
+        while let Some((pair, merged)) = symbols
+            .windows(2)
+            .filter_map(|w| {
+                self.merge_map
+                    .get(&(w[0].0, w[1].0))
+                    .map(|m| ((w[0].0, w[1].0), *m))
+            })
+            .min_by_key(|(_, (rank, _))| *rank)
+            .map(|(pair, (_, merged))| (pair, merged))
+        {
+            let mut next = Vec::with_capacity(symbols.len());
+            let mut i = 0;
+            while i < symbols.len() {
+                if i + 1 < symbols.len() && (symbols[i].0, symbols[i + 1].0) == pair {
+                    next.push((merged, symbols[i].1, symbols[i + 1].2));
+                    i += 2;
+                } else {
+                    next.push(symbols[i]);
+                    i += 1;
+                }
+            }
+            *symbols = next;
+        }
+    }
+
+    pub(crate) fn to_tokens(
+        &self,
+        symbols: Vec<(u32, usize, usize)>,
+    ) -> impl Iterator<Item = Token> + '_ {
+        symbols
+            .into_iter()
+            .map(|(id, start, end)| Token::new(id, self.vocab_r[&id].clone(), (start, end)))
+    }
+
+    /// Apply merges to one word, whose first byte is at `offset` in the input
+    fn tokenize_word(
+        &self,
+        word: &str,
+        offset: usize,
+        tokens: &mut Vec<Token>,
+    ) -> Result<(), TokenizerError> {
+        // (id, start, end) of each symbol
+        let mut symbols: Vec<(u32, usize, usize)> = word
+            .char_indices()
+            .map(|(i, c)| {
+                let id = self.vocab.get(c.encode_utf8(&mut [0; 4]) as &str).ok_or(
+                    TokenizerError::UnsupportedCharacter(format!("{:?} is not in the vocab", c)),
+                )?;
+                Ok((*id, offset + i, offset + i + c.len_utf8()))
+            })
+            .collect::<Result<_, TokenizerError>>()?;
+
+        self.merge(&mut symbols);
+        tokens.extend(self.to_tokens(symbols));
+        Ok(())
+    }
+}
+
+// Temporary struct for deserialization
+#[derive(Deserialize)]
+struct TempBpe {
+    vocab: Vocab,
+    merges: Merges,
+}
+
+impl<'de> Deserialize<'de> for Bpe {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let temp: TempBpe = Deserialize::deserialize(deserializer)?;
+        Bpe::new(temp.vocab, temp.merges).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Model for Bpe {
+    type Trainer = BpeTrainer;
+    fn get_trainer(&self) -> Self::Trainer {
+        Self::Trainer::new()
+    }
+    fn get_vocab(&self) -> HashMap<String, u32> {
+        self.vocab.clone()
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+    fn token_to_id(&self, token: &str) -> Option<u32> {
+        self.vocab.get(token).copied()
+    }
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.vocab_r.get(&id).cloned()
+    }
+    /// Byte offsets. Merges never span a newline, since training sees one line at a time
+    fn tokenize(&self, text: &str) -> Result<Vec<Token>, TokenizerError> {
+        let mut tokens = Vec::new();
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            let word = line.strip_suffix('\n').unwrap_or(line);
+            self.tokenize_word(word, offset, &mut tokens)?;
+            if word.len() < line.len() {
+                self.tokenize_word("\n", offset + word.len(), &mut tokens)?;
+            }
+            offset += line.len();
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+    use crate::tokenizer::models::ModelWrapper;
+
+    fn model() -> Bpe {
+        let vocab: Vocab = ["a", "b", "c", "\n", "ab", "abc"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.to_string(), i as u32))
+            .collect();
+        let merges = vec![("a".into(), "b".into()), ("ab".into(), "c".into())];
+        Bpe::new(vocab, merges).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let model = model();
+        let tokens = model.tokenize("abcab\nba").unwrap();
+        let ids: Vec<u32> = tokens.iter().map(|t| t.id).collect();
+        assert_eq!(ids, [5, 4, 3, 1, 0]);
+        assert_eq!(tokens[1].offsets, (3, 5));
+        assert_eq!(tokens[2].value, "\n");
+        assert!(model.tokenize("abd").is_err());
+    }
+
+    #[test]
+    fn test_save() {
+        let wrapper = ModelWrapper::Bpe(model());
+        let paths = wrapper.save(&temp_dir(), Some("bpe-test")).unwrap();
+        let json = std::fs::read_to_string(&paths[0]).unwrap();
+        let loaded: ModelWrapper = serde_json::from_str(&json).unwrap();
+        let ids: Vec<u32> = loaded
+            .tokenize("cabc")
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        assert_eq!(ids, [2, 5]);
+    }
+}
@@ -0,0 +1,281 @@
+use super::{Bpe, Merges};
+use crate::tokenizer::models::character::Vocab;
+use crate::tokenizer::trainer::{Trainer, TrainerError};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+type Pair = (u32, u32);
+
+/// Learns merges by repeatedly joining the most frequent adjacent pair
+pub struct BpeTrainer {
+    /// Stop once the vocab, special tokens included, reaches this size
+    pub vocab_size: usize,
+    /// Stop once the best pair occurs fewer times than this
+    pub min_frequency: u64,
+    /// Added to the vocab first, in order, and never merged
+    pub special_tokens: Vec<String>,
+    /// Characters to include even if the corpus lacks them
+    pub initial_alphabet: Vec<char>,
+    /// Marks symbols that don't start a word, e.g. `##` for WordPiece
+    pub continuing_subword_prefix: Option<String>,
+    /// Count of every word fed so far
+    words: HashMap<String, u64>,
+}
+
+impl Default for BpeTrainer {
+    fn default() -> Self {
+        Self {
+            vocab_size: 1000,
+            min_frequency: 2,
+            special_tokens: Vec::new(),
+            initial_alphabet: Vec::new(),
+            continuing_subword_prefix: None,
+            words: HashMap::new(),
+        }
+    }
+}
+
+/// Candidate merge in the queue. Ties go to the pair of older tokens, for reproducibility
+#[derive(PartialEq, Eq)]
+struct Candidate {
+    count: i64,
+    pair: Pair,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.count
+            .cmp(&other.count)
+            .then_with(|| other.pair.cmp(&self.pair))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Replace each occurrence of `pair` in `word` with `merged`, returning the pair count deltas
+fn merge_word(word: &mut Vec<u32>, pair: Pair, merged: u32) -> Vec<(Pair, i64)> {
+    let mut changes = Vec::new();
+    let mut next = Vec::with_capacity(word.len());
+    let mut i = 0;
+    while i < word.len() {
+        if i + 1 < word.len() && (word[i], word[i + 1]) == pair {
+            if let Some(&prev) = next.last() {
+                changes.push(((prev, pair.0), -1));
+                changes.push(((prev, merged), 1));
+            }
+            changes.push((pair, -1));
+            if let Some(&following) = word.get(i + 2) {
+                changes.push(((pair.1, following), -1));
+                changes.push(((merged, following), 1));
+            }
+            next.push(merged);
+            i += 2;
+        } else {
+            next.push(word[i]);
+            i += 1;
+        }
+    }
+    *word = next;
+    changes
+}
+
+/// Id of `token`, adding it to the vocab if it's new. `tokens` is indexed by id
+fn add_token(token: String, vocab: &mut Vocab, tokens: &mut Vec<String>) -> u32 {
+    *vocab.entry(token.clone()).or_insert_with(|| {
+        tokens.push(token);
+        tokens.len() as u32 - 1
+    })
+}
+
+impl BpeTrainer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Split a word into its initial symbols
+    fn symbols(&self, word: &str) -> Vec<String> {
+        word.chars()
+            .enumerate()
+            .map(|(i, c)| match &self.continuing_subword_prefix {
+                Some(prefix) if i > 0 => format!("{}{}", prefix, c),
+                _ => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// Learn a vocab, and the merges that built it, from everything fed so far
+    pub fn learn(&self) -> (Vocab, Merges) {
+        self.learn_from(&self.words)
+    }
+
+    fn learn_from(&self, word_counts: &HashMap<String, u64>) -> (Vocab, Merges) {
+        let mut vocab: Vocab = HashMap::new();
+        let mut tokens: Vec<String> = Vec::new();
+        for token in &self.special_tokens {
+            add_token(token.clone(), &mut vocab, &mut tokens);
+        }
+        // Alphabet, sorted so ids don't depend on hash order
+        let mut alphabet: Vec<String> = word_counts
+            .keys()
+            .flat_map(|w| self.symbols(w))
+            .chain(self.initial_alphabet.iter().map(|c| c.to_string()))
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect();
+        alphabet.sort();
+        for symbol in alphabet {
+            add_token(symbol, &mut vocab, &mut tokens);
+        }
+
+        let mut sorted_words: Vec<(&String, &u64)> = word_counts.iter().collect();
+        sorted_words.sort();
+        let counts: Vec<i64> = sorted_words.iter().map(|(_, c)| **c as i64).collect();
+        let mut words: Vec<Vec<u32>> = sorted_words
+            .iter()
+            .map(|(w, _)| self.symbols(w).iter().map(|s| vocab[s]).collect())
+            .collect();
+
+        let mut pair_counts: HashMap<Pair, i64> = HashMap::new();
+        let mut where_to_update: HashMap<Pair, HashSet<usize>> = HashMap::new();
+        for (i, word) in words.iter().enumerate() {
+            for w in word.windows(2) {
+                *pair_counts.entry((w[0], w[1])).or_default() += counts[i];
+                where_to_update.entry((w[0], w[1])).or_default().insert(i);
+            }
+        }
+        let mut queue: BinaryHeap<Candidate> = pair_counts
+            .iter()
+            .map(|(&pair, &count)| Candidate { count, pair })
+            .collect();
+
+        let mut merges: Merges = Vec::new();
+        while vocab.len() < self.vocab_size {
+            let Some(top) = queue.pop() else {
+                break;
+            };
+            // Counts change after pairs are queued, so stale entries are requeued as they are now
+            let count = pair_counts.get(&top.pair).copied().unwrap_or(0);
+            if count != top.count {
+                if count > 0 {
+                    queue.push(Candidate {
+                        count,
+                        pair: top.pair,
+                    });
+                }
+                continue;
+            }
+            if (count as u64) < self.min_frequency.max(1) {
+                break;
+            }
+
+            let (a, b) = (
+                tokens[top.pair.0 as usize].clone(),
+                tokens[top.pair.1 as usize].clone(),
+            );
+            let suffix = match &self.continuing_subword_prefix {
+                Some(prefix) => b.strip_prefix(prefix.as_str()).unwrap_or(&b),
+                None => &b,
+            };
+            let merged = add_token(format!("{}{}", a, suffix), &mut vocab, &mut tokens);
+            merges.push((a, b));
+
+            let mut changed: HashSet<Pair> = HashSet::new();
+            for i in where_to_update.remove(&top.pair).unwrap_or_default() {
+                for (pair, delta) in merge_word(&mut words[i], top.pair, merged) {
+                    *pair_counts.entry(pair).or_default() += delta * counts[i];
+                    if delta > 0 {
+                        where_to_update.entry(pair).or_default().insert(i);
+                        changed.insert(pair);
+                    }
+                }
+            }
+            for pair in changed {
+                queue.push(Candidate {
+                    count: pair_counts[&pair],
+                    pair,
+                });
+            }
+        }
+        (vocab, merges)
+    }
+
+    pub fn do_train(
+        &self,
+        word_counts: &HashMap<String, u64>,
+        model: &mut Bpe,
+    ) -> Result<(), TrainerError> {
+        let (vocab, merges) = self.learn_from(word_counts);
+        *model = Bpe::new(vocab, merges).map_err(|e| TrainerError::InvalidModel(e.to_string()))?;
+        Ok(())
+    }
+}
+
+impl Trainer for BpeTrainer {
+    type Model = Bpe;
+    fn train(&self, model: &mut Self::Model) -> Result<(), TrainerError> {
+        self.do_train(&self.words, model)
+    }
+    fn feed<I, S, F>(&mut self, iterator: I, processor: F) -> Result<(), TrainerError>
+    where
+        I: Iterator<Item = S>,
+        S: AsRef<str>,
+        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    {
+        for seq in iterator {
+            for word in processor(seq.as_ref()).map_err(TrainerError::ProcessorError)? {
+                *self.words.entry(word).or_default() += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::tokenizer::models::Model;
+
+    #[test]
+    fn test_trainer() {
+        let mut model = Bpe::new(HashMap::new(), Vec::new()).unwrap();
+        let mut trainer = BpeTrainer {
+            vocab_size: 20,
+            special_tokens: vec!["</s>".into()],
+            ..Default::default()
+        };
+        let corpus = ["hug hug hug pug", "pun bun hugs"];
+        trainer
+            .feed(corpus.iter(), |s| {
+                Ok(s.split(' ').map(|w| w.to_string()).collect())
+            })
+            .unwrap();
+        trainer.train(&mut model).unwrap();
+
+        assert_eq!(model.token_to_id("</s>"), Some(0));
+        // "ug" is the most frequent pair, then "hug"
+        assert_eq!(model.merges[0], ("u".into(), "g".into()));
+        assert_eq!(model.merges[1], ("h".into(), "ug".into()));
+        // Then "un", after which every pair is below the default min frequency
+        assert_eq!(model.merges[2], ("u".into(), "n".into()));
+        assert_eq!(model.get_vocab_size(), 11);
+        let ids: Vec<u32> = model
+            .tokenize("hugs")
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                model.token_to_id("hug").unwrap(),
+                model.token_to_id("s").unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use crate::tokenizer::{models::Model, Token, TokenizerError};
+use fancy_regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+pub mod trainer;
+use serde::{Deserialize, Serialize};
+use trainer::ByteLevelBpeTrainer;
+
+use super::bpe::Bpe;
+
+/// GPT-2's pre-tokenization: contractions, letters, digits and other symbols, each with at
+/// most one leading space, and runs of whitespace
+pub const GPT2_PATTERN: &str =
+    r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+/// GPT-3.5/4's (`cl100k_base`) pre-tokenization: like GPT-2's, but case-insensitive
+/// contractions, digits in groups of at most 3 and newlines kept apart from other whitespace
+pub const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+/// GPT-2's reversible map from bytes to printable chars. Printable Latin-1 maps to itself
+/// and the rest is shifted past U+0100, so e.g. a space becomes `Ġ`
+pub fn bytes_to_char() -> &'static [char; 256] {
+    static TABLE: OnceLock<[char; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        let mut table = ['\0'; 256];
+        let mut shifted = 0;
+        for b in 0..=255u8 {
+            table[b as usize] = if printable(b) {
+                b as char
+            } else {
+                shifted += 1;
+                char::from_u32(255 + shifted).unwrap()
+            };
+        }
+        table
+    })
+}
+
+/// Inverse of `bytes_to_char`
+pub fn char_to_byte() -> &'static HashMap<char, u8> {
+    static TABLE: OnceLock<HashMap<char, u8>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        bytes_to_char()
+            .iter()
+            .enumerate()
+            .map(|(b, &c)| (c, b as u8))
+            .collect()
+    })
+}
+
+/// Split text into `(offset, piece)` with `GPT2_PATTERN`
+pub fn split(text: &str) -> Result<Vec<(usize, &str)>, TokenizerError> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    split_with(
+        PATTERN.get_or_init(|| Regex::new(GPT2_PATTERN).unwrap()),
+        text,
+    )
+}
+
+/// Split text into `(offset, piece)` with any pre-tokenization regex
+pub fn split_with<'a>(
+    pattern: &Regex,
+    text: &'a str,
+) -> Result<Vec<(usize, &'a str)>, TokenizerError> {
+    pattern
+        .find_iter(text)
+        .map(|m| {
+            m.map(|m| (m.start(), m.as_str()))
+                .map_err(|e| TokenizerError::OtherError(Box::new(e)))
+        })
+        .collect()
+}
+
+/// A piece's bytes, spelled with `bytes_to_char`
+pub fn to_chars(piece: &str) -> String {
+    piece.bytes().map(|b| bytes_to_char()[b as usize]).collect()
+}
+
+/// BPE over UTF-8 bytes rather than characters, as in GPT-2.
+///
+/// Every byte has a token, so any text can be encoded. Vocab entries are spelled with
+/// `bytes_to_char`; decode with `decoders::ByteLevel`. Text is pre-tokenized with
+/// `GPT2_PATTERN` unless built `with_pattern`.
+#[derive(Serialize)]
+pub struct ByteLevelBpe {
+    #[serde(flatten)]
+    pub bpe: Bpe,
+    #[serde(skip_serializing_if = "is_gpt2")]
+    pattern: String,
+    #[serde(skip)]
+    regex: Regex,
+}
+
+fn is_gpt2(pattern: &str) -> bool {
+    pattern == GPT2_PATTERN
+}
+
+impl ByteLevelBpe {
+    pub fn new(bpe: Bpe) -> Self {
+        Self::with_pattern(bpe, GPT2_PATTERN).unwrap()
+    }
+
+    /// Pre-tokenize with `pattern` instead of GPT-2's
+    pub fn with_pattern(bpe: Bpe, pattern: &str) -> Result<Self, TokenizerError> {
+        let regex = Regex::new(pattern).map_err(|e| {
+            TokenizerError::InvalidInput(format!("Invalid pre-tokenization regex: {}", e))
+        })?;
+        Ok(Self {
+            bpe,
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+}
+
+// Temporary struct for deserialization
+#[derive(Deserialize)]
+struct TempByteLevelBpe {
+    #[serde(flatten)]
+    bpe: Bpe,
+    #[serde(default)]
+    pattern: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for ByteLevelBpe {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let temp: TempByteLevelBpe = Deserialize::deserialize(deserializer)?;
+        let pattern = temp.pattern.as_deref().unwrap_or(GPT2_PATTERN);
+        ByteLevelBpe::with_pattern(temp.bpe, pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Model for ByteLevelBpe {
+    type Trainer = ByteLevelBpeTrainer;
+    fn get_trainer(&self) -> Self::Trainer {
+        Self::Trainer::new().with_regex(self.regex.clone())
+    }
+    fn get_vocab(&self) -> HashMap<String, u32> {
+        self.bpe.get_vocab()
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.bpe.get_vocab_size()
+    }
+    fn token_to_id(&self, token: &str) -> Option<u32> {
+        self.bpe.token_to_id(token)
+    }
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.bpe.id_to_token(id)
+    }
+    /// Byte offsets, so a token may cover part of a character
+    fn tokenize(&self, text: &str) -> Result<Vec<Token>, TokenizerError> {
+        let mut tokens = Vec::new();
+        for (offset, piece) in split_with(&self.regex, text)? {
+            let mut symbols: Vec<(u32, usize, usize)> = piece
+                .bytes()
+                .enumerate()
+                .map(|(i, b)| {
+                    let c = bytes_to_char()[b as usize];
+                    let id = self.bpe.token_to_id(c.encode_utf8(&mut [0; 4])).ok_or(
+                        TokenizerError::UnsupportedCharacter(format!(
+                            "Byte {:#04x} is not in the vocab",
+                            b
+                        )),
+                    )?;
+                    Ok((id, offset + i, offset + i + 1))
+                })
+                .collect::<Result<_, TokenizerError>>()?;
+            self.bpe.merge(&mut symbols);
+            tokens.extend(self.bpe.to_tokens(symbols));
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::trainer::Trainer;
+    use crate::tokenizer::Tokenizer;
+
+    #[test]
+    fn test_round_trip() {
+        let mut model = ByteLevelBpe::new(Bpe::new(HashMap::new(), Vec::new()).unwrap());
+        let mut trainer = ByteLevelBpeTrainer::new();
+        trainer.bpe.vocab_size = 300;
+        trainer
+            .feed(["the cat sat on the mat", "the hat"].iter(), |s| {
+                Ok(vec![s.to_string()])
+            })
+            .unwrap();
+        trainer.train(&mut model).unwrap();
+        assert!(model.token_to_id("the").is_some());
+
+        let tokenizer = Tokenizer::new(model.into());
+        for text in [
+            "the cat",
+            "Émoji: 👋🏽 🏳️‍🌈!",
+            "\u{0}\t\r\n  \u{7f}\u{FFFD}\u{FFFF}",
+            "e\u{301}\u{200d}\u{10FFFF}",
+        ] {
+            let ids = tokenizer.encode(text).unwrap().ids;
+            assert_eq!(tokenizer.decode(&ids).unwrap(), text);
+        }
+        // A lone continuation byte decodes to U+FFFD, but its raw bytes are kept
+        let ids = tokenizer.encode("é").unwrap().ids;
+        assert_eq!(ids.len(), 2);
+        assert_eq!(tokenizer.decode(&ids[..1]).unwrap(), "\u{FFFD}");
+        assert_eq!(tokenizer.decode_bytes(&ids[1..]).unwrap(), [0xA9]);
+
+        // Trained with the model's own pattern: whole lines, so merges can cross spaces
+        let bpe = Bpe::new(HashMap::new(), Vec::new()).unwrap();
+        let mut model = ByteLevelBpe::with_pattern(bpe, ".+").unwrap();
+        let mut trainer = model.get_trainer();
+        trainer.bpe.vocab_size = 258;
+        trainer
+            .feed(["a a a a"].iter(), |s| Ok(vec![s.to_string()]))
+            .unwrap();
+        trainer.train(&mut model).unwrap();
+        assert!(model.get_vocab().keys().any(|t| t.contains("aĠ")));
+    }
+
+    #[test]
+    fn test_split() {
+        let pieces: Vec<&str> = split("I'll pay $12.50  now!\n")
+            .unwrap()
+            .into_iter()
+            .map(|(_, p)| p)
+            .collect();
+        assert_eq!(
+            pieces,
+            ["I", "'ll", " pay", " $", "12", ".", "50", " ", " now", "!", "\n"]
+        );
+        assert_eq!(to_chars(" é"), "ĠÃ©");
+        assert_eq!(char_to_byte().len(), 256);
+    }
+}
@@ -0,0 +1,59 @@
+use fancy_regex::Regex;
+
+use super::{bytes_to_char, split_with, to_chars, ByteLevelBpe, GPT2_PATTERN};
+use crate::tokenizer::models::bpe::trainer::BpeTrainer;
+use crate::tokenizer::trainer::{Trainer, TrainerError};
+
+/// `BpeTrainer` over pre-tokenized bytes, whose alphabet always has all 256 bytes
+pub struct ByteLevelBpeTrainer {
+    pub bpe: BpeTrainer,
+    /// Pre-tokenization regex, the model's when made by `get_trainer`
+    regex: Regex,
+}
+
+impl Default for ByteLevelBpeTrainer {
+    fn default() -> Self {
+        let mut bpe = BpeTrainer::new();
+        bpe.initial_alphabet = bytes_to_char().to_vec();
+        Self {
+            bpe,
+            regex: Regex::new(GPT2_PATTERN).unwrap(),
+        }
+    }
+}
+
+impl ByteLevelBpeTrainer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pre-tokenize with `regex` instead of GPT-2's
+    pub fn with_regex(mut self, regex: Regex) -> Self {
+        self.regex = regex;
+        self
+    }
+}
+
+impl Trainer for ByteLevelBpeTrainer {
+    type Model = ByteLevelBpe;
+    fn train(&self, model: &mut Self::Model) -> Result<(), TrainerError> {
+        self.bpe.train(&mut model.bpe)
+    }
+    fn feed<I, S, F>(&mut self, iterator: I, processor: F) -> Result<(), TrainerError>
+    where
+        I: Iterator<Item = S>,
+        S: AsRef<str>,
+        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    {
+        let regex = &self.regex;
+        self.bpe.feed(iterator, |seq| {
+            let mut words = Vec::new();
+            for processed in processor(seq)? {
+                for (_, piece) in split_with(regex, &processed)? {
+                    words.push(to_chars(piece));
+                }
+            }
+            Ok(words)
+        })
+    }
+}
@@ -1,11 +1,11 @@
 use super::Character;
 use crate::tokenizer::trainer::{Trainer, TrainerError};
-use crate::tokenizer::Token;
 use std::collections::{HashMap, HashSet};
 
 #[derive(Default)]
 pub struct CharacterTrainer {
-    pub special_tokens: Vec<Token>,
+    /// Reserved at the start of the vocab
+    pub special_tokens: Vec<String>,
     /// All chars encountered during tokenizer training
     chars: HashSet<String>,
 }
@@ -21,11 +21,21 @@ impl CharacterTrainer {
         chars: &HashSet<String>,
         model: &mut Character,
     ) -> Result<(), TrainerError> {
-        // Create token index for char entries, alphabetized
-        let mut items: Vec<String> = chars.iter().cloned().collect();
+        // Create token index for char entries, alphabetized, after the special tokens
+        let mut items: Vec<String> = chars
+            .iter()
+            .filter(|c| !self.special_tokens.contains(c))
+            .cloned()
+            .collect();
         items.sort();
-        let token_map: HashMap<String, u32> =
-            HashMap::from_iter(items.into_iter().enumerate().map(|(i, t)| (t, i as u32)));
+        let token_map: HashMap<String, u32> = HashMap::from_iter(
+            self.special_tokens
+                .iter()
+                .cloned()
+                .chain(items)
+                .enumerate()
+                .map(|(i, t)| (t, i as u32)),
+        );
 
         // Persist to model
         model.vocab = token_map;
@@ -0,0 +1,396 @@
+use crate::tokenizer::{models::Model, Token, TokenizerError};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub mod trainer;
+use serde::{Deserialize, Serialize};
+use trainer::UnigramTrainer;
+
+/// How much less likely than the rarest piece an unknown character is
+const UNK_PENALTY: f64 = 10.0;
+
+/// Unigram language model (Kudo 2018), as in SentencePiece: each piece has a log-prob and a
+/// word is split into the most likely sequence of pieces.
+#[derive(Serialize)]
+pub struct Unigram {
+    /// Pieces and their log-probs, indexed by id
+    pub vocab: Vec<(String, f64)>,
+    pub unk_id: Option<usize>,
+    #[serde(skip_serializing, skip_deserializing)]
+    token_to_ids: HashMap<String, u32>,
+    /// Longest piece, in chars
+    #[serde(skip_serializing, skip_deserializing)]
+    max_piece_len: usize,
+    #[serde(skip_serializing, skip_deserializing)]
+    min_score: f64,
+    /// Sample segmentations with this smoothing and RNG instead of taking the best one.
+    /// Not saved with the model
+    #[serde(skip_serializing, skip_deserializing)]
+    sampling: Option<(f64, Mutex<StdRng>)>,
+}
+
+impl Unigram {
+    pub fn new(vocab: Vec<(String, f64)>, unk_id: Option<usize>) -> Result<Self, TokenizerError> {
+        if unk_id.is_some_and(|id| id >= vocab.len()) {
+            return Err(TokenizerError::InvalidInput(
+                "Unk id is not in the vocab".into(),
+            ));
+        }
+        let token_to_ids = vocab
+            .iter()
+            .enumerate()
+            .map(|(id, (piece, _))| (piece.clone(), id as u32))
+            .collect();
+        let max_piece_len = vocab
+            .iter()
+            .map(|(piece, _)| piece.chars().count())
+            .max()
+            .unwrap_or(0);
+        let min_score = vocab.iter().map(|(_, score)| *score).fold(0.0, f64::min);
+        Ok(Self {
+            vocab,
+            unk_id,
+            token_to_ids,
+            max_piece_len,
+            min_score,
+            sampling: None,
+        })
+    }
+
+    /// Subword regularization: sample each segmentation with probability proportional to
+    /// `P(segmentation)^alpha`, rather than taking the best one. `None` turns it off.
+    ///
+    /// Samples are drawn from an RNG seeded with `seed`, so encoding is reproducible
+    pub fn set_sampling(&mut self, alpha: Option<f64>, seed: u64) {
+        self.sampling = alpha.map(|alpha| (alpha, Mutex::new(StdRng::seed_from_u64(seed))));
+    }
+
+    fn lattice(&self, word: &str) -> Lattice {
+        Lattice::new(
+            word,
+            self.max_piece_len,
+            |piece| {
+                let id = *self.token_to_ids.get(piece)?;
+                Some((id, self.vocab[id as usize].1))
+            },
+            self.unk_id
+                .map(|id| (id as u32, self.min_score - UNK_PENALTY)),
+        )
+    }
+
+    /// Tokenize `text`, sampling segmentations with smoothing `alpha` from `rng`
+    pub fn sample<R: Rng>(
+        &self,
+        text: &str,
+        alpha: f64,
+        rng: &mut R,
+    ) -> Result<Vec<Token>, TokenizerError> {
+        self.tokenize_with(text, |lattice| lattice.sample(alpha, rng))
+    }
+
+    /// Lines are segmented one at a time, as in training
+    fn tokenize_with<F>(&self, text: &str, mut segment: F) -> Result<Vec<Token>, TokenizerError>
+    where
+        F: FnMut(&Lattice) -> Option<Vec<Edge>>,
+    {
+        let mut tokens = Vec::new();
+        let mut offset = 0;
+        for line in text.split_inclusive('\n') {
+            let word = line.strip_suffix('\n').unwrap_or(line);
+            for part in [word, &line[word.len()..]] {
+                let lattice = self.lattice(part);
+                let edges = segment(&lattice).ok_or(TokenizerError::UnsupportedCharacter(
+                    "Unknown token without <unk>".into(),
+                ))?;
+                tokens.extend(edges.into_iter().map(|e| {
+                    let (start, end) = lattice.byte_range(&e);
+                    Token::new(
+                        e.id,
+                        self.vocab[e.id as usize].0.clone(),
+                        (offset + start, offset + end),
+                    )
+                }));
+                offset += part.len();
+            }
+        }
+        Ok(tokens)
+    }
+}
+
+/// Piece spanning chars `start..end` of a word
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Edge {
+    pub start: usize,
+    pub end: usize,
+    pub id: u32,
+    pub score: f64,
+}
+
+/// Every way of covering a word with known pieces
+pub(crate) struct Lattice {
+    /// Byte offset of each char boundary
+    bounds: Vec<usize>,
+    /// Edges ending at each char boundary
+    ends: Vec<Vec<Edge>>,
+}
+
+fn log_add(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    let (hi, lo) = if a > b { (a, b) } else { (b, a) };
+    hi + (lo - hi).exp().ln_1p()
+}
+
+impl Lattice {
+    /// `lookup` gives the id and log-prob of a piece. Chars with no piece get `unk`, if any
+    pub fn new<F>(word: &str, max_len: usize, lookup: F, unk: Option<(u32, f64)>) -> Self
+    where
+        F: Fn(&str) -> Option<(u32, f64)>,
+    {
+        let bounds: Vec<usize> = word
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([word.len()])
+            .collect();
+        let n = bounds.len() - 1;
+        let mut ends = vec![Vec::new(); n + 1];
+        for start in 0..n {
+            let mut has_char = false;
+            for end in start + 1..=(start + max_len).min(n) {
+                if let Some((id, score)) = lookup(&word[bounds[start]..bounds[end]]) {
+                    has_char |= end == start + 1;
+                    ends[end].push(Edge {
+                        start,
+                        end,
+                        id,
+                        score,
+                    });
+                }
+            }
+            if let (false, Some((id, score))) = (has_char, unk) {
+                ends[start + 1].push(Edge {
+                    start,
+                    end: start + 1,
+                    id,
+                    score,
+                });
+            }
+        }
+        Self { bounds, ends }
+    }
+
+    pub fn byte_range(&self, edge: &Edge) -> (usize, usize) {
+        (self.bounds[edge.start], self.bounds[edge.end])
+    }
+
+    /// Most likely segmentation and its log-prob, or `None` if some char can't be covered
+    pub fn viterbi(&self) -> Option<(Vec<Edge>, f64)> {
+        let n = self.ends.len() - 1;
+        let mut best: Vec<Option<(f64, Edge)>> = vec![None; n + 1];
+        let mut best_score = vec![f64::NEG_INFINITY; n + 1];
+        best_score[0] = 0.0;
+        for end in 1..=n {
+            for edge in &self.ends[end] {
+                let score = best_score[edge.start] + edge.score;
+                if score > best_score[end] {
+                    best_score[end] = score;
+                    best[end] = Some((score, *edge));
+                }
+            }
+        }
+        let mut path = Vec::new();
+        let mut pos = n;
+        while pos > 0 {
+            let (_, edge) = best[pos]?;
+            path.push(edge);
+            pos = edge.start;
+        }
+        path.reverse();
+        Some((path, best_score[n]))
+    }
+
+    /// Log of the summed probability of all segmentations of each prefix
+    fn forward(&self, theta: f64) -> Vec<f64> {
+        let mut alpha = vec![f64::NEG_INFINITY; self.ends.len()];
+        alpha[0] = 0.0;
+        for end in 1..self.ends.len() {
+            for edge in &self.ends[end] {
+                alpha[end] = log_add(alpha[end], alpha[edge.start] + theta * edge.score);
+            }
+        }
+        alpha
+    }
+
+    /// Draw a segmentation with probability proportional to `P(segmentation)^theta`
+    pub fn sample<R: Rng>(&self, theta: f64, rng: &mut R) -> Option<Vec<Edge>> {
+        let alpha = self.forward(theta);
+        let mut path = Vec::new();
+        let mut pos = self.ends.len() - 1;
+        while pos > 0 {
+            // Pick the last edge in proportion to the mass of paths through it
+            let weights: Vec<f64> = self.ends[pos]
+                .iter()
+                .map(|e| (alpha[e.start] + theta * e.score - alpha[pos]).exp())
+                .collect();
+            let mut threshold = rng.gen::<f64>() * weights.iter().sum::<f64>();
+            let mut chosen = None;
+            for (edge, w) in self.ends[pos].iter().zip(weights) {
+                chosen = Some(*edge);
+                threshold -= w;
+                if threshold <= 0.0 {
+                    break;
+                }
+            }
+            let edge = chosen?;
+            path.push(edge);
+            pos = edge.start;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Add each piece's expected count, for a word seen `freq` times, to `counts`.
+    /// Returns the word's log-likelihood
+    pub fn expected_counts(&self, freq: f64, counts: &mut [f64]) -> f64 {
+        let n = self.ends.len() - 1;
+        let alpha = self.forward(1.0);
+        let mut beta = vec![f64::NEG_INFINITY; n + 1];
+        beta[n] = 0.0;
+        for end in (1..=n).rev() {
+            for edge in &self.ends[end] {
+                beta[edge.start] = log_add(beta[edge.start], edge.score + beta[end]);
+            }
+        }
+        let z = alpha[n];
+        for edges in &self.ends {
+            for edge in edges {
+                counts[edge.id as usize] +=
+                    freq * (alpha[edge.start] + edge.score + beta[edge.end] - z).exp();
+            }
+        }
+        z
+    }
+}
+
+// Temporary struct for deserialization
+#[derive(Deserialize)]
+struct TempUnigram {
+    vocab: Vec<(String, f64)>,
+    unk_id: Option<usize>,
+}
+
+impl<'de> Deserialize<'de> for Unigram {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let temp: TempUnigram = Deserialize::deserialize(deserializer)?;
+        Unigram::new(temp.vocab, temp.unk_id).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Model for Unigram {
+    type Trainer = UnigramTrainer;
+    fn get_trainer(&self) -> Self::Trainer {
+        Self::Trainer::new()
+    }
+    fn get_vocab(&self) -> HashMap<String, u32> {
+        self.token_to_ids.clone()
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+    fn token_to_id(&self, token: &str) -> Option<u32> {
+        self.token_to_ids.get(token).copied()
+    }
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.vocab.get(id as usize).map(|(piece, _)| piece.clone())
+    }
+    /// Byte offsets. Samples segmentations if `set_sampling` was given an alpha
+    fn tokenize(&self, text: &str) -> Result<Vec<Token>, TokenizerError> {
+        match &self.sampling {
+            Some((alpha, rng)) => self.sample(text, *alpha, &mut *rng.lock().unwrap()),
+            None => self.tokenize_with(text, |lattice| lattice.viterbi().map(|(path, _)| path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn model() -> Unigram {
+        let vocab = [
+            ("<unk>", 0.0),
+            ("a", -2.0),
+            ("b", -2.0),
+            ("c", -2.0),
+            ("ab", -3.0),
+            ("abc", -5.0),
+            ("bc", -2.5),
+        ];
+        let vocab = vocab.iter().map(|(p, s)| (p.to_string(), *s)).collect();
+        Unigram::new(vocab, Some(0)).unwrap()
+    }
+
+    #[test]
+    fn test_viterbi() {
+        let model = model();
+        let pieces: Vec<String> = model
+            .tokenize("abcxab")
+            .unwrap()
+            .into_iter()
+            .map(|t| t.value)
+            .collect();
+        // a + bc (-4.5) beats abc (-5), ab + c (-5) and a + b + c (-6)
+        assert_eq!(pieces, ["a", "bc", "<unk>", "ab"]);
+        let tokens = model.tokenize("abcxab").unwrap();
+        assert_eq!(tokens[2].offsets, (3, 4));
+    }
+
+    #[test]
+    fn test_sample() {
+        let model = model();
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut segmentations = std::collections::HashSet::new();
+        for _ in 0..50 {
+            let ids: Vec<u32> = model
+                .sample("abc", 1.0, &mut rng)
+                .unwrap()
+                .into_iter()
+                .map(|t| t.id)
+                .collect();
+            segmentations.insert(ids);
+        }
+        // a|b|c, ab|c, a|bc and abc are all possible
+        assert_eq!(segmentations.len(), 4);
+        // Very large alpha is Viterbi
+        let ids: Vec<u32> = model
+            .sample("abc", 100.0, &mut rng)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.id)
+            .collect();
+        assert_eq!(ids, [1, 6]);
+
+        // Same seed, same segmentations
+        let mut a = model;
+        let mut b = self::model();
+        a.set_sampling(Some(1.0), 7);
+        b.set_sampling(Some(1.0), 7);
+        let ids = |m: &Unigram| -> Vec<u32> {
+            m.tokenize("abcabcabc")
+                .unwrap()
+                .into_iter()
+                .map(|t| t.id)
+                .collect()
+        };
+        assert_eq!(ids(&a), ids(&b));
+    }
+}
@@ -0,0 +1,304 @@
+use super::{Lattice, Unigram};
+use crate::tokenizer::trainer::{Trainer, TrainerError};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Learns a unigram vocab by EM: start from the most frequent substrings, then repeatedly
+/// re-estimate piece probabilities and drop the pieces whose loss hurts likelihood least
+pub struct UnigramTrainer {
+    /// Final vocab size, special tokens and unk included
+    pub vocab_size: usize,
+    /// Added to the vocab first, in order
+    pub special_tokens: Vec<String>,
+    /// Token for characters the vocab lacks, added after the special tokens
+    pub unk_token: Option<String>,
+    /// Longest piece, in chars
+    pub max_piece_length: usize,
+    /// How many of the most frequent substrings to start from
+    pub seed_size: usize,
+    /// Fraction of pieces kept by each pruning round
+    pub shrinking_factor: f64,
+    /// EM iterations between pruning rounds
+    pub n_sub_iterations: usize,
+    /// Count of every word fed so far
+    words: HashMap<String, u64>,
+}
+
+impl Default for UnigramTrainer {
+    fn default() -> Self {
+        Self {
+            vocab_size: 1000,
+            special_tokens: Vec::new(),
+            unk_token: Some("<unk>".into()),
+            max_piece_length: 16,
+            seed_size: 1_000_000,
+            shrinking_factor: 0.75,
+            n_sub_iterations: 2,
+            words: HashMap::new(),
+        }
+    }
+}
+
+/// Candidate pieces with their log-probs
+type Pieces = Vec<(String, f64)>;
+
+fn by_score_desc(a: &(String, f64), b: &(String, f64)) -> Ordering {
+    b.1.partial_cmp(&a.1)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| a.0.cmp(&b.0))
+}
+
+impl UnigramTrainer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Most frequent substrings, scored by frequency times length as in SentencePiece.
+    /// Every character is kept so any seen word can still be segmented
+    fn seed_pieces(&self, words: &[(String, f64)]) -> Pieces {
+        let mut freqs: HashMap<&str, f64> = HashMap::new();
+        for (word, count) in words {
+            let bounds: Vec<usize> = word
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain([word.len()])
+                .collect();
+            for start in 0..bounds.len() - 1 {
+                for end in start + 1..bounds.len().min(start + 1 + self.max_piece_length) {
+                    *freqs.entry(&word[bounds[start]..bounds[end]]).or_default() += count;
+                }
+            }
+        }
+        let (chars, mut substrings): (Vec<_>, Vec<_>) = freqs
+            .into_iter()
+            .map(|(piece, freq)| (piece.to_string(), freq))
+            .partition(|(piece, _)| piece.chars().count() == 1);
+        substrings.retain(|(_, freq)| *freq > 1.0);
+        substrings.sort_by(|a, b| {
+            let score = |(piece, freq): &(String, f64)| freq * piece.chars().count() as f64;
+            score(b)
+                .partial_cmp(&score(a))
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.0.cmp(&b.0))
+        });
+        substrings.truncate(self.seed_size.saturating_sub(chars.len()));
+
+        let mut pieces: Pieces = chars.into_iter().chain(substrings).collect();
+        let total: f64 = pieces.iter().map(|(_, f)| f).sum();
+        for (_, score) in pieces.iter_mut() {
+            *score = (*score / total).ln();
+        }
+        pieces.sort_by(by_score_desc);
+        pieces
+    }
+
+    fn lattice(&self, word: &str, ids: &HashMap<&str, u32>, pieces: &Pieces) -> Lattice {
+        Lattice::new(
+            word,
+            self.max_piece_length,
+            |piece| {
+                let id = *ids.get(piece)?;
+                Some((id, pieces[id as usize].1))
+            },
+            None,
+        )
+    }
+
+    /// Expected count of each piece over the corpus
+    fn e_step(&self, pieces: &Pieces, words: &[(String, f64)]) -> Vec<f64> {
+        let ids: HashMap<&str, u32> = pieces
+            .iter()
+            .enumerate()
+            .map(|(id, (piece, _))| (piece.as_str(), id as u32))
+            .collect();
+        let mut counts = vec![0.0; pieces.len()];
+        for (word, freq) in words {
+            self.lattice(word, &ids, pieces)
+                .expected_counts(*freq, &mut counts);
+        }
+        counts
+    }
+
+    /// Re-estimate log-probs from expected counts, dropping pieces that are never used
+    fn m_step(&self, pieces: &Pieces, counts: &[f64]) -> Pieces {
+        let mut kept: Pieces = pieces
+            .iter()
+            .zip(counts)
+            .filter(|((piece, _), &count)| count > 0.5 || piece.chars().count() == 1)
+            .map(|((piece, _), &count)| (piece.clone(), count.max(f64::MIN_POSITIVE)))
+            .collect();
+        let total: f64 = kept.iter().map(|(_, c)| c).sum();
+        for (_, score) in kept.iter_mut() {
+            *score = (*score / total).ln();
+        }
+        kept
+    }
+
+    /// Keep the `size` pieces whose removal would cost the most likelihood
+    fn prune(&self, pieces: &Pieces, counts: &[f64], size: usize) -> Pieces {
+        let ids: HashMap<&str, u32> = pieces
+            .iter()
+            .enumerate()
+            .map(|(id, (piece, _))| (piece.as_str(), id as u32))
+            .collect();
+        let mut losses: Vec<(usize, f64)> = pieces
+            .iter()
+            .enumerate()
+            .map(|(id, (piece, score))| {
+                if piece.chars().count() == 1 {
+                    return (id, f64::INFINITY);
+                }
+                // Without this piece, its text falls back to the best split into others
+                let alternative = Lattice::new(
+                    piece,
+                    self.max_piece_length,
+                    |p| {
+                        let other = *ids.get(p)?;
+                        (other as usize != id).then(|| (other, pieces[other as usize].1))
+                    },
+                    None,
+                )
+                .viterbi()
+                .map(|(_, score)| score)
+                .unwrap_or(f64::NEG_INFINITY);
+                (id, counts[id] * (score - alternative))
+            })
+            .collect();
+        losses.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| pieces[a.0].0.cmp(&pieces[b.0].0))
+        });
+        let mut kept: Pieces = losses
+            .into_iter()
+            .take(size)
+            .map(|(id, _)| pieces[id].clone())
+            .collect();
+        kept.sort_by(by_score_desc);
+        kept
+    }
+
+    pub fn do_train(
+        &self,
+        word_counts: &HashMap<String, u64>,
+        model: &mut Unigram,
+    ) -> Result<(), TrainerError> {
+        let mut words: Vec<(String, f64)> = word_counts
+            .iter()
+            .map(|(w, c)| (w.clone(), *c as f64))
+            .collect();
+        words.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut reserved: Vec<String> = self.special_tokens.clone();
+        if let Some(unk) = &self.unk_token {
+            if !reserved.contains(unk) {
+                reserved.push(unk.clone());
+            }
+        }
+        let target = self.vocab_size.saturating_sub(reserved.len());
+
+        let mut pieces = self.seed_pieces(&words);
+        // Pruning never drops a character, or some words couldn't be segmented at all
+        let n_chars = pieces
+            .iter()
+            .filter(|(p, _)| p.chars().count() == 1)
+            .count();
+        if n_chars > target {
+            return Err(TrainerError::InvalidSettings(format!(
+                "Vocab size {} can't fit the {} characters seen plus {} reserved tokens",
+                self.vocab_size,
+                n_chars,
+                reserved.len()
+            )));
+        }
+        loop {
+            for _ in 0..self.n_sub_iterations.max(1) {
+                let counts = self.e_step(&pieces, &words);
+                pieces = self.m_step(&pieces, &counts);
+            }
+            if pieces.len() <= target {
+                break;
+            }
+            let counts = self.e_step(&pieces, &words);
+            let size = ((pieces.len() as f64 * self.shrinking_factor) as usize).max(target);
+            pieces = self.prune(&pieces, &counts, size);
+        }
+
+        pieces.sort_by(by_score_desc);
+        // Reserved tokens first, with a neutral score
+        let mut vocab: Vec<(String, f64)> = reserved.iter().map(|t| (t.clone(), 0.0)).collect();
+        vocab.extend(pieces.into_iter().filter(|(p, _)| !reserved.contains(p)));
+        let unk_id = self
+            .unk_token
+            .as_ref()
+            .and_then(|unk| vocab.iter().position(|(p, _)| p == unk));
+        *model =
+            Unigram::new(vocab, unk_id).map_err(|e| TrainerError::InvalidModel(e.to_string()))?;
+        Ok(())
+    }
+}
+
+impl Trainer for UnigramTrainer {
+    type Model = Unigram;
+    fn train(&self, model: &mut Self::Model) -> Result<(), TrainerError> {
+        self.do_train(&self.words, model)
+    }
+    fn feed<I, S, F>(&mut self, iterator: I, processor: F) -> Result<(), TrainerError>
+    where
+        I: Iterator<Item = S>,
+        S: AsRef<str>,
+        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    {
+        for seq in iterator {
+            for word in processor(seq.as_ref()).map_err(TrainerError::ProcessorError)? {
+                *self.words.entry(word).or_default() += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::models::Model;
+
+    #[test]
+    fn test_trainer() {
+        let mut model = Unigram::new(Vec::new(), None).unwrap();
+        let mut trainer = UnigramTrainer {
+            vocab_size: 20,
+            special_tokens: vec!["</s>".into()],
+            ..Default::default()
+        };
+        let corpus = [
+            "the cat sat on the mat",
+            "the hat is on the cat",
+            "that cat",
+        ];
+        trainer
+            .feed(corpus.iter(), |s| {
+                Ok(s.split(' ').map(|w| w.to_string()).collect())
+            })
+            .unwrap();
+        trainer.train(&mut model).unwrap();
+
+        // Pieces EM never uses are dropped, so the vocab can come out smaller
+        assert!(model.get_vocab_size() <= 20);
+        assert_eq!(model.token_to_id("</s>"), Some(0));
+        assert_eq!(model.unk_id, Some(1));
+        // Frequent words survive pruning whole
+        let pieces: Vec<String> = model
+            .tokenize("thecat")
+            .unwrap()
+            .into_iter()
+            .map(|t| t.value)
+            .collect();
+        assert_eq!(pieces, ["the", "cat"]);
+        assert_eq!(model.tokenize("dog").unwrap()[0].value, "<unk>");
+
+        // Too small for every character the corpus has
+        trainer.vocab_size = 8;
+        assert!(trainer.train(&mut model).is_err());
+    }
+}
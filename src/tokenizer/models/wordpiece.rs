@@ -0,0 +1,205 @@
+use crate::tokenizer::{models::Model, Token, TokenizerError};
+use std::collections::HashMap;
+
+pub mod trainer;
+use serde::{Deserialize, Serialize};
+use trainer::WordPieceTrainer;
+
+use super::character::Vocab;
+
+type VocabR = HashMap<u32, String>;
+
+/// WordPiece, as in BERT: each whitespace-separated word is split greedily into the longest
+/// pieces in the vocab, with non-initial pieces marked by a prefix.
+///
+/// Whitespace itself is never a token; `decoders::WordPiece` puts single spaces back.
+#[derive(Serialize)]
+pub struct WordPiece {
+    pub vocab: Vocab,
+    /// Stands in for any word that can't be split into known pieces
+    pub unk_token: String,
+    pub continuing_subword_prefix: String,
+    /// Longer words become `unk_token` without being split
+    pub max_input_chars_per_word: usize,
+    #[serde(skip_serializing, skip_deserializing)]
+    vocab_r: VocabR,
+}
+
+impl WordPiece {
+    pub fn new(vocab: Vocab) -> Self {
+        let vocab_r: VocabR = vocab.iter().map(|(k, v)| (*v, k.clone())).collect();
+        Self {
+            vocab,
+            unk_token: "[UNK]".into(),
+            continuing_subword_prefix: "##".into(),
+            max_input_chars_per_word: 100,
+            vocab_r,
+        }
+    }
+
+    /// Split one word, whose first byte is at `offset` in the input
+    fn tokenize_word(
+        &self,
+        word: &str,
+        offset: usize,
+        tokens: &mut Vec<Token>,
+    ) -> Result<(), TokenizerError> {
+        let unk = || -> Result<Token, TokenizerError> {
+            let id =
+                self.token_to_id(&self.unk_token)
+                    .ok_or(TokenizerError::UnsupportedCharacter(format!(
+                        "Cannot split {:?} and {:?} is not in the vocab",
+                        word, self.unk_token
+                    )))?;
+            let offsets = (offset, offset + word.len());
+            Ok(Token::new(id, self.unk_token.clone(), offsets))
+        };
+        if word.chars().count() > self.max_input_chars_per_word {
+            tokens.push(unk()?);
+            return Ok(());
+        }
+
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        while start < word.len() {
+            // Longest prefix of the rest of the word that's in the vocab
+            let found = word[start..]
+                .char_indices()
+                .map(|(i, c)| start + i + c.len_utf8())
+                .rev()
+                .find_map(|end| {
+                    let piece = match start {
+                        0 => word[..end].to_string(),
+                        _ => format!("{}{}", self.continuing_subword_prefix, &word[start..end]),
+                    };
+                    let id = self.token_to_id(&piece)?;
+                    Some((end, Token::new(id, piece, (offset + start, offset + end))))
+                });
+            match found {
+                Some((end, token)) => {
+                    pieces.push(token);
+                    start = end;
+                }
+                None => {
+                    tokens.push(unk()?);
+                    return Ok(());
+                }
+            }
+        }
+        tokens.extend(pieces);
+        Ok(())
+    }
+}
+
+/// `(offset, word)` for each whitespace-separated word
+pub(crate) fn split_whitespace(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(char::is_whitespace)
+        .filter(|w| !w.is_empty())
+        .map(move |w| (w.as_ptr() as usize - text.as_ptr() as usize, w))
+}
+
+// Temporary struct for deserialization
+#[derive(Deserialize)]
+struct TempWordPiece {
+    vocab: Vocab,
+    unk_token: Option<String>,
+    continuing_subword_prefix: Option<String>,
+    max_input_chars_per_word: Option<usize>,
+}
+
+impl From<TempWordPiece> for WordPiece {
+    fn from(temp: TempWordPiece) -> Self {
+        let mut model = WordPiece::new(temp.vocab);
+        if let Some(unk_token) = temp.unk_token {
+            model.unk_token = unk_token;
+        }
+        if let Some(prefix) = temp.continuing_subword_prefix {
+            model.continuing_subword_prefix = prefix;
+        }
+        if let Some(max) = temp.max_input_chars_per_word {
+            model.max_input_chars_per_word = max;
+        }
+        model
+    }
+}
+
+impl<'de> Deserialize<'de> for WordPiece {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let temp: TempWordPiece = Deserialize::deserialize(deserializer)?;
+        Ok(temp.into())
+    }
+}
+
+impl Model for WordPiece {
+    type Trainer = WordPieceTrainer;
+    fn get_trainer(&self) -> Self::Trainer {
+        Self::Trainer::new()
+    }
+    fn get_vocab(&self) -> HashMap<String, u32> {
+        self.vocab.clone()
+    }
+    fn get_vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+    fn token_to_id(&self, token: &str) -> Option<u32> {
+        self.vocab.get(token).copied()
+    }
+    fn id_to_token(&self, id: u32) -> Option<String> {
+        self.vocab_r.get(&id).cloned()
+    }
+    /// Byte offsets
+    fn tokenize(&self, text: &str) -> Result<Vec<Token>, TokenizerError> {
+        let mut tokens = Vec::new();
+        for (offset, word) in split_whitespace(text) {
+            self.tokenize_word(word, offset, &mut tokens)?;
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+    use crate::tokenizer::models::ModelWrapper;
+    use crate::tokenizer::Tokenizer;
+
+    fn model() -> WordPiece {
+        let vocab: Vocab = ["[UNK]", "un", "##aff", "##able", "a", "##b", "b"]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.to_string(), i as u32))
+            .collect();
+        WordPiece::new(vocab)
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let mut model = model();
+        let tokens = model.tokenize("unaffable  ab\nbc").unwrap();
+        let values: Vec<&str> = tokens.iter().map(|t| t.value.as_str()).collect();
+        assert_eq!(values, ["un", "##aff", "##able", "a", "##b", "[UNK]"]);
+        assert_eq!(tokens[3].offsets, (11, 12));
+        assert_eq!(tokens[5].offsets, (14, 16));
+
+        model.max_input_chars_per_word = 4;
+        assert_eq!(model.tokenize("unaffable").unwrap()[0].id, 0);
+    }
+
+    #[test]
+    fn test_save() {
+        let mut model = model();
+        model.max_input_chars_per_word = 8;
+        let paths = ModelWrapper::WordPiece(model)
+            .save(&temp_dir(), Some("wordpiece-test"))
+            .unwrap();
+        let tokenizer = Tokenizer::from_file(&paths[0]).unwrap();
+        let ids = tokenizer.encode("unaffable ab b").unwrap().ids;
+        assert_eq!(ids, [0, 4, 5, 6]);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "[UNK] ab b");
+    }
+}
@@ -0,0 +1,89 @@
+use super::{split_whitespace, WordPiece};
+use crate::tokenizer::models::bpe::trainer::BpeTrainer;
+use crate::tokenizer::trainer::{Trainer, TrainerError};
+
+/// Learns a WordPiece vocab with BPE merges over whitespace-separated words, marking
+/// non-initial pieces with `##`
+pub struct WordPieceTrainer {
+    pub bpe: BpeTrainer,
+}
+
+impl Default for WordPieceTrainer {
+    fn default() -> Self {
+        let mut bpe = BpeTrainer::new();
+        bpe.continuing_subword_prefix = Some("##".into());
+        bpe.special_tokens = vec!["[UNK]".into()];
+        Self { bpe }
+    }
+}
+
+impl WordPieceTrainer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Trainer for WordPieceTrainer {
+    type Model = WordPiece;
+    /// Keeps the model's settings. Its unk token is added if the special tokens lack it
+    fn train(&self, model: &mut Self::Model) -> Result<(), TrainerError> {
+        let (mut vocab, _) = self.bpe.learn();
+        if !vocab.contains_key(&model.unk_token) {
+            vocab.insert(model.unk_token.clone(), vocab.len() as u32);
+        }
+        let mut trained = WordPiece::new(vocab);
+        trained.unk_token = model.unk_token.clone();
+        trained.max_input_chars_per_word = model.max_input_chars_per_word;
+        if let Some(prefix) = &self.bpe.continuing_subword_prefix {
+            trained.continuing_subword_prefix = prefix.clone();
+        }
+        *model = trained;
+        Ok(())
+    }
+    fn feed<I, S, F>(&mut self, iterator: I, processor: F) -> Result<(), TrainerError>
+    where
+        I: Iterator<Item = S>,
+        S: AsRef<str>,
+        F: Fn(&str) -> Result<Vec<String>, Box<dyn std::error::Error>>,
+    {
+        self.bpe.feed(iterator, |seq| {
+            let mut words = Vec::new();
+            for processed in processor(seq)? {
+                words.extend(split_whitespace(&processed).map(|(_, w)| w.to_string()));
+            }
+            Ok(words)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::tokenizer::models::Model;
+
+    #[test]
+    fn test_trainer() {
+        let mut model = WordPiece::new(HashMap::new());
+        let mut trainer = WordPieceTrainer::new();
+        trainer.bpe.vocab_size = 30;
+        trainer
+            .feed(["hugging hug hugs", "bugging\nbug"].iter(), |s| {
+                Ok(vec![s.to_string()])
+            })
+            .unwrap();
+        trainer.train(&mut model).unwrap();
+
+        assert_eq!(model.token_to_id("[UNK]"), Some(0));
+        assert!(model.token_to_id("##g").is_some());
+        assert!(model.token_to_id("g").is_none());
+        let values: Vec<String> = model
+            .tokenize("hugs bug")
+            .unwrap()
+            .into_iter()
+            .map(|t| t.value)
+            .collect();
+        assert_eq!(values, ["hug", "##s", "bug"]);
+    }
+}
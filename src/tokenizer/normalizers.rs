@@ -0,0 +1,217 @@
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use super::TokenizerError;
+
+pub trait Normalizer {
+    /// Clean up text before it is split into tokens
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum NormalizerWrapper {
+    Nfc(Nfc),
+    Nfd(Nfd),
+    Nfkc(Nfkc),
+    Nfkd(Nfkd),
+    Lowercase(Lowercase),
+    StripAccents(StripAccents),
+    Strip(Strip),
+    Replace(Replace),
+    Sequence(Sequence),
+}
+
+impl Normalizer for NormalizerWrapper {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        match self {
+            Self::Nfc(n) => n.normalize(text),
+            Self::Nfd(n) => n.normalize(text),
+            Self::Nfkc(n) => n.normalize(text),
+            Self::Nfkd(n) => n.normalize(text),
+            Self::Lowercase(n) => n.normalize(text),
+            Self::StripAccents(n) => n.normalize(text),
+            Self::Strip(n) => n.normalize(text),
+            Self::Replace(n) => n.normalize(text),
+            Self::Sequence(n) => n.normalize(text),
+        }
+    }
+}
+
+/// Unicode canonical composition, so e.g. `e` + U+0301 becomes `é`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Nfc;
+
+impl Normalizer for Nfc {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        Ok(text.nfc().collect())
+    }
+}
+
+/// Unicode canonical decomposition, so e.g. `é` becomes `e` + U+0301
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Nfd;
+
+impl Normalizer for Nfd {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        Ok(text.nfd().collect())
+    }
+}
+
+/// Like `Nfc`, but also folding compatibility variants, e.g. `ﬁ` into `fi`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Nfkc;
+
+impl Normalizer for Nfkc {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        Ok(text.nfkc().collect())
+    }
+}
+
+/// Like `Nfd`, but also folding compatibility variants
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Nfkd;
+
+impl Normalizer for Nfkd {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        Ok(text.nfkd().collect())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Lowercase;
+
+impl Normalizer for Lowercase {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        Ok(text.to_lowercase())
+    }
+}
+
+/// Drop combining marks. Composed characters keep their accents, so run `Nfd` or `Nfkd` first
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct StripAccents;
+
+impl Normalizer for StripAccents {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        Ok(text.chars().filter(|&c| !is_combining_mark(c)).collect())
+    }
+}
+
+/// Trim whitespace from either end
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Strip {
+    pub left: bool,
+    pub right: bool,
+}
+
+impl Normalizer for Strip {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        let text = if self.left { text.trim_start() } else { text };
+        let text = if self.right { text.trim_end() } else { text };
+        Ok(text.to_string())
+    }
+}
+
+/// Replace every match of a regex, e.g. runs of spaces with a single one
+#[derive(Serialize, Debug, Clone)]
+pub struct Replace {
+    pub pattern: String,
+    pub content: String,
+    #[serde(skip)]
+    regex: Regex,
+}
+
+impl Replace {
+    pub fn new(pattern: &str, content: &str) -> Result<Self, TokenizerError> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| TokenizerError::InvalidInput(format!("Invalid replace pattern: {}", e)))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            content: content.to_string(),
+            regex,
+        })
+    }
+}
+
+// Temporary struct for deserialization
+#[derive(Deserialize)]
+struct TempReplace {
+    pattern: String,
+    content: String,
+}
+
+impl<'de> Deserialize<'de> for Replace {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let temp: TempReplace = Deserialize::deserialize(deserializer)?;
+        Replace::new(&temp.pattern, &temp.content).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Normalizer for Replace {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        let mut replaced = String::with_capacity(text.len());
+        let mut last = 0;
+        // `Regex::replace_all` would hide matching errors, like hitting the backtrack limit
+        for m in self.regex.find_iter(text) {
+            let m = m.map_err(|e| TokenizerError::OtherError(Box::new(e)))?;
+            replaced.push_str(&text[last..m.start()]);
+            replaced.push_str(&self.content);
+            last = m.end();
+        }
+        replaced.push_str(&text[last..]);
+        Ok(replaced)
+    }
+}
+
+/// Several normalizers, applied in order
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sequence {
+    pub normalizers: Vec<NormalizerWrapper>,
+}
+
+impl Normalizer for Sequence {
+    fn normalize(&self, text: &str) -> Result<String, TokenizerError> {
+        self.normalizers
+            .iter()
+            .try_fold(text.to_string(), |text, n| n.normalize(&text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalizers() {
+        let decomposed = "Cafe\u{301}";
+        assert_eq!(Nfc.normalize(decomposed).unwrap(), "Café");
+        assert_eq!(Nfd.normalize("Café").unwrap(), decomposed);
+        assert_eq!(Nfkc.normalize("ﬁx²").unwrap(), "fix2");
+
+        let bert_like = NormalizerWrapper::Sequence(Sequence {
+            normalizers: vec![
+                NormalizerWrapper::Strip(Strip {
+                    left: true,
+                    right: true,
+                }),
+                NormalizerWrapper::Nfd(Nfd),
+                NormalizerWrapper::StripAccents(StripAccents),
+                NormalizerWrapper::Lowercase(Lowercase),
+                NormalizerWrapper::Replace(Replace::new(r"\s+", " ").unwrap()),
+            ],
+        });
+        assert_eq!(
+            bert_like.normalize("  Ça  VA,\tCafé? \n").unwrap(),
+            "ca va, cafe?"
+        );
+
+        // Replace keeps its compiled pattern through a save and load
+        let json = serde_json::to_string(&bert_like).unwrap();
+        let loaded: NormalizerWrapper = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.normalize("A  B").unwrap(), "a b");
+    }
+}
@@ -0,0 +1,354 @@
+//! Splitting normalized text into the pieces a model tokenizes one at a time.
+//!
+//! Both training and encoding go through the same pre-tokenizer, so merges never cross a
+//! piece boundary. Models still apply their own splitting within each piece.
+use fancy_regex::Regex;
+use serde::{Deserialize, Serialize};
+use unicode_categories::UnicodeCategories;
+
+use super::decoders::{
+    ByteLevel as ByteLevelDecoder, DecoderWrapper, Metaspace as MetaspaceDecoder,
+};
+use super::models::byte_level::{split, to_chars};
+use super::TokenizerError;
+
+/// A piece of text and where it starts in the input
+pub type Piece = (usize, String);
+
+pub trait PreTokenizer {
+    /// Split text into pieces, which may also be rewritten (e.g. spelled with byte chars)
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum PreTokenizerWrapper {
+    Whitespace(Whitespace),
+    Punctuation(Punctuation),
+    Digits(Digits),
+    ByteLevel(ByteLevel),
+    Split(Split),
+    Metaspace(Metaspace),
+    Sequence(Sequence),
+}
+
+impl PreTokenizer for PreTokenizerWrapper {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        match self {
+            Self::Whitespace(p) => p.pre_tokenize(text),
+            Self::Punctuation(p) => p.pre_tokenize(text),
+            Self::Digits(p) => p.pre_tokenize(text),
+            Self::ByteLevel(p) => p.pre_tokenize(text),
+            Self::Split(p) => p.pre_tokenize(text),
+            Self::Metaspace(p) => p.pre_tokenize(text),
+            Self::Sequence(p) => p.pre_tokenize(text),
+        }
+    }
+}
+
+impl PreTokenizerWrapper {
+    /// Decoder undoing what this pre-tokenizer does to the text, if it needs one
+    pub fn default_decoder(&self) -> Option<DecoderWrapper> {
+        match self {
+            Self::ByteLevel(_) => Some(DecoderWrapper::ByteLevel(ByteLevelDecoder)),
+            Self::Metaspace(m) => Some(DecoderWrapper::Metaspace(MetaspaceDecoder {
+                replacement: m.replacement,
+                prepend_space: m.prepend_space,
+            })),
+            Self::Sequence(s) => s
+                .pre_tokenizers
+                .iter()
+                .rev()
+                .find_map(|p| p.default_decoder()),
+            _ => None,
+        }
+    }
+    /// Whether this spells text with byte chars, as `ByteLevelBpe` does itself
+    pub fn is_byte_level(&self) -> bool {
+        match self {
+            Self::ByteLevel(_) => true,
+            Self::Sequence(s) => s.pre_tokenizers.iter().any(|p| p.is_byte_level()),
+            _ => false,
+        }
+    }
+}
+
+/// Split where `is_split` holds. Matching chars are dropped, kept as one piece per run, or
+/// kept as one piece each
+fn split_chars<F: Fn(char) -> bool>(text: &str, is_split: F, keep: Keep) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    // Start of the current piece, and whether it matches
+    let mut current: Option<(usize, bool)> = None;
+    for (i, c) in text.char_indices() {
+        let matches = is_split(c);
+        if let Some((start, was_match)) = current {
+            if was_match == matches && !(matches && keep == Keep::Each) {
+                continue;
+            }
+            if !was_match || keep != Keep::None {
+                pieces.push((start, text[start..i].to_string()));
+            }
+        }
+        current = Some((i, matches));
+    }
+    if let Some((start, was_match)) = current {
+        if !was_match || keep != Keep::None {
+            pieces.push((start, text[start..].to_string()));
+        }
+    }
+    pieces
+}
+
+#[derive(PartialEq)]
+enum Keep {
+    None,
+    Runs,
+    Each,
+}
+
+/// Split on whitespace, dropping it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Whitespace;
+
+impl PreTokenizer for Whitespace {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        Ok(split_chars(text, char::is_whitespace, Keep::None))
+    }
+}
+
+/// Put each punctuation char in a piece of its own
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Punctuation;
+
+impl PreTokenizer for Punctuation {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        let is_punctuation = |c: char| c.is_ascii_punctuation() || c.is_punctuation();
+        Ok(split_chars(text, is_punctuation, Keep::Each))
+    }
+}
+
+/// Keep runs of digits apart from other text, or every digit on its own
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Digits {
+    pub individual_digits: bool,
+}
+
+impl PreTokenizer for Digits {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        let keep = if self.individual_digits {
+            Keep::Each
+        } else {
+            Keep::Runs
+        };
+        Ok(split_chars(text, |c| c.is_numeric(), keep))
+    }
+}
+
+/// GPT-2's pre-tokenization for a plain `Bpe` model: split with `GPT2_PATTERN` (unless
+/// `use_regex` is off) and spell each piece's bytes with `bytes_to_char`. `ByteLevelBpe`
+/// does this itself
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ByteLevel {
+    pub use_regex: bool,
+}
+
+impl Default for ByteLevel {
+    fn default() -> Self {
+        Self { use_regex: true }
+    }
+}
+
+impl PreTokenizer for ByteLevel {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        if !self.use_regex {
+            return Ok(vec![(0, to_chars(text))]);
+        }
+        Ok(split(text)?
+            .into_iter()
+            .map(|(offset, piece)| (offset, to_chars(piece)))
+            .collect())
+    }
+}
+
+/// What `Split` does with the regex matches
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SplitBehavior {
+    /// Matches are pieces of their own
+    Isolated,
+    /// Matches are dropped
+    Removed,
+}
+
+/// Split around the matches of a regex, keeping the text between them
+#[derive(Serialize, Debug, Clone)]
+pub struct Split {
+    pub pattern: String,
+    pub behavior: SplitBehavior,
+    #[serde(skip)]
+    regex: Regex,
+}
+
+impl Split {
+    pub fn new(pattern: &str, behavior: SplitBehavior) -> Result<Self, TokenizerError> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| TokenizerError::InvalidInput(format!("Invalid split pattern: {}", e)))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            behavior,
+            regex,
+        })
+    }
+}
+
+// Temporary struct for deserialization
+#[derive(Deserialize)]
+struct TempSplit {
+    pattern: String,
+    behavior: SplitBehavior,
+}
+
+impl<'de> Deserialize<'de> for Split {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let temp: TempSplit = Deserialize::deserialize(deserializer)?;
+        Split::new(&temp.pattern, temp.behavior).map_err(serde::de::Error::custom)
+    }
+}
+
+impl PreTokenizer for Split {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        let mut pieces = Vec::new();
+        let mut last = 0;
+        for m in self.regex.find_iter(text) {
+            let m = m.map_err(|e| TokenizerError::OtherError(Box::new(e)))?;
+            if last < m.start() {
+                pieces.push((last, text[last..m.start()].to_string()));
+            }
+            if self.behavior == SplitBehavior::Isolated && !m.as_str().is_empty() {
+                pieces.push((m.start(), m.as_str().to_string()));
+            }
+            last = m.end();
+        }
+        if last < text.len() {
+            pieces.push((last, text[last..].to_string()));
+        }
+        Ok(pieces)
+    }
+}
+
+/// SentencePiece-style: spaces become `replacement` (`▁`), which starts each piece, so
+/// words keep their leading space. Decode with `decoders::Metaspace`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Metaspace {
+    pub replacement: char,
+    /// Put a space in front of the text, so the first word looks like the others
+    pub prepend_space: bool,
+}
+
+impl Default for Metaspace {
+    fn default() -> Self {
+        Self {
+            replacement: '▁',
+            prepend_space: true,
+        }
+    }
+}
+
+impl PreTokenizer for Metaspace {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        let mut pieces: Vec<Piece> = Vec::new();
+        if self.prepend_space && !text.starts_with(' ') {
+            pieces.push((0, self.replacement.to_string()));
+        }
+        for (i, c) in text.char_indices() {
+            let c = if c == ' ' { self.replacement } else { c };
+            match pieces.last_mut() {
+                Some((_, piece)) if c != self.replacement || piece.ends_with(c) => piece.push(c),
+                _ => pieces.push((i, c.to_string())),
+            }
+        }
+        Ok(pieces)
+    }
+}
+
+/// Several pre-tokenizers, each splitting the pieces of the one before
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sequence {
+    pub pre_tokenizers: Vec<PreTokenizerWrapper>,
+}
+
+impl PreTokenizer for Sequence {
+    fn pre_tokenize(&self, text: &str) -> Result<Vec<Piece>, TokenizerError> {
+        let mut pieces = vec![(0, text.to_string())];
+        for pre_tokenizer in &self.pre_tokenizers {
+            let mut next = Vec::new();
+            for (offset, piece) in pieces {
+                next.extend(
+                    pre_tokenizer
+                        .pre_tokenize(&piece)?
+                        .into_iter()
+                        .map(|(o, p)| (offset + o, p)),
+                );
+            }
+            pieces = next;
+        }
+        Ok(pieces)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::decoders::Decoder;
+
+    fn pieces(pre_tokenizer: &PreTokenizerWrapper, text: &str) -> Vec<String> {
+        let pieces = pre_tokenizer.pre_tokenize(text).unwrap();
+        pieces.into_iter().map(|(_, p)| p).collect()
+    }
+
+    #[test]
+    fn test_pre_tokenizers() {
+        let bert = PreTokenizerWrapper::Sequence(Sequence {
+            pre_tokenizers: vec![
+                PreTokenizerWrapper::Whitespace(Whitespace),
+                PreTokenizerWrapper::Punctuation(Punctuation),
+                PreTokenizerWrapper::Digits(Digits {
+                    individual_digits: true,
+                }),
+            ],
+        });
+        assert_eq!(
+            pieces(&bert, " Hey,  you!… 42x\n"),
+            ["Hey", ",", "you", "!", "…", "4", "2", "x"]
+        );
+        assert_eq!(
+            bert.pre_tokenize("a  b42").unwrap(),
+            [
+                (0, "a".into()),
+                (3, "b".into()),
+                (4, "4".into()),
+                (5, "2".into())
+            ]
+        );
+
+        let metaspace = PreTokenizerWrapper::Metaspace(Metaspace::default());
+        assert_eq!(pieces(&metaspace, "Hey  you"), ["▁Hey", "▁▁you"]);
+        let decoded = metaspace
+            .default_decoder()
+            .unwrap()
+            .decode_bytes(&pieces(&metaspace, "Hey  you"));
+        assert_eq!(decoded, b"Hey  you");
+
+        let split = Split::new(r"\d+", SplitBehavior::Isolated).unwrap();
+        let split = PreTokenizerWrapper::Split(split);
+        assert_eq!(pieces(&split, "ab12c"), ["ab", "12", "c"]);
+        // Serialized without the compiled regex, which is rebuilt on load
+        let json = serde_json::to_string(&split).unwrap();
+        let loaded: PreTokenizerWrapper = serde_json::from_str(&json).unwrap();
+        assert_eq!(pieces(&loaded, "3d"), ["3", "d"]);
+
+        let byte_level = PreTokenizerWrapper::ByteLevel(ByteLevel::default());
+        assert_eq!(pieces(&byte_level, "I'm here"), ["I", "'m", "Ġhere"]);
+    }
+}
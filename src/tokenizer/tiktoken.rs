@@ -0,0 +1,125 @@
+//! OpenAI `tiktoken` rank files, as used for GPT-2 (`r50k_base`) and GPT-3.5/4 (`cl100k_base`).
+//!
+//! Each line is a base64 token and its rank, which is also its id. tiktoken merges whichever
+//! adjacent pair makes the lowest-ranked token, so the files have no merge list; we recover one
+//! by replaying that on every token and load the result as a byte-level BPE.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use super::models::bpe::{Bpe, Merges};
+use super::models::byte_level::{bytes_to_char, ByteLevelBpe};
+use super::models::character::Vocab;
+use super::{Tokenizer, TokenizerError};
+
+/// A byte string spelled with `bytes_to_char`, as byte-level BPE vocab entries are
+fn spell(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| bytes_to_char()[b as usize]).collect()
+}
+
+/// How tiktoken splits `token` using only tokens ranked below `max_rank`
+fn byte_pair_split<'a>(
+    token: &'a [u8],
+    ranks: &HashMap<Vec<u8>, u32>,
+    max_rank: u32,
+) -> Vec<&'a [u8]> {
+    // Start of each part, plus the end
+    let mut bounds: Vec<usize> = (0..=token.len()).collect();
+    while bounds.len() > 3 {
+        let best = (0..bounds.len() - 2)
+            .filter_map(|i| {
+                ranks
+                    .get(&token[bounds[i]..bounds[i + 2]])
+                    .filter(|&&rank| rank < max_rank)
+                    .map(|&rank| (rank, i))
+            })
+            .min();
+        match best {
+            Some((_, i)) => {
+                bounds.remove(i + 1);
+            }
+            None => break,
+        }
+    }
+    bounds.windows(2).map(|w| &token[w[0]..w[1]]).collect()
+}
+
+/// Load the contents of a rank file, pre-tokenizing with `pattern`
+/// (e.g. `byte_level::CL100K_PATTERN`)
+pub fn from_ranks(ranks: &str, pattern: &str) -> Result<Tokenizer, TokenizerError> {
+    let mut tokens: Vec<(Vec<u8>, u32)> = Vec::new();
+    for (i, line) in ranks.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || TokenizerError::InvalidInput(format!("Invalid rank on line {}", i + 1));
+        let (token, rank) = line.split_once(' ').ok_or_else(invalid)?;
+        let token = STANDARD.decode(token).map_err(|_| invalid())?;
+        let rank = rank.trim().parse().map_err(|_| invalid())?;
+        tokens.push((token, rank));
+    }
+    tokens.sort_by_key(|(_, rank)| *rank);
+    let ranks: HashMap<Vec<u8>, u32> = tokens.iter().cloned().collect();
+
+    let mut merges: Merges = Vec::new();
+    for (token, rank) in tokens.iter().filter(|(token, _)| token.len() > 1) {
+        match byte_pair_split(token, &ranks, *rank)[..] {
+            [a, b] => merges.push((spell(a), spell(b))),
+            _ => {
+                return Err(TokenizerError::InvalidInput(format!(
+                    "Token {:?} with rank {} can't be built from lower-ranked tokens",
+                    spell(token),
+                    rank
+                )))
+            }
+        }
+    }
+    let vocab: Vocab = tokens
+        .iter()
+        .map(|(token, rank)| (spell(token), *rank))
+        .collect();
+    let model = ByteLevelBpe::with_pattern(Bpe::new(vocab, merges)?, pattern)?;
+    Ok(Tokenizer::new(model.into()))
+}
+
+/// Load a `.tiktoken` rank file, pre-tokenizing with `pattern`
+pub fn from_file(path: &Path, pattern: &str) -> Result<Tokenizer, TokenizerError> {
+    from_ranks(&fs::read_to_string(path)?, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::from_file;
+    use crate::tokenizer::hf;
+    use crate::tokenizer::models::byte_level::{CL100K_PATTERN, GPT2_PATTERN};
+
+    #[test]
+    fn test_fixture() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tiny.tiktoken");
+        let tokenizer = from_file(&path, CL100K_PATTERN).unwrap();
+        assert_eq!(tokenizer.get_vocab_size(), 267);
+
+        // " the" is built from " t" + "he" even though "the" exists too
+        let ids = tokenizer.encode("the cat sat on the mat").unwrap().ids;
+        assert_eq!(ids, [259, 262, 264, 32, 111, 110, 260, 32, 109, 258]);
+        assert_eq!(tokenizer.decode(&ids).unwrap(), "the cat sat on the mat");
+
+        // Bytes the file has no merges for still round-trip
+        let text = "Thé 12345\n\n👋";
+        let ids = tokenizer.encode(text).unwrap().ids;
+        assert_eq!(tokenizer.decode(&ids).unwrap(), text);
+
+        // The pattern decides what can merge: cl100k splits digits in threes, GPT-2 doesn't
+        let gpt2 = from_file(&path, GPT2_PATTERN).unwrap();
+        assert_eq!(tokenizer.encode("4123").unwrap().ids, [52, 265, 51]);
+        assert_eq!(gpt2.encode("4123").unwrap().ids, [52, 266]);
+
+        // The pattern survives a round trip through tokenizer.json
+        let loaded = hf::from_json(&hf::to_json(&tokenizer).unwrap()).unwrap();
+        assert_eq!(loaded.encode("4123").unwrap().ids, [52, 265, 51]);
+    }
+}
@@ -1,6 +1,10 @@
 use thiserror::Error;
 
-use super::models::{character::trainer::CharacterTrainer, Model, ModelWrapper};
+use super::models::{
+    bpe::trainer::BpeTrainer, byte_level::trainer::ByteLevelBpeTrainer,
+    character::trainer::CharacterTrainer, unigram::trainer::UnigramTrainer,
+    wordpiece::trainer::WordPieceTrainer, Model, ModelWrapper,
+};
 
 #[derive(Error, Debug)]
 pub enum TrainerError {
@@ -8,6 +12,8 @@ pub enum TrainerError {
     ProcessorError(#[from] Box<dyn std::error::Error>),
     #[error("Mismatching model: {0}")]
     InvalidModel(String),
+    #[error("Invalid trainer settings: {0}")]
+    InvalidSettings(String),
     #[error(transparent)]
     IoError(#[from] std::io::Error),
 }
@@ -25,6 +31,23 @@ pub trait Trainer {
 
 pub enum TrainerWrapper {
     CharacterTrainer(CharacterTrainer),
+    BpeTrainer(BpeTrainer),
+    ByteLevelBpeTrainer(ByteLevelBpeTrainer),
+    UnigramTrainer(UnigramTrainer),
+    WordPieceTrainer(WordPieceTrainer),
+}
+
+impl TrainerWrapper {
+    /// Tokens the trainer puts at the start of the vocab
+    pub fn special_tokens_mut(&mut self) -> &mut Vec<String> {
+        match self {
+            Self::CharacterTrainer(c) => &mut c.special_tokens,
+            Self::BpeTrainer(b) => &mut b.special_tokens,
+            Self::ByteLevelBpeTrainer(b) => &mut b.bpe.special_tokens,
+            Self::UnigramTrainer(u) => &mut u.special_tokens,
+            Self::WordPieceTrainer(w) => &mut w.bpe.special_tokens,
+        }
+    }
 }
 
 impl Trainer for TrainerWrapper {
@@ -37,13 +60,22 @@ impl Trainer for TrainerWrapper {
     {
         match self {
             Self::CharacterTrainer(c) => c.feed(iterator, processor),
+            Self::BpeTrainer(b) => b.feed(iterator, processor),
+            Self::ByteLevelBpeTrainer(b) => b.feed(iterator, processor),
+            Self::UnigramTrainer(u) => u.feed(iterator, processor),
+            Self::WordPieceTrainer(w) => w.feed(iterator, processor),
         }
     }
     fn train(&self, model: &mut Self::Model) -> Result<(), TrainerError> {
-        match self {
-            Self::CharacterTrainer(c) => match model {
-                ModelWrapper::Character(m) => c.train(m),
-            },
+        match (self, model) {
+            (Self::CharacterTrainer(c), ModelWrapper::Character(m)) => c.train(m),
+            (Self::BpeTrainer(b), ModelWrapper::Bpe(m)) => b.train(m),
+            (Self::ByteLevelBpeTrainer(b), ModelWrapper::ByteLevelBpe(m)) => b.train(m),
+            (Self::UnigramTrainer(u), ModelWrapper::Unigram(m)) => u.train(m),
+            (Self::WordPieceTrainer(w), ModelWrapper::WordPiece(m)) => w.train(m),
+            _ => Err(TrainerError::InvalidModel(
+                "Trainer does not match the tokenizer model".into(),
+            )),
         }
     }
 }
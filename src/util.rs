@@ -1,4 +1,11 @@
-use candle_core::Device;
+use std::sync::Mutex;
+
+use candle_core::{DType, Device, Error, Result, Shape, Tensor, Var};
+use candle_nn::init::{Init, NormalOrUniform};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::{VarBuilder, VarMap};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 /// Get either CUDA or Metal if compiled.
 /// Recommended to put the override elsewhere
@@ -19,3 +26,118 @@ pub fn get_device() -> Device {
     }
     device
 }
+
+/// `VarMap` backend that draws new weights from a seeded RNG.
+///
+/// Candle can't seed its CPU RNG, so `VarBuilder::from_varmap` initializes differently every run.
+/// Weights are drawn on the CPU and then moved to the target device.
+struct SeededVarMap {
+    varmap: VarMap,
+    rng: Mutex<StdRng>,
+}
+
+impl SeededVarMap {
+    fn sample(&self, shape: &Shape, init: Init) -> Vec<f64> {
+        let mut rng = self.rng.lock().unwrap();
+        let n = shape.elem_count();
+        match init {
+            Init::Const(c) => vec![c; n],
+            Init::Uniform { lo, up } => (0..n).map(|_| rng.gen_range(lo..up)).collect(),
+            Init::Randn { mean, stdev } => (0..n)
+                .map(|_| mean + stdev * standard_normal(&mut *rng))
+                .collect(),
+            Init::Kaiming {
+                dist,
+                fan,
+                non_linearity,
+            } => {
+                let std = non_linearity.gain() / (fan.for_shape(shape) as f64).sqrt();
+                match dist {
+                    NormalOrUniform::Uniform => {
+                        let bound = 3f64.sqrt() * std;
+                        (0..n).map(|_| rng.gen_range(-bound..bound)).collect()
+                    }
+                    NormalOrUniform::Normal => {
+                        (0..n).map(|_| std * standard_normal(&mut *rng)).collect()
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl SimpleBackend for SeededVarMap {
+    fn get(&self, s: Shape, name: &str, h: Init, dtype: DType, dev: &Device) -> Result<Tensor> {
+        if let Some(var) = self.varmap.data().lock().unwrap().get(name) {
+            if var.shape() != &s {
+                return Err(Error::Msg(format!(
+                    "shape mismatch on {}: {:?} <> {:?}",
+                    name,
+                    s,
+                    var.shape()
+                )));
+            }
+            return Ok(var.as_tensor().clone());
+        }
+        let values = self.sample(&s, h);
+        let tensor = Tensor::from_vec(values, s, &Device::Cpu)?
+            .to_dtype(dtype)?
+            .to_device(dev)?;
+        let var = Var::from_tensor(&tensor)?;
+        let tensor = var.as_tensor().clone();
+        self.varmap
+            .data()
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), var);
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.varmap.data().lock().unwrap().contains_key(name)
+    }
+}
+
+/// Box-Muller transform
+fn standard_normal<R: Rng>(rng: &mut R) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Like `VarBuilder::from_varmap`, but new weights are reproducible for a given `seed`
+pub fn seeded_var_builder(
+    varmap: &VarMap,
+    seed: u64,
+    dtype: DType,
+    device: &Device,
+) -> VarBuilder<'static> {
+    let backend = SeededVarMap {
+        varmap: varmap.clone(),
+        rng: Mutex::new(StdRng::seed_from_u64(seed)),
+    };
+    VarBuilder::from_backend(Box::new(backend), dtype, device.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+    use candle_nn::{linear, VarMap};
+
+    use super::seeded_var_builder;
+
+    #[test]
+    fn test_seeded_init() {
+        let device = Device::Cpu;
+        let weights = |seed: u64| -> Vec<Vec<f32>> {
+            let varmap = VarMap::new();
+            let vs = seeded_var_builder(&varmap, seed, DType::F32, &device);
+            let layer = linear(4, 3, vs.pp("layer")).unwrap();
+            // Initialized weights land in the varmap, so they can be saved and trained
+            assert!(varmap.data().lock().unwrap().contains_key("layer.weight"));
+            layer.weight().to_vec2().unwrap()
+        };
+        assert_eq!(weights(42), weights(42));
+        assert_ne!(weights(42), weights(43));
+    }
+}